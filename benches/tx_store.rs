@@ -0,0 +1,112 @@
+//! Compares the memory use and throughput of the transaction stores on a large synthetic input.
+//!
+//! Run with `cargo bench --bench tx_store`. The input has `BENCH_ROWS` rows (300 million by
+//! default, about 8GB) and is written to `BENCH_FILE` (`tx_store_bench.csv` in the temp dir) the
+//! first time. The hashmap store needs tens of GB of memory at the default size, set `BENCH_ROWS`
+//! lower on smaller machines.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use toy_payment_engine::{DenseTxStore, Engine, MemoryTxStore, TxStore};
+
+/// Keeps track of the current and peak heap use.
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const DEFAULT_ROWS: u64 = 300_000_000;
+
+/// Writes mostly sequential deposits and withdrawals for 1000 clients, with a dispute every
+/// thousand rows and a resolve for every other dispute.
+fn write_input(input_file: &path::Path, rows: u64) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(fs::File::create(input_file)?);
+    writeln!(writer, "type,client,tx,amount")?;
+    let mut tx_id: u64 = 0;
+    let mut disputed_tx_id = None;
+    for row in 0..rows {
+        if row % 1000 == 999 && tx_id > 500 {
+            let disputed = tx_id - 500;
+            writeln!(writer, "dispute,{},{disputed},", disputed % 1000)?;
+            disputed_tx_id = Some(disputed);
+        } else if let Some(disputed) = disputed_tx_id.filter(|_| row % 2000 == 499) {
+            writeln!(writer, "resolve,{},{disputed},", disputed % 1000)?;
+            disputed_tx_id = None;
+        } else {
+            tx_id += 1;
+            let tx_type = if row % 10 == 9 {
+                "withdrawal"
+            } else {
+                "deposit"
+            };
+            writeln!(
+                writer,
+                "{tx_type},{},{tx_id},{}.{:04}",
+                tx_id % 1000,
+                row % 100,
+                row % 9973
+            )?;
+        }
+    }
+    writer.flush()
+}
+
+fn bench(name: &str, store: impl TxStore, input_file: &path::Path, rows: u64) {
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+    let start = Instant::now();
+    let mut engine = Engine::with_store(store);
+    engine.process_csv_file(input_file).unwrap();
+    let elapsed = start.elapsed().as_secs_f64();
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    let retained = ALLOCATED.load(Ordering::Relaxed) - baseline;
+    println!(
+        "{name:>8}: {elapsed:8.1}s {:12.0} rows/s  peak {:8.1} MiB  retained {:8.1} MiB  {:6.1} bytes/row",
+        rows as f64 / elapsed,
+        peak as f64 / (1024.0 * 1024.0),
+        retained as f64 / (1024.0 * 1024.0),
+        retained as f64 / rows as f64,
+    );
+}
+
+fn main() {
+    let rows = env::var("BENCH_ROWS").map_or(DEFAULT_ROWS, |rows| rows.parse().unwrap());
+    let input_file = env::var_os("BENCH_FILE").map_or_else(
+        || env::temp_dir().join("tx_store_bench.csv"),
+        path::PathBuf::from,
+    );
+    // Reuse the input from an earlier run if it has the same number of rows.
+    let rows_file = input_file.with_extension("rows");
+    if fs::read_to_string(&rows_file).ok() != Some(rows.to_string()) {
+        println!("writing {rows} rows to {}", input_file.display());
+        write_input(&input_file, rows).unwrap();
+        fs::write(&rows_file, rows.to_string()).unwrap();
+    }
+
+    bench("hashmap", MemoryTxStore::default(), &input_file, rows);
+    bench("dense", DenseTxStore::new(), &input_file, rows);
+}
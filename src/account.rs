@@ -0,0 +1,61 @@
+use crate::amount::Amount;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Set by an operator. Deposits and withdrawals are refused but disputes are still accepted.
+    Frozen,
+    /// Set by a chargeback. Every transaction is refused.
+    Locked,
+    /// Set by an operator. Every transaction is refused and the account can't be reopened.
+    Closed,
+}
+
+impl fmt::Display for AccountStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Active => "active",
+            Self::Frozen => "frozen",
+            Self::Locked => "locked",
+            Self::Closed => "closed",
+        })
+    }
+}
+
+/// The state of a client account.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OutputRecord {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub status: AccountStatus,
+}
+
+impl OutputRecord {
+    pub const fn new(amount: Amount) -> Self {
+        Self {
+            available: amount,
+            held: Amount::ZERO,
+            total: amount,
+            status: AccountStatus::Active,
+        }
+    }
+
+    /// Whether the account refuses transactions, for any reason.
+    pub const fn locked(&self) -> bool {
+        !matches!(self.status, AccountStatus::Active)
+    }
+
+    /// Returns the account with `available` and `held` moved by the given signed amounts, and `total`
+    /// by their sum. Returns `None` if any balance would overflow, leaving the caller's copy untouched.
+    pub fn checked_adjust(&self, available: Amount, held: Amount) -> Option<Self> {
+        Some(Self {
+            available: self.available.checked_add(available)?,
+            held: self.held.checked_add(held)?,
+            total: self.total.checked_add(available)?.checked_add(held)?,
+            status: self.status,
+        })
+    }
+}
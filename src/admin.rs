@@ -0,0 +1,74 @@
+use crate::account::AccountStatus;
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminType {
+    /// Lets a locked or frozen account transact again.
+    Unlock,
+    /// Stops deposits and withdrawals on an active account. Disputes are still accepted.
+    Freeze,
+    /// Permanently closes the account. A closed account can't be reopened.
+    Close,
+}
+
+impl AdminType {
+    /// Returns the status an account moves to when this action is applied to it, or `None` if the
+    /// action isn't allowed from `status`.
+    pub const fn next_status(self, status: AccountStatus) -> Option<AccountStatus> {
+        match (self, status) {
+            (Self::Unlock, AccountStatus::Locked | AccountStatus::Frozen) => {
+                Some(AccountStatus::Active)
+            }
+            (Self::Freeze, AccountStatus::Active) => Some(AccountStatus::Frozen),
+            (
+                Self::Close,
+                AccountStatus::Active | AccountStatus::Frozen | AccountStatus::Locked,
+            ) => Some(AccountStatus::Closed),
+            _ => None,
+        }
+    }
+}
+
+/// An administrative action on a client account, with who took it and why.
+///
+/// These only come from operator input (see `Engine::apply_admin`), never from the transaction stream.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AdminRecord {
+    pub action: AdminType,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub operator: String,
+    pub reason: String,
+}
+
+impl AdminRecord {
+    pub fn new(action: AdminType, client_id: u16, operator: &str, reason: &str) -> Self {
+        Self {
+            action,
+            client_id,
+            operator: operator.to_owned(),
+            reason: reason.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn next_status_test() {
+        use AccountStatus::{Active, Closed, Frozen, Locked};
+        use AdminType::{Close, Freeze, Unlock};
+
+        assert_eq!(Unlock.next_status(Locked), Some(Active));
+        assert_eq!(Unlock.next_status(Frozen), Some(Active));
+        assert_eq!(Unlock.next_status(Active), None);
+        assert_eq!(Freeze.next_status(Active), Some(Frozen));
+        assert_eq!(Freeze.next_status(Locked), None);
+        assert_eq!(Close.next_status(Locked), Some(Closed));
+        for action in [Unlock, Freeze, Close] {
+            assert_eq!(action.next_status(Closed), None);
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A fixed-point amount stored as an integer number of ten-thousandths (0.0001).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Amount(i64);
+
+/// The number of decimal places an amount is stored with.
+pub const DECIMALS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseAmountError {
+    /// The input is not a decimal number.
+    Invalid,
+    /// The input has more than 4 digits after the decimal point.
+    TooPrecise,
+    /// The input does not fit in the ledger.
+    Overflow,
+}
+
+impl fmt::Display for ParseAmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => write!(f, "invalid decimal amount"),
+            Self::TooPrecise => write!(f, "amount has more than {DECIMALS} decimal places"),
+            Self::Overflow => write!(f, "amount is too large"),
+        }
+    }
+}
+
+impl Error for ParseAmountError {}
+
+/// How trailing zeros past the decimal point are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecimalStyle {
+    /// Always write 4 decimal places, e.g. `1.5000` and `2.0000`.
+    #[default]
+    Fixed,
+    /// Drop trailing zeros and the decimal point if nothing is left, e.g. `1.5` and `2`.
+    Minimal,
+}
+
+impl FromStr for DecimalStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(Self::Fixed),
+            "minimal" => Ok(Self::Minimal),
+            _ => Err(format!(
+                "unknown decimal style `{s}`, expected `fixed` or `minimal`"
+            )),
+        }
+    }
+}
+
+impl Amount {
+    pub const ZERO: Self = Self(0);
+
+    /// Creates an amount from a number of ten-thousandths, e.g. `from_ten_thousandths(15)` is 0.0015.
+    pub const fn from_ten_thousandths(ten_thousandths: i64) -> Self {
+        Self(ten_thousandths)
+    }
+
+    /// The amount as a number of ten-thousandths.
+    pub const fn ten_thousandths(self) -> i64 {
+        self.0
+    }
+
+    pub const fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    /// Returns `None` if the result would overflow.
+    pub const fn checked_add(self, rhs: Self) -> Option<Self> {
+        match self.0.checked_add(rhs.0) {
+            Some(sum) => Some(Self(sum)),
+            None => None,
+        }
+    }
+
+    /// Returns `None` if the result would overflow.
+    pub const fn checked_sub(self, rhs: Self) -> Option<Self> {
+        match self.0.checked_sub(rhs.0) {
+            Some(difference) => Some(Self(difference)),
+            None => None,
+        }
+    }
+
+    /// Returns the largest or smallest amount instead of overflowing.
+    pub const fn saturating_add(self, rhs: Self) -> Self {
+        Self(self.0.saturating_add(rhs.0))
+    }
+
+    /// Returns `None` for the one amount whose negation doesn't fit.
+    pub const fn checked_neg(self) -> Option<Self> {
+        match self.0.checked_neg() {
+            Some(negated) => Some(Self(negated)),
+            None => None,
+        }
+    }
+
+    /// Formats the amount straight from its integer representation.
+    pub fn to_string_with(self, style: DecimalStyle) -> String {
+        // unsigned_abs so that i64::MIN doesn't overflow.
+        let magnitude = self.0.unsigned_abs();
+        let scale = 10_u64.pow(DECIMALS);
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let int_part = magnitude / scale;
+        let frac_part = format!("{:0width$}", magnitude % scale, width = DECIMALS as usize);
+        let frac_part = match style {
+            DecimalStyle::Fixed => &frac_part,
+            DecimalStyle::Minimal => frac_part.trim_end_matches('0'),
+        };
+        if frac_part.is_empty() {
+            format!("{sign}{int_part}")
+        } else {
+            format!("{sign}{int_part}.{frac_part}")
+        }
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_string_with(DecimalStyle::Fixed))
+    }
+}
+
+impl FromStr for Amount {
+    type Err = ParseAmountError;
+
+    /// Parses a decimal string such as `-12.5`, `.0001` or `5e6` without going through floating point.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, unsigned) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (mantissa, exponent) = match unsigned.find(['e', 'E']) {
+            Some(idx) => {
+                let exponent: i32 = unsigned[idx + 1..]
+                    .parse()
+                    .map_err(|_| ParseAmountError::Invalid)?;
+                (&unsigned[..idx], exponent)
+            }
+            None => (unsigned, 0),
+        };
+        let (int_digits, frac_digits) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if int_digits.is_empty() && frac_digits.is_empty() {
+            return Err(ParseAmountError::Invalid);
+        }
+
+        // The number of digits the value has past the decimal point once the exponent is applied.
+        let frac_len = i32::try_from(frac_digits.len()).map_err(|_| ParseAmountError::Overflow)?;
+        let decimals = frac_len
+            .checked_sub(exponent)
+            .ok_or(ParseAmountError::Overflow)?;
+        if decimals > DECIMALS as i32 {
+            return Err(ParseAmountError::TooPrecise);
+        }
+
+        let mut value: i64 = 0;
+        for c in int_digits.chars().chain(frac_digits.chars()) {
+            let digit = c.to_digit(10).ok_or(ParseAmountError::Invalid)?;
+            value = value
+                .checked_mul(10)
+                .and_then(|value| value.checked_add(i64::from(digit)))
+                .ok_or(ParseAmountError::Overflow)?;
+        }
+        let scale = (DECIMALS as i32)
+            .checked_sub(decimals)
+            .and_then(|shift| u32::try_from(shift).ok())
+            .and_then(|shift| 10_i64.checked_pow(shift))
+            .ok_or(ParseAmountError::Overflow)?;
+        value = value.checked_mul(scale).ok_or(ParseAmountError::Overflow)?;
+
+        Ok(Self(if negative { -value } else { value }))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Amount {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_str(AmountVisitor)
+    }
+}
+
+/// An amount in JSON input, which may be a number as well as a decimal string.
+pub(crate) struct JsonAmount(pub(crate) Amount);
+
+impl<'de> serde::Deserialize<'de> for JsonAmount {
+    // Numbers keep the text they were written as, so they are parsed as exactly as strings and
+    // never go through a float.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        match <serde_json::Value as serde::Deserialize>::deserialize(deserializer)? {
+            serde_json::Value::String(s) => s.parse().map(Self).map_err(D::Error::custom),
+            serde_json::Value::Number(n) => {
+                n.to_string().parse().map(Self).map_err(D::Error::custom)
+            }
+            _ => Err(D::Error::custom("expected a decimal amount")),
+        }
+    }
+}
+
+/// Reads an amount from a decimal string.
+struct AmountVisitor;
+
+impl serde::de::Visitor<'_> for AmountVisitor {
+    type Value = Amount;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a decimal amount")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, s: &str) -> Result<Amount, E> {
+        s.parse().map_err(E::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<i64, ParseAmountError> {
+        s.parse::<Amount>().map(Amount::ten_thousandths)
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse("1"), Ok(10_000));
+        assert_eq!(parse("1.0001"), Ok(10_001));
+        assert_eq!(parse("123456.7891"), Ok(1_234_567_891));
+        assert_eq!(parse(".5"), Ok(5_000));
+        assert_eq!(parse("2."), Ok(20_000));
+        assert_eq!(parse("-0.0001"), Ok(-1));
+        assert_eq!(parse("+3"), Ok(30_000));
+        assert_eq!(parse("5e6"), Ok(50_000_000_000));
+        assert_eq!(parse("1.25e-2"), Ok(125));
+        assert_eq!(parse("922337203685477.5807"), Ok(i64::MAX));
+    }
+
+    #[test]
+    fn parse_errors_test() {
+        assert_eq!(parse("1.00010001"), Err(ParseAmountError::TooPrecise));
+        assert_eq!(parse("1.5E-4"), Err(ParseAmountError::TooPrecise));
+        assert_eq!(
+            parse("922337203685477.5808"),
+            Err(ParseAmountError::Overflow)
+        );
+        assert_eq!(parse("1e20"), Err(ParseAmountError::Overflow));
+        assert_eq!(parse("1e2147483647"), Err(ParseAmountError::Overflow));
+        assert_eq!(parse("1e-2147483648"), Err(ParseAmountError::Overflow));
+        assert_eq!(parse(""), Err(ParseAmountError::Invalid));
+        assert_eq!(parse("."), Err(ParseAmountError::Invalid));
+        assert_eq!(parse("-"), Err(ParseAmountError::Invalid));
+        assert_eq!(parse("π"), Err(ParseAmountError::Invalid));
+        assert_eq!(parse("1.2.3"), Err(ParseAmountError::Invalid));
+        assert_eq!(parse("--1"), Err(ParseAmountError::Invalid));
+        assert_eq!(parse("1e"), Err(ParseAmountError::Invalid));
+    }
+
+    // JSON numbers are read as exactly as strings, so one with more places than an amount has is
+    // refused rather than rounded.
+    #[test]
+    fn json_amount_test() {
+        let json = |s: &str| serde_json::from_str::<JsonAmount>(s).map(|amount| amount.0);
+        for (s, expected) in [
+            ("92233720368547.5807", 922_337_203_685_475_807),
+            ("\"92233720368547.5807\"", 922_337_203_685_475_807),
+            ("3", 30_000),
+            ("1.25e-2", 125),
+        ] {
+            assert_eq!(
+                json(s).unwrap(),
+                Amount::from_ten_thousandths(expected),
+                "{s}"
+            );
+        }
+        for s in [
+            "1.00001",
+            "1.00000000000000000001",
+            "922337203685477.5808",
+            "true",
+            "[]",
+        ] {
+            assert!(json(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn format_test() {
+        let fixed = |n: i64| Amount(n).to_string_with(DecimalStyle::Fixed);
+        let minimal = |n: i64| Amount(n).to_string_with(DecimalStyle::Minimal);
+
+        assert_eq!(fixed(0), "0.0000");
+        assert_eq!(fixed(1), "0.0001");
+        assert_eq!(fixed(-1), "-0.0001");
+        assert_eq!(fixed(-5_000), "-0.5000");
+        assert_eq!(fixed(12_345_678_901), "1234567.8901");
+        assert_eq!(fixed(-8_000_000), "-800.0000");
+        assert_eq!(fixed(i64::MAX), "922337203685477.5807");
+        assert_eq!(fixed(i64::MIN), "-922337203685477.5808");
+        assert_eq!(fixed(i64::MIN + 1), "-922337203685477.5807");
+
+        assert_eq!(minimal(0), "0");
+        assert_eq!(minimal(-5_000), "-0.5");
+        assert_eq!(minimal(20_000), "2");
+        assert_eq!(minimal(10_010), "1.001");
+        assert_eq!(minimal(i64::MAX), "922337203685477.5807");
+        assert_eq!(minimal(i64::MIN), "-922337203685477.5808");
+        assert_eq!(minimal(-9_223_372_036_854_770_000), "-922337203685477");
+
+        assert_eq!(Amount(15).to_string(), "0.0015");
+    }
+
+    #[test]
+    fn checked_arithmetic_test() {
+        assert_eq!(Amount(i64::MAX).checked_add(Amount(1)), None);
+        assert_eq!(Amount(i64::MIN).checked_sub(Amount(1)), None);
+        assert_eq!(Amount(i64::MIN).checked_neg(), None);
+        assert_eq!(
+            Amount(i64::MAX).checked_sub(Amount(1)),
+            Some(Amount(i64::MAX - 1))
+        );
+        assert_eq!(Amount(-5).checked_neg(), Some(Amount(5)));
+    }
+
+    // Formatting then parsing gives back the same amount.
+    #[test]
+    fn round_trip_test() {
+        for n in [
+            0,
+            1,
+            -1,
+            9_999,
+            10_000,
+            -123_456_789,
+            i64::MAX,
+            i64::MIN + 1,
+        ] {
+            for style in [DecimalStyle::Fixed, DecimalStyle::Minimal] {
+                assert_eq!(parse(&Amount(n).to_string_with(style)), Ok(n));
+            }
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use crate::account::{AccountStatus, OutputRecord};
+use crate::admin::{AdminRecord, AdminType};
+use crate::amount::Amount;
+use crate::engine::Engine;
+use crate::fees::FeeTotals;
+use crate::ledger::{Ledger, Posting};
+use crate::store::TxStore;
+use crate::transaction::{InputRecord, TxState, TxType};
+use std::error::Error;
+use std::io;
+
+/// Bumped whenever the checkpoint format changes.
+const VERSION: &str = "4";
+
+/// Where processing had got to when a checkpoint was taken.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The index of the input being processed, in the order the inputs were given.
+    pub input: usize,
+    /// The name of that input, so a resumed run can check it was given the same inputs.
+    pub source: String,
+    /// Where to continue in that input, or `None` to start it from the beginning.
+    pub position: Option<csv::Position>,
+    /// How many bytes of rejects had been written.
+    pub rejects_len: u64,
+}
+
+/// Writes the full engine state and `checkpoint` to writer, so that a later run can continue from it.
+///
+/// The checkpoint is a csv file with no header, where the first field of each row says what it holds.
+pub fn write_checkpoint<S: TxStore>(
+    engine: &Engine<S>,
+    checkpoint: &Checkpoint,
+    writer: impl io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+    wtr.write_record(["version", VERSION])?;
+
+    let position = checkpoint.position.as_ref().map_or_else(
+        || [String::new(), String::new(), String::new()],
+        |position| {
+            [
+                position.byte().to_string(),
+                position.line().to_string(),
+                position.record().to_string(),
+            ]
+        },
+    );
+    wtr.write_record([
+        "input",
+        &checkpoint.input.to_string(),
+        &checkpoint.source,
+        &checkpoint.rejects_len.to_string(),
+        &position[0],
+        &position[1],
+        &position[2],
+    ])?;
+
+    for (client_id, output_record) in engine.accounts() {
+        wtr.write_record([
+            "account".to_owned(),
+            client_id.to_string(),
+            output_record.available.to_string(),
+            output_record.held.to_string(),
+            output_record.total.to_string(),
+            output_record.status.to_string(),
+        ])?;
+    }
+
+    engine.tx_store.for_each(&mut |record| {
+        wtr.write_record([
+            "tx".to_owned(),
+            record.tx_id.to_string(),
+            record.client_id.to_string(),
+            record.tx_type.to_string(),
+            record.state.to_string(),
+            record
+                .amount
+                .map(|amount| amount.to_string())
+                .unwrap_or_default(),
+            record.disputed.to_string(),
+            record.charged_back.to_string(),
+            record
+                .destination
+                .map(|destination| destination.to_string())
+                .unwrap_or_default(),
+            record.captured.to_string(),
+        ])
+        .map_err(io::Error::from)
+    })?;
+
+    for entry in engine.ledger().map_or(&[][..], Ledger::entries) {
+        for posting in &entry.postings {
+            wtr.write_record([
+                "ledger".to_owned(),
+                entry.id.to_string(),
+                entry.tx_id.to_string(),
+                entry.tx_type.to_string(),
+                posting.account.to_string(),
+                posting.amount.to_string(),
+            ])?;
+        }
+    }
+
+    for (tx_type, totals) in engine.fee_summary().totals() {
+        wtr.write_record([
+            "fee".to_owned(),
+            tx_type.to_string(),
+            totals.count.to_string(),
+            totals.charged.to_string(),
+            totals.waived.to_string(),
+        ])?;
+    }
+
+    for admin_record in engine.admin_log() {
+        wtr.write_record([
+            "admin",
+            admin_action_name(admin_record.action),
+            &admin_record.client_id.to_string(),
+            &admin_record.operator,
+            &admin_record.reason,
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Loads the engine state written by `write_checkpoint` into `engine`, which should be new, and
+/// returns where processing should continue.
+pub fn read_checkpoint<S: TxStore>(
+    engine: &mut Engine<S>,
+    reader: impl io::Read,
+) -> Result<Checkpoint, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader);
+    let mut checkpoint = None;
+    for (index, result) in csv_reader.records().enumerate() {
+        let row = result?;
+        let field = |i: usize| row.get(i).ok_or("truncated checkpoint row");
+        match (index, field(0)?) {
+            (0, "version") if field(1)? == VERSION => {}
+            (0, _) => return Err("unsupported checkpoint version".into()),
+            (_, "input") => {
+                let position = if field(4)?.is_empty() {
+                    None
+                } else {
+                    let mut position = csv::Position::new();
+                    position
+                        .set_byte(field(4)?.parse()?)
+                        .set_line(field(5)?.parse()?)
+                        .set_record(field(6)?.parse()?);
+                    Some(position)
+                };
+                checkpoint = Some(Checkpoint {
+                    input: field(1)?.parse()?,
+                    source: field(2)?.to_owned(),
+                    position,
+                    rejects_len: field(3)?.parse()?,
+                });
+            }
+            (_, "account") => {
+                let output_record = OutputRecord {
+                    available: field(2)?.parse()?,
+                    held: field(3)?.parse()?,
+                    total: field(4)?.parse()?,
+                    status: parse_status(field(5)?)?,
+                };
+                engine.client_map.insert(field(1)?.parse()?, output_record);
+            }
+            (_, "tx") => {
+                let amount = match field(5)? {
+                    "" => None,
+                    amount => Some(amount.parse()?),
+                };
+                let mut record = InputRecord::new(
+                    parse_tx_type(field(3)?)?,
+                    field(2)?.parse()?,
+                    field(1)?.parse()?,
+                    amount,
+                );
+                record.state = parse_tx_state(field(4)?)?;
+                record.disputed = field(6)?.parse::<Amount>()?;
+                record.charged_back = field(7)?.parse::<Amount>()?;
+                record.destination = match field(8)? {
+                    "" => None,
+                    destination => Some(destination.parse()?),
+                };
+                record.captured = field(9)?.parse::<Amount>()?;
+                engine.tx_store.insert(record)?;
+            }
+            (_, "ledger") => {
+                let posting = Posting {
+                    account: field(4)?.parse()?,
+                    amount: field(5)?.parse()?,
+                };
+                // The postings of an entry are on consecutive rows.
+                let entry_id: u64 = field(1)?.parse()?;
+                if let Some(ledger) = engine.ledger.as_mut() {
+                    match ledger.entries.last_mut() {
+                        Some(entry) if entry.id == entry_id => entry.postings.push(posting),
+                        _ => ledger.push(
+                            field(2)?.parse()?,
+                            parse_tx_type(field(3)?)?,
+                            vec![posting],
+                        ),
+                    }
+                }
+            }
+            (_, "fee") => {
+                let totals = FeeTotals {
+                    count: field(2)?.parse()?,
+                    charged: field(3)?.parse()?,
+                    waived: field(4)?.parse()?,
+                };
+                engine
+                    .fee_summary
+                    .insert(parse_tx_type(field(1)?)?, totals)?;
+            }
+            (_, "admin") => {
+                let action = parse_admin_action(field(1)?)?;
+                engine.admin_log.push(AdminRecord::new(
+                    action,
+                    field(2)?.parse()?,
+                    field(3)?,
+                    field(4)?,
+                ));
+            }
+            (_, kind) => return Err(format!("unknown checkpoint row {kind:?}").into()),
+        }
+    }
+    Ok(checkpoint.ok_or("checkpoint has no input position")?)
+}
+
+fn parse_tx_type(name: &str) -> Result<TxType, Box<dyn Error>> {
+    [
+        TxType::Deposit,
+        TxType::Withdrawal,
+        TxType::Dispute,
+        TxType::Resolve,
+        TxType::Chargeback,
+        TxType::Transfer,
+        TxType::Authorize,
+        TxType::Capture,
+        TxType::Void,
+    ]
+    .into_iter()
+    .find(|tx_type| tx_type.to_string() == name)
+    .ok_or_else(|| format!("unknown transaction type {name:?}").into())
+}
+
+fn parse_tx_state(name: &str) -> Result<TxState, Box<dyn Error>> {
+    [
+        TxState::NotApplied,
+        TxState::Applied,
+        TxState::InDispute,
+        TxState::ChargedBack,
+        TxState::Authorized,
+        TxState::Captured,
+        TxState::Voided,
+    ]
+    .into_iter()
+    .find(|state| state.to_string() == name)
+    .ok_or_else(|| format!("unknown transaction state {name:?}").into())
+}
+
+fn parse_status(name: &str) -> Result<AccountStatus, Box<dyn Error>> {
+    [
+        AccountStatus::Active,
+        AccountStatus::Frozen,
+        AccountStatus::Locked,
+        AccountStatus::Closed,
+    ]
+    .into_iter()
+    .find(|status| status.to_string() == name)
+    .ok_or_else(|| format!("unknown account status {name:?}").into())
+}
+
+const fn admin_action_name(action: AdminType) -> &'static str {
+    match action {
+        AdminType::Unlock => "unlock",
+        AdminType::Freeze => "freeze",
+        AdminType::Close => "close",
+    }
+}
+
+fn parse_admin_action(name: &str) -> Result<AdminType, Box<dyn Error>> {
+    [AdminType::Unlock, AdminType::Freeze, AdminType::Close]
+        .into_iter()
+        .find(|action| admin_action_name(*action) == name)
+        .ok_or_else(|| format!("unknown admin action {name:?}").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeSchedule;
+    use crate::output::{write_admin_log, write_output};
+    use crate::rejects::RejectsWriter;
+    use crate::DecimalStyle;
+    use std::path;
+
+    fn output_of<S: TxStore>(engine: &Engine<S>) -> String {
+        let mut bytes = Vec::new();
+        write_output(engine, &mut bytes, DecimalStyle::Fixed).unwrap();
+        write_admin_log(engine, &mut bytes).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    // Stopping at every checkpoint and resuming from it gives the same accounts, admin log and
+    // rejects as an uninterrupted run.
+    #[test]
+    fn resume_test() {
+        let csv_file = path::Path::new("sample_data/partial_disputes.csv");
+        let admin_file = path::Path::new("sample_data/admin_actions.csv");
+        let run = |engine: &mut Engine, rejects: &mut RejectsWriter<Vec<u8>>| {
+            engine
+                .process_csv_file_resumable(csv_file, Some(rejects), None, 0, |_, _| Ok(()))
+                .unwrap();
+        };
+        let mut engine = Engine::new();
+        let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+        run(&mut engine, &mut rejects);
+        engine
+            .process_admin_csv_file(admin_file, Some(&mut rejects))
+            .unwrap();
+        let expected_output = output_of(&engine);
+        let expected_rejects = rejects.into_inner().unwrap();
+
+        // Take a checkpoint after every row.
+        let mut checkpoints = Vec::new();
+        let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+        Engine::new()
+            .process_csv_file_resumable(
+                csv_file,
+                Some(&mut rejects),
+                None,
+                1,
+                |engine, position| {
+                    let checkpoint = Checkpoint {
+                        input: 0,
+                        source: csv_file.display().to_string(),
+                        position: Some(position.clone()),
+                        rejects_len: 0,
+                    };
+                    let mut bytes = Vec::new();
+                    write_checkpoint(engine, &checkpoint, &mut bytes).unwrap();
+                    checkpoints.push(bytes);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let all_rejects = rejects.into_inner().unwrap();
+        assert!(checkpoints.len() > 5);
+
+        for bytes in checkpoints {
+            let mut engine = Engine::new();
+            let checkpoint = read_checkpoint(&mut engine, bytes.as_slice()).unwrap();
+            assert_eq!(checkpoint.input, 0);
+            // Rejects written before the checkpoint are kept, the rest are written again.
+            let mut rejects = RejectsWriter::append(Vec::new());
+            engine
+                .process_csv_file_resumable(
+                    csv_file,
+                    Some(&mut rejects),
+                    checkpoint.position.as_ref(),
+                    0,
+                    |_, _| Ok(()),
+                )
+                .unwrap();
+            engine
+                .process_admin_csv_file(admin_file, Some(&mut rejects))
+                .unwrap();
+            assert_eq!(output_of(&engine), expected_output);
+            let resumed_rejects = rejects.into_inner().unwrap();
+            assert!(expected_rejects.ends_with(&resumed_rejects));
+        }
+        assert!(expected_rejects.starts_with(&all_rejects));
+    }
+
+    #[test]
+    fn round_trip_test() {
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        engine.set_fee_schedule(
+            FeeSchedule::from_csv_file(path::Path::new("sample_data/fee_schedule.csv")).unwrap(),
+        );
+        engine
+            .process_csv_file(path::Path::new("sample_data/disputes.csv"))
+            .unwrap();
+        // A partly captured authorization.
+        for (tx_type, amount) in [(TxType::Authorize, "3"), (TxType::Capture, "1")] {
+            engine
+                .apply(InputRecord::new(
+                    tx_type,
+                    2,
+                    20,
+                    Some(amount.parse().unwrap()),
+                ))
+                .unwrap();
+        }
+        engine
+            .apply_admin(AdminRecord::new(
+                AdminType::Close,
+                1,
+                "ops, \"night shift\"",
+                "multi\nline",
+            ))
+            .unwrap();
+        let mut position = csv::Position::new();
+        position.set_byte(10).set_line(2).set_record(1);
+        for position in [None, Some(position)] {
+            let checkpoint = Checkpoint {
+                input: 3,
+                source: "a,b.csv".to_owned(),
+                position,
+                rejects_len: 42,
+            };
+            let mut bytes = Vec::new();
+            write_checkpoint(&engine, &checkpoint, &mut bytes).unwrap();
+
+            let mut restored = Engine::new();
+            restored.enable_ledger();
+            assert_eq!(
+                read_checkpoint(&mut restored, bytes.as_slice()).unwrap(),
+                checkpoint
+            );
+            assert_eq!(output_of(&restored), output_of(&engine));
+            assert_eq!(
+                restored.ledger().unwrap().entries(),
+                engine.ledger().unwrap().entries()
+            );
+            assert_eq!(restored.fee_summary(), engine.fee_summary());
+            for tx_id in (1..=10).chain([20]) {
+                let (restored, original) = (
+                    restored.transaction(tx_id).unwrap(),
+                    engine.transaction(tx_id).unwrap(),
+                );
+                assert_eq!(restored.is_some(), original.is_some());
+                if let (Some(restored), Some(original)) = (restored, original) {
+                    assert_eq!(restored.state(), original.state());
+                    assert_eq!(restored.disputed_amount(), original.disputed_amount());
+                    assert_eq!(restored.captured_amount(), original.captured_amount());
+                    assert_eq!(restored.amount, original.amount);
+                }
+            }
+        }
+
+        let mut engine = Engine::new();
+        assert!(read_checkpoint(&mut engine, "version,0\n".as_bytes()).is_err());
+        assert!(read_checkpoint(&mut engine, "version,1\n".as_bytes()).is_err());
+        assert!(read_checkpoint(&mut engine, "version,2\n".as_bytes()).is_err());
+        assert!(read_checkpoint(&mut engine, "version,3\n".as_bytes()).is_err());
+    }
+
+    // Checkpoints part way through a JSON Lines file resume from the right line too.
+    #[test]
+    fn json_lines_resume_test() {
+        let json_file = path::Path::new("sample_data/bad_fields.jsonl");
+        let mut engine = Engine::new();
+        engine.process_json_lines_file(json_file).unwrap();
+        let expected_output = output_of(&engine);
+
+        let mut checkpoints = Vec::new();
+        Engine::new()
+            .process_json_lines_file_resumable(
+                json_file,
+                None::<&mut RejectsWriter<io::Sink>>,
+                None,
+                2,
+                |engine, position| {
+                    let checkpoint = Checkpoint {
+                        input: 0,
+                        source: json_file.display().to_string(),
+                        position: Some(position.clone()),
+                        rejects_len: 0,
+                    };
+                    let mut bytes = Vec::new();
+                    write_checkpoint(engine, &checkpoint, &mut bytes).unwrap();
+                    checkpoints.push(bytes);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(checkpoints.len(), 7);
+
+        for bytes in checkpoints {
+            let mut engine = Engine::new();
+            let checkpoint = read_checkpoint(&mut engine, bytes.as_slice()).unwrap();
+            let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+            engine
+                .process_json_lines_file_resumable(
+                    json_file,
+                    Some(&mut rejects),
+                    checkpoint.position.as_ref(),
+                    0,
+                    |_, _| Ok(()),
+                )
+                .unwrap();
+            assert_eq!(output_of(&engine), expected_output);
+            // Line numbers carry on from the checkpoint.
+            let rejects = String::from_utf8(rejects.into_inner().unwrap()).unwrap();
+            let line = checkpoint.position.unwrap().line();
+            assert!(rejects
+                .lines()
+                .skip(1)
+                .all(|row| { row.split(',').nth(1).unwrap().parse::<u64>().unwrap() >= line }));
+        }
+    }
+}
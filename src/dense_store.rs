@@ -0,0 +1,308 @@
+use crate::amount::Amount;
+use crate::store::TxStore;
+use crate::transaction::{InputRecord, TxState, TxType};
+use std::collections::HashMap;
+use std::io;
+
+/// Each page covers this many consecutive transaction IDs.
+const PAGE_BITS: u32 = 12;
+const PAGE_LEN: usize = 1 << PAGE_BITS;
+/// A page is only allocated once this many of its IDs are used, until then they are kept in a map.
+const PROMOTE_LEN: u32 = (PAGE_LEN / 4) as u32;
+
+const OCCUPIED: u8 = 1;
+const HAS_AMOUNT: u8 = 1 << 1;
+const HAS_DESTINATION: u8 = 1 << 2;
+
+/// One remembered transaction, packed into 16 bytes.
+#[derive(Debug, Clone, Copy, Default)]
+struct Slot {
+    amount: i64,
+    client_id: u16,
+    // The destination of a transfer, it fits in what would otherwise be padding.
+    destination: u16,
+    // Bit 0 is set when the slot is used, bit 1 when there is an amount and bit 2 when there is a
+    // destination.
+    flags: u8,
+    tx_type: u8,
+    state: u8,
+}
+
+type Page = Box<[Slot; PAGE_LEN]>;
+
+/// Keeps transactions in memory in fixed size slots, in pages indexed by transaction ID.
+///
+/// This is much smaller than `MemoryTxStore` when transaction IDs are mostly sequential. Sparse
+/// IDs are kept in a map until enough of a page is used for the page to be worth allocating. The
+/// disputed, charged back and captured amounts are rarely set, so they are kept in a separate map.
+#[derive(Default)]
+pub struct DenseTxStore {
+    pages: Vec<Option<Page>>,
+    // Transactions whose page hasn't been allocated yet.
+    sparse: HashMap<u32, Slot>,
+    // How many transactions are in `sparse` for each page.
+    sparse_counts: HashMap<u32, u32>,
+    // The disputed, charged back and captured amounts of transactions where any isn't zero.
+    tracked_amounts: HashMap<u32, (Amount, Amount, Amount)>,
+}
+
+impl DenseTxStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn slot(&self, tx_id: u32) -> Option<Slot> {
+        let slot = match self.pages.get((tx_id >> PAGE_BITS) as usize) {
+            Some(Some(page)) => page[tx_id as usize % PAGE_LEN],
+            _ => *self.sparse.get(&tx_id)?,
+        };
+        (slot.flags & OCCUPIED != 0).then_some(slot)
+    }
+
+    fn set_slot(&mut self, tx_id: u32, slot: Slot) {
+        let page_id = tx_id >> PAGE_BITS;
+        if let Some(Some(page)) = self.pages.get_mut(page_id as usize) {
+            page[tx_id as usize % PAGE_LEN] = slot;
+            return;
+        }
+        if self.sparse.insert(tx_id, slot).is_some() {
+            return;
+        }
+        let count = self.sparse_counts.entry(page_id).or_insert(0);
+        *count += 1;
+        if *count >= PROMOTE_LEN {
+            self.sparse_counts.remove(&page_id);
+            self.promote(page_id);
+        }
+    }
+
+    /// Moves the sparse transactions of a page into a newly allocated page.
+    fn promote(&mut self, page_id: u32) {
+        let mut page: Page = vec![Slot::default(); PAGE_LEN]
+            .into_boxed_slice()
+            .try_into()
+            .unwrap();
+        let first_tx_id = page_id << PAGE_BITS;
+        for (index, slot) in page.iter_mut().enumerate() {
+            if let Some(sparse_slot) = self.sparse.remove(&(first_tx_id + index as u32)) {
+                *slot = sparse_slot;
+            }
+        }
+        if self.pages.len() <= page_id as usize {
+            self.pages.resize_with(page_id as usize + 1, || None);
+        }
+        self.pages[page_id as usize] = Some(page);
+    }
+}
+
+impl TxStore for DenseTxStore {
+    fn get(&self, tx_id: u32) -> io::Result<Option<InputRecord>> {
+        let slot = match self.slot(tx_id) {
+            Some(slot) => slot,
+            None => return Ok(None),
+        };
+        let tx_type = match slot.tx_type {
+            0 => TxType::Deposit,
+            1 => TxType::Withdrawal,
+            2 => TxType::Dispute,
+            3 => TxType::Resolve,
+            4 => TxType::Chargeback,
+            5 => TxType::Transfer,
+            6 => TxType::Authorize,
+            7 => TxType::Capture,
+            _ => TxType::Void,
+        };
+        let amount = (slot.flags & HAS_AMOUNT != 0).then_some(slot.amount);
+        let mut record = InputRecord::new(
+            tx_type,
+            slot.client_id,
+            tx_id,
+            amount.map(Amount::from_ten_thousandths),
+        );
+        record.state = match slot.state {
+            0 => TxState::NotApplied,
+            1 => TxState::Applied,
+            2 => TxState::InDispute,
+            3 => TxState::ChargedBack,
+            4 => TxState::Authorized,
+            5 => TxState::Captured,
+            _ => TxState::Voided,
+        };
+        if let Some((disputed, charged_back, captured)) = self.tracked_amounts.get(&tx_id) {
+            record.disputed = *disputed;
+            record.charged_back = *charged_back;
+            record.captured = *captured;
+        }
+        record.destination = (slot.flags & HAS_DESTINATION != 0).then_some(slot.destination);
+        Ok(Some(record))
+    }
+
+    fn insert(&mut self, record: InputRecord) -> io::Result<()> {
+        let tx_type: u8 = match record.tx_type {
+            TxType::Deposit => 0,
+            TxType::Withdrawal => 1,
+            TxType::Dispute => 2,
+            TxType::Resolve => 3,
+            TxType::Chargeback => 4,
+            TxType::Transfer => 5,
+            TxType::Authorize => 6,
+            TxType::Capture => 7,
+            TxType::Void => 8,
+        };
+        let state: u8 = match record.state {
+            TxState::NotApplied => 0,
+            TxState::Applied => 1,
+            TxState::InDispute => 2,
+            TxState::ChargedBack => 3,
+            TxState::Authorized => 4,
+            TxState::Captured => 5,
+            TxState::Voided => 6,
+        };
+        let mut flags = OCCUPIED;
+        if record.amount.is_some() {
+            flags |= HAS_AMOUNT;
+        }
+        if record.destination.is_some() {
+            flags |= HAS_DESTINATION;
+        }
+        let slot = Slot {
+            amount: record.amount.unwrap_or(Amount::ZERO).ten_thousandths(),
+            client_id: record.client_id,
+            destination: record.destination.unwrap_or(0),
+            flags,
+            tx_type,
+            state,
+        };
+        self.set_slot(record.tx_id, slot);
+
+        if record.disputed == Amount::ZERO
+            && record.charged_back == Amount::ZERO
+            && record.captured == Amount::ZERO
+        {
+            self.tracked_amounts.remove(&record.tx_id);
+        } else {
+            self.tracked_amounts.insert(
+                record.tx_id,
+                (record.disputed, record.charged_back, record.captured),
+            );
+        }
+        Ok(())
+    }
+
+    fn contains(&self, tx_id: u32) -> io::Result<bool> {
+        Ok(self.slot(tx_id).is_some())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(InputRecord) -> io::Result<()>) -> io::Result<()> {
+        let paged_ids = self.pages.iter().enumerate().flat_map(|(page_id, page)| {
+            let first_tx_id = (page_id as u32) << PAGE_BITS;
+            page.iter()
+                .flat_map(|page| page.iter().enumerate())
+                .map(move |(index, _)| first_tx_id + index as u32)
+        });
+        for tx_id in paged_ids.chain(self.sparse.keys().copied()) {
+            if let Some(record) = self.get(tx_id)? {
+                f(record)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_same(stored: &InputRecord, record: &InputRecord) {
+        assert_eq!(stored.tx_type, record.tx_type);
+        assert_eq!(stored.state, record.state);
+        assert_eq!(stored.disputed, record.disputed);
+        assert_eq!(stored.charged_back, record.charged_back);
+        assert_eq!(stored.captured, record.captured);
+        assert_eq!(stored.client_id, record.client_id);
+        assert_eq!(stored.tx_id, record.tx_id);
+        assert_eq!(stored.amount, record.amount);
+        assert_eq!(stored.destination, record.destination);
+    }
+
+    // Records come back the same from sparse IDs and from pages, including once a page fills up
+    // enough to be allocated.
+    #[test]
+    fn round_trip_test() {
+        assert_eq!(std::mem::size_of::<Slot>(), 16);
+
+        let mut store = DenseTxStore::new();
+        let mut records = Vec::new();
+        let sparse_ids = [u32::MAX, 1 << 30, 12_345_678];
+        let dense_ids = (0..PROMOTE_LEN * 2).map(|tx_id| tx_id * 3);
+        for tx_id in sparse_ids.into_iter().chain(dense_ids) {
+            let mut record = InputRecord::new(
+                if tx_id % 2 == 0 {
+                    TxType::Withdrawal
+                } else {
+                    TxType::Deposit
+                },
+                tx_id as u16,
+                tx_id,
+                (tx_id % 5 != 0).then(|| Amount::from_ten_thousandths(-i64::from(tx_id))),
+            );
+            record.state = TxState::Applied;
+            store.insert(record.clone()).unwrap();
+            records.push(record);
+        }
+        // The first page filled up enough to be allocated, the rest are still sparse.
+        assert_eq!(store.pages.iter().flatten().count(), 1);
+        assert_eq!(
+            store.sparse.len(),
+            records
+                .iter()
+                .filter(|record| record.tx_id >= PAGE_LEN as u32)
+                .count()
+        );
+
+        let mut updated = records[0].clone();
+        updated.state = TxState::InDispute;
+        updated.disputed = Amount::from_ten_thousandths(i64::MAX);
+        updated.charged_back = Amount::from_ten_thousandths(i64::MIN);
+        store.insert(updated.clone()).unwrap();
+        records[0] = updated;
+        let mut updated = records[10].clone();
+        updated.state = TxState::ChargedBack;
+        updated.charged_back = Amount::from_ten_thousandths(5);
+        store.insert(updated.clone()).unwrap();
+        records[10] = updated;
+        // A partly captured authorization.
+        let mut updated = records[11].clone();
+        updated.tx_type = TxType::Authorize;
+        updated.state = TxState::Authorized;
+        updated.captured = Amount::from_ten_thousandths(3);
+        store.insert(updated.clone()).unwrap();
+        records[11] = updated;
+        // Transfers on an allocated page and a sparse ID.
+        for tx_id in [7, 1 << 29] {
+            let mut record =
+                InputRecord::transfer(3, u16::MAX, tx_id, Amount::from_ten_thousandths(1));
+            record.state = TxState::Applied;
+            store.insert(record.clone()).unwrap();
+            records.push(record);
+        }
+
+        for record in &records {
+            assert_same(&store.get(record.tx_id).unwrap().unwrap(), record);
+        }
+        let mut stored = HashMap::new();
+        store
+            .for_each(&mut |record| {
+                stored.insert(record.tx_id, record);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(stored.len(), records.len());
+        for record in &records {
+            assert_same(&stored[&record.tx_id], record);
+        }
+        assert!(!store.contains(1).unwrap());
+        assert!(!store.contains(u32::MAX - 1).unwrap());
+        assert!(store.get(PROMOTE_LEN * 4 + 1).unwrap().is_none());
+    }
+}
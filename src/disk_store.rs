@@ -0,0 +1,394 @@
+use crate::amount::Amount;
+use crate::store::TxStore;
+use crate::transaction::{InputRecord, TxState, TxType};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path;
+
+/// The size of one encoded transaction in the log.
+pub(crate) const RECORD_LEN: usize = 44;
+/// The size of one slot in the index file.
+const INDEX_SLOT_LEN: u64 = 8;
+/// Appended records are buffered in memory until there are this many bytes of them.
+const FLUSH_BYTES: usize = 64 * 1024;
+
+/// Keeps transactions on disk so that the transaction history doesn't have to fit in memory.
+///
+/// Every insert appends the full record to `tx.log`, so a transaction's latest version is always the
+/// last one written. `tx.idx` has one 8 byte slot per transaction ID, at `tx_id * 8`, holding the
+/// offset of the latest version plus one, with zero meaning the transaction was never stored. The
+/// index file is sparse, so unused IDs take no disk space. Only the most recently used records are
+/// cached in memory.
+pub struct DiskTxStore {
+    log: fs::File,
+    index: fs::File,
+    // The length of the log on disk, pending records come after it.
+    flushed_log_len: u64,
+    pending_log: Vec<u8>,
+    // Index slots that haven't been written to the index file yet.
+    pending_index: HashMap<u32, u64>,
+    cache: RefCell<Cache>,
+}
+
+/// A bounded cache of records, evicting the oldest entry first.
+struct Cache {
+    records: HashMap<u32, InputRecord>,
+    order: VecDeque<u32>,
+    capacity: usize,
+}
+
+impl Cache {
+    fn get(&self, tx_id: u32) -> Option<&InputRecord> {
+        self.records.get(&tx_id)
+    }
+
+    fn put(&mut self, record: InputRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        let tx_id = record.tx_id;
+        if self.records.insert(tx_id, record).is_none() {
+            self.order.push_back(tx_id);
+            if self.order.len() > self.capacity {
+                let evicted = self.order.pop_front().unwrap();
+                self.records.remove(&evicted);
+            }
+        }
+    }
+}
+
+impl DiskTxStore {
+    /// Creates an empty store in `dir`, replacing any store that was there.
+    /// * `cache_capacity` - How many records to keep in memory.
+    pub fn create(dir: &path::Path, cache_capacity: usize) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let open = |name: &str| {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(dir.join(name))
+        };
+        Ok(Self {
+            log: open("tx.log")?,
+            index: open("tx.idx")?,
+            flushed_log_len: 0,
+            pending_log: Vec::with_capacity(FLUSH_BYTES + RECORD_LEN),
+            pending_index: HashMap::new(),
+            cache: RefCell::new(Cache {
+                records: HashMap::new(),
+                order: VecDeque::new(),
+                capacity: cache_capacity,
+            }),
+        })
+    }
+
+    /// Returns the log offset of the latest version of `tx_id`.
+    fn offset_of(&self, tx_id: u32) -> io::Result<Option<u64>> {
+        if let Some(offset) = self.pending_index.get(&tx_id) {
+            return Ok(Some(*offset));
+        }
+        let mut slot = [0; INDEX_SLOT_LEN as usize];
+        let mut index = &self.index;
+        index.seek(SeekFrom::Start(u64::from(tx_id) * INDEX_SLOT_LEN))?;
+        // Slots past the end of the index file were never written.
+        if read_full(&mut index, &mut slot)? < slot.len() {
+            return Ok(None);
+        }
+        Ok(u64::from_le_bytes(slot).checked_sub(1))
+    }
+
+    fn read_record(&self, offset: u64) -> io::Result<InputRecord> {
+        let mut buf = [0; RECORD_LEN];
+        if offset >= self.flushed_log_len {
+            let start = (offset - self.flushed_log_len) as usize;
+            buf.copy_from_slice(&self.pending_log[start..start + RECORD_LEN]);
+        } else {
+            let mut log = &self.log;
+            log.seek(SeekFrom::Start(offset))?;
+            log.read_exact(&mut buf)?;
+        }
+        decode(&buf)
+    }
+}
+
+impl TxStore for DiskTxStore {
+    fn get(&self, tx_id: u32) -> io::Result<Option<InputRecord>> {
+        if let Some(record) = self.cache.borrow().get(tx_id) {
+            return Ok(Some(record.clone()));
+        }
+        let record = match self.offset_of(tx_id)? {
+            Some(offset) => self.read_record(offset)?,
+            None => return Ok(None),
+        };
+        self.cache.borrow_mut().put(record.clone());
+        Ok(Some(record))
+    }
+
+    fn insert(&mut self, record: InputRecord) -> io::Result<()> {
+        let offset = self.flushed_log_len + self.pending_log.len() as u64;
+        self.pending_log.extend_from_slice(&encode(&record));
+        self.pending_index.insert(record.tx_id, offset);
+        self.cache.get_mut().put(record);
+        if self.pending_log.len() >= FLUSH_BYTES {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(InputRecord) -> io::Result<()>) -> io::Result<()> {
+        // Every version of every transaction is in the log, only the one the index points to is
+        // the latest.
+        let mut log = &self.log;
+        log.seek(SeekFrom::Start(0))?;
+        let mut reader = io::BufReader::new(log).take(self.flushed_log_len);
+        let mut buf = [0; RECORD_LEN];
+        let mut offset = 0;
+        while offset < self.flushed_log_len {
+            reader.read_exact(&mut buf)?;
+            let record = decode(&buf)?;
+            if self.offset_of(record.tx_id)? == Some(offset) {
+                f(record)?;
+            }
+            offset += RECORD_LEN as u64;
+        }
+        for chunk in self.pending_log.chunks_exact(RECORD_LEN) {
+            let record = decode(chunk.try_into().unwrap())?;
+            if self.offset_of(record.tx_id)? == Some(offset) {
+                f(record)?;
+            }
+            offset += RECORD_LEN as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes buffered records and index slots to disk.
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.pending_log.is_empty() {
+            self.log.seek(SeekFrom::Start(self.flushed_log_len))?;
+            self.log.write_all(&self.pending_log)?;
+            self.flushed_log_len += self.pending_log.len() as u64;
+            self.pending_log.clear();
+        }
+        // A slot stays pending until it's written, so a failed flush can be retried and `get` still
+        // finds the records in the meantime.
+        let mut slots: Vec<(u32, u64)> = self
+            .pending_index
+            .iter()
+            .map(|(tx_id, offset)| (*tx_id, *offset))
+            .collect();
+        slots.sort_unstable();
+        for (tx_id, offset) in slots {
+            self.index
+                .seek(SeekFrom::Start(u64::from(tx_id) * INDEX_SLOT_LEN))?;
+            self.index.write_all(&(offset + 1).to_le_bytes())?;
+            self.pending_index.remove(&tx_id);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DiskTxStore {
+    // Only a last resort, the engine flushes the store and reports any error when it finishes an
+    // input or takes a checkpoint.
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+/// Reads until `buf` is full or the reader ends, and returns how many bytes were read.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(read)
+}
+
+pub(crate) fn encode(record: &InputRecord) -> [u8; RECORD_LEN] {
+    let mut buf = [0; RECORD_LEN];
+    buf[0..4].copy_from_slice(&record.tx_id.to_le_bytes());
+    buf[4..6].copy_from_slice(&record.client_id.to_le_bytes());
+    buf[6] = match record.tx_type {
+        TxType::Deposit => 0,
+        TxType::Withdrawal => 1,
+        TxType::Dispute => 2,
+        TxType::Resolve => 3,
+        TxType::Chargeback => 4,
+        TxType::Transfer => 5,
+        TxType::Authorize => 6,
+        TxType::Capture => 7,
+        TxType::Void => 8,
+    };
+    buf[7] = match record.state {
+        TxState::NotApplied => 0,
+        TxState::Applied => 1,
+        TxState::InDispute => 2,
+        TxState::ChargedBack => 3,
+        TxState::Authorized => 4,
+        TxState::Captured => 5,
+        TxState::Voided => 6,
+    };
+    buf[8] = u8::from(record.amount.is_some());
+    let amount = record.amount.unwrap_or(Amount::ZERO);
+    buf[9..17].copy_from_slice(&amount.ten_thousandths().to_le_bytes());
+    buf[17..25].copy_from_slice(&record.disputed.ten_thousandths().to_le_bytes());
+    buf[25..33].copy_from_slice(&record.charged_back.ten_thousandths().to_le_bytes());
+    buf[33] = u8::from(record.destination.is_some());
+    buf[34..36].copy_from_slice(&record.destination.unwrap_or(0).to_le_bytes());
+    buf[36..44].copy_from_slice(&record.captured.ten_thousandths().to_le_bytes());
+    buf
+}
+
+pub(crate) fn decode(buf: &[u8; RECORD_LEN]) -> io::Result<InputRecord> {
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt transaction record");
+    let amount_at = |start: usize| {
+        Amount::from_ten_thousandths(i64::from_le_bytes(
+            buf[start..start + 8].try_into().unwrap(),
+        ))
+    };
+    let tx_type = match buf[6] {
+        0 => TxType::Deposit,
+        1 => TxType::Withdrawal,
+        2 => TxType::Dispute,
+        3 => TxType::Resolve,
+        4 => TxType::Chargeback,
+        5 => TxType::Transfer,
+        6 => TxType::Authorize,
+        7 => TxType::Capture,
+        8 => TxType::Void,
+        _ => return Err(corrupt()),
+    };
+    let state = match buf[7] {
+        0 => TxState::NotApplied,
+        1 => TxState::Applied,
+        2 => TxState::InDispute,
+        3 => TxState::ChargedBack,
+        4 => TxState::Authorized,
+        5 => TxState::Captured,
+        6 => TxState::Voided,
+        _ => return Err(corrupt()),
+    };
+    let mut record = InputRecord::new(
+        tx_type,
+        u16::from_le_bytes([buf[4], buf[5]]),
+        u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+        (buf[8] == 1).then(|| amount_at(9)),
+    );
+    record.state = state;
+    record.disputed = amount_at(17);
+    record.charged_back = amount_at(25);
+    record.destination = (buf[33] == 1).then(|| u16::from_le_bytes([buf[34], buf[35]]));
+    record.captured = amount_at(36);
+    Ok(record)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> path::PathBuf {
+        std::env::temp_dir().join(format!("toy_payment_engine_{name}_{}", std::process::id()))
+    }
+
+    // Records come back the same whether they are read from the cache, the pending buffer or disk.
+    #[test]
+    fn round_trip_test() {
+        let dir = temp_dir("disk_store");
+        let mut store = DiskTxStore::create(&dir, 2).unwrap();
+        let mut records = Vec::new();
+        for tx_id in [7, 0, u32::MAX, 1_000_000] {
+            let mut record = InputRecord::new(
+                TxType::Withdrawal,
+                tx_id as u16,
+                tx_id,
+                Some(Amount::from_ten_thousandths(-i64::from(tx_id))),
+            );
+            record.state = TxState::InDispute;
+            record.disputed = Amount::from_ten_thousandths(i64::MAX);
+            record.charged_back = Amount::from_ten_thousandths(i64::MIN);
+            store.insert(record.clone()).unwrap();
+            records.push(record);
+        }
+        records.push(InputRecord::transfer(
+            1,
+            u16::MAX,
+            9,
+            Amount::from_ten_thousandths(1),
+        ));
+        store.insert(records[4].clone()).unwrap();
+        let mut authorization = InputRecord::new(
+            TxType::Authorize,
+            2,
+            11,
+            Some(Amount::from_ten_thousandths(5)),
+        );
+        authorization.state = TxState::Authorized;
+        authorization.captured = Amount::from_ten_thousandths(2);
+        store.insert(authorization.clone()).unwrap();
+        records.push(authorization);
+        let mut updated = records[0].clone();
+        updated.state = TxState::Applied;
+        updated.amount = None;
+        store.insert(updated.clone()).unwrap();
+        records[0] = updated;
+
+        for flush in [false, true] {
+            if flush {
+                store.flush().unwrap();
+            }
+            for record in &records {
+                let stored = store.get(record.tx_id).unwrap().unwrap();
+                assert_eq!(encode(&stored), encode(record));
+            }
+            assert!(!store.contains(8).unwrap());
+            assert!(store.get(u32::MAX - 1).unwrap().is_none());
+
+            // Only the latest version of each record is visited.
+            let mut stored = HashMap::new();
+            store
+                .for_each(&mut |record| {
+                    assert!(stored.insert(record.tx_id, encode(&record)).is_none());
+                    Ok(())
+                })
+                .unwrap();
+            assert_eq!(stored.len(), records.len());
+            for record in &records {
+                assert_eq!(stored[&record.tx_id], encode(record));
+            }
+        }
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Index slots that fail to be written stay pending, so the records can still be found and a
+    // later flush writes them.
+    #[test]
+    fn failed_flush_test() {
+        let dir = temp_dir("disk_store_failed_flush");
+        let mut store = DiskTxStore::create(&dir, 0).unwrap();
+        let record = InputRecord::new(TxType::Deposit, 1, 3, Some(Amount::from_ten_thousandths(5)));
+        store.insert(record.clone()).unwrap();
+        let writable = std::mem::replace(
+            &mut store.index,
+            fs::File::open(dir.join("tx.idx")).unwrap(),
+        );
+        assert!(store.flush().is_err());
+        assert_eq!(encode(&store.get(3).unwrap().unwrap()), encode(&record));
+
+        store.index = writable;
+        store.flush().unwrap();
+        assert!(store.pending_index.is_empty());
+        assert_eq!(encode(&store.get(3).unwrap().unwrap()), encode(&record));
+        drop(store);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
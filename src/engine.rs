@@ -0,0 +1,2419 @@
+use crate::account::{AccountStatus, OutputRecord};
+use crate::admin::AdminRecord;
+use crate::amount::Amount;
+use crate::error::TxError;
+use crate::fees::{Fee, FeeSchedule, FeeSummary};
+use crate::journal::{Effect, FsyncPolicy, Journal};
+use crate::ledger::{Ledger, LedgerAccount, LedgerError, TrialBalance};
+use crate::overdraft::{OverLimit, OverdraftOutcome, OverdraftPolicies, OverdraftPolicy};
+use crate::rejects::RejectsWriter;
+use crate::statement::{Counterparty, Statement};
+use crate::store::{MemoryTxStore, TxStore};
+use crate::transaction::{InputRecord, JsonInputRecord, TxState, TxType};
+use itertools::Itertools;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path;
+
+/// The payment engine. Owns every client account and the transactions that may later be disputed.
+pub struct Engine<S = MemoryTxStore> {
+    // This maps the client_id to an output record.
+    pub(crate) client_map: HashMap<u16, OutputRecord>,
+    // This maps tx_ids to previously processed transactions. Invalid transactions are not kept.
+    pub(crate) tx_store: S,
+    // Every admin action that was applied, in order.
+    pub(crate) admin_log: Vec<AdminRecord>,
+    // Every change is written here before it is applied, if there is a journal.
+    pub(crate) journal: Option<Journal>,
+    // The postings of every change, if the engine is keeping a ledger.
+    pub(crate) ledger: Option<Ledger>,
+    // Every transaction for one client with its outcome, if a statement is being recorded.
+    pub(crate) statement: Option<Statement>,
+    // The fees charged on transactions, none unless a schedule is set.
+    pub(crate) fee_schedule: FeeSchedule,
+    // Every fee charged so far.
+    pub(crate) fee_summary: FeeSummary,
+    // How far each client's available funds may go below zero.
+    pub(crate) overdraft_policies: OverdraftPolicies,
+    // Every dispute the overdraft policies refused or capped in this run.
+    pub(crate) overdraft_report: Vec<OverdraftOutcome>,
+}
+
+impl Engine {
+    /// Creates an engine that keeps its transaction history in memory.
+    pub fn new() -> Self {
+        Self::with_store(MemoryTxStore::default())
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: TxStore> Engine<S> {
+    /// Creates an engine that keeps its transaction history in `tx_store`.
+    pub fn with_store(tx_store: S) -> Self {
+        Self {
+            client_map: HashMap::new(),
+            tx_store,
+            admin_log: Vec::new(),
+            journal: None,
+            ledger: None,
+            statement: None,
+            fee_schedule: FeeSchedule::default(),
+            fee_summary: FeeSummary::default(),
+            overdraft_policies: OverdraftPolicies::default(),
+            overdraft_report: Vec::new(),
+        }
+    }
+
+    /// Starts keeping a double-entry ledger of every change to a client's balances. Call this
+    /// before processing anything, and before `open_journal` so replayed changes are included.
+    pub fn enable_ledger(&mut self) {
+        self.ledger.get_or_insert_with(Ledger::default);
+    }
+
+    /// Returns the ledger, if the engine is keeping one.
+    pub fn ledger(&self) -> Option<&Ledger> {
+        self.ledger.as_ref()
+    }
+
+    /// Starts recording a statement of every transaction applied for `client_id` from now on,
+    /// including the ones that are refused.
+    pub fn record_statement(&mut self, client_id: u16) {
+        self.statement = Some(Statement::new(client_id));
+    }
+
+    /// Returns the statement, if one is being recorded.
+    pub fn statement(&self) -> Option<&Statement> {
+        self.statement.as_ref()
+    }
+
+    /// Charges the fees in `fee_schedule` on every transaction applied from now on.
+    pub fn set_fee_schedule(&mut self, fee_schedule: FeeSchedule) {
+        self.fee_schedule = fee_schedule;
+    }
+
+    /// Returns every fee charged so far, including the ones replayed from a journal.
+    pub fn fee_summary(&self) -> &FeeSummary {
+        &self.fee_summary
+    }
+
+    /// Limits how far clients' available funds may go below zero from now on.
+    pub fn set_overdraft_policies(&mut self, overdraft_policies: OverdraftPolicies) {
+        self.overdraft_policies = overdraft_policies;
+    }
+
+    /// Returns every dispute the overdraft policies refused or capped, in the order they were
+    /// processed. Like the statement, only transactions processed in this run are included.
+    pub fn overdraft_report(&self) -> &[OverdraftOutcome] {
+        &self.overdraft_report
+    }
+
+    /// Adds up the ledger and checks that the debits match the credits, and that every client's
+    /// available and held balances in the ledger match their account.
+    pub fn trial_balance(&self) -> Result<TrialBalance, LedgerError> {
+        let trial_balance = self
+            .ledger
+            .as_ref()
+            .ok_or(LedgerError::Disabled)?
+            .trial_balance()?;
+        if trial_balance.total_debits != trial_balance.total_credits {
+            return Err(LedgerError::Unbalanced {
+                debits: trial_balance.total_debits,
+                credits: trial_balance.total_credits,
+            });
+        }
+        for (client_id, output_record) in self.accounts() {
+            // Client balances are credited as they grow.
+            let available = trial_balance.balance(LedgerAccount::Available(client_id));
+            let held = trial_balance.balance(LedgerAccount::Held(client_id));
+            if available.checked_neg() != Some(output_record.available)
+                || held.checked_neg() != Some(output_record.held)
+            {
+                return Err(LedgerError::ClientMismatch { client_id });
+            }
+        }
+        Ok(trial_balance)
+    }
+
+    /// Replays the journal at `journal_path` into the engine, which should be new, and from then on
+    /// writes every change to it before applying the change. The journal is created if it doesn't
+    /// exist. A torn entry at the end, e.g. from a crash part way through writing it, is truncated.
+    ///
+    /// Returns the number of entries that were replayed.
+    pub fn open_journal(
+        &mut self,
+        journal_path: &path::Path,
+        fsync: FsyncPolicy,
+    ) -> io::Result<u64> {
+        let journal = Journal::open(journal_path, fsync, |effect| self.apply_effect(effect))?;
+        let replayed = journal.entries();
+        self.journal = Some(journal);
+        Ok(replayed)
+    }
+
+    /// Writes anything the transaction store buffers, so that a failed write is reported rather
+    /// than lost. Every input processed from a file or reader ends with this.
+    pub fn flush_store(&mut self) -> io::Result<()> {
+        self.tx_store.flush()
+    }
+
+    /// Syncs the journal to disk, unless its policy is to never sync.
+    pub fn sync_journal(&mut self) -> io::Result<()> {
+        self.journal.as_mut().map_or(Ok(()), Journal::sync)
+    }
+
+    /// Returns the transaction store.
+    pub fn tx_store(&self) -> &S {
+        &self.tx_store
+    }
+
+    /// Returns the account of `client_id`, if the engine has seen that client.
+    pub fn account(&self, client_id: u16) -> Option<&OutputRecord> {
+        self.client_map.get(&client_id)
+    }
+
+    /// Returns every client account, sorted by client id.
+    pub fn accounts(&self) -> impl Iterator<Item = (u16, &OutputRecord)> {
+        self.client_map
+            .iter()
+            .map(|(client_id, output_record)| (*client_id, output_record))
+            .sorted_by_key(|(client_id, _)| *client_id)
+    }
+
+    /// Returns a previously processed deposit, withdrawal or transfer.
+    pub fn transaction(&self, tx_id: u32) -> io::Result<Option<InputRecord>> {
+        self.tx_store.get(tx_id)
+    }
+
+    /// Returns every admin action that was applied, in order.
+    pub fn admin_log(&self) -> &[AdminRecord] {
+        &self.admin_log
+    }
+
+    /// Returns an error if the client account's status refuses `tx_type` transactions.
+    fn check_client_status(
+        &self,
+        client_id: u16,
+        tx_id: u32,
+        tx_type: TxType,
+    ) -> Result<(), TxError> {
+        match self
+            .client_map
+            .get(&client_id)
+            .map(|output_record| output_record.status)
+        {
+            Some(AccountStatus::Locked) => Err(TxError::AccountLocked { client_id, tx_id }),
+            Some(AccountStatus::Closed) => Err(TxError::AccountClosed { client_id, tx_id }),
+            // A frozen account still accepts disputes, captures and voids so that they can be settled.
+            Some(AccountStatus::Frozen)
+                if matches!(
+                    tx_type,
+                    TxType::Deposit | TxType::Withdrawal | TxType::Transfer | TxType::Authorize
+                ) =>
+            {
+                Err(TxError::AccountFrozen { client_id, tx_id })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Process the csv file pointed to by `csv_file_path` and update the client accounts.
+    /// * `csv_file_path` - A path to the csv file.
+    ///
+    /// Fails if the file can't be opened or read.
+    pub fn process_csv_file(&mut self, csv_file_path: &path::Path) -> Result<(), csv::Error> {
+        let file = fs::File::open(csv_file_path)?;
+        self.process_csv_reader(io::BufReader::new(file))
+    }
+
+    /// Same as `process_csv_file`, but every refused or unparseable row is written to `rejects`.
+    /// * `csv_file_path` - A path to the csv file.
+    /// * `rejects` - The sink for refused rows.
+    pub fn process_csv_file_with_rejects<W: io::Write>(
+        &mut self,
+        csv_file_path: &path::Path,
+        rejects: &mut RejectsWriter<W>,
+    ) -> Result<(), csv::Error> {
+        let file = fs::File::open(csv_file_path)?;
+        let source = csv_file_path.display().to_string();
+        self.process_csv(
+            csv_reader(io::BufReader::new(file)),
+            &source,
+            Some(rejects),
+            None,
+            Self::process_input_record,
+        )
+    }
+
+    /// Same as `process_csv_file_with_rejects`, for long runs that may have to be resumed.
+    ///
+    /// Processing starts at `start`, a position an earlier run passed to `checkpoint`, or at the
+    /// first row if there is none. After every `interval` rows `rejects` is flushed and `checkpoint`
+    /// is called with the position of the next row, e.g. to save the engine state.
+    pub fn process_csv_file_resumable<W: io::Write>(
+        &mut self,
+        csv_file_path: &path::Path,
+        rejects: Option<&mut RejectsWriter<W>>,
+        start: Option<&csv::Position>,
+        interval: u64,
+        mut checkpoint: impl FnMut(&Self, &csv::Position) -> io::Result<()>,
+    ) -> Result<(), csv::Error> {
+        let file = fs::File::open(csv_file_path)?;
+        let source = csv_file_path.display().to_string();
+        let mut csv_reader = csv_reader(io::BufReader::new(file));
+        if let Some(start) = start {
+            csv_reader.seek(start.clone())?;
+        }
+        self.process_csv(
+            csv_reader,
+            &source,
+            rejects,
+            Some((interval, &mut checkpoint)),
+            Self::process_input_record,
+        )
+    }
+
+    /// Process csv transactions read from `reader`, e.g. stdin, and update the client accounts.
+    pub fn process_csv_reader(&mut self, reader: impl io::Read) -> Result<(), csv::Error> {
+        self.process_csv(
+            csv_reader(reader),
+            "",
+            None::<&mut RejectsWriter<io::Sink>>,
+            None,
+            Self::process_input_record,
+        )
+    }
+
+    /// Same as `process_csv_reader`, but every refused or unparseable row is written to `rejects`.
+    /// * `source` - The name of the input written alongside each rejected row.
+    pub fn process_csv_reader_with_rejects<W: io::Write>(
+        &mut self,
+        reader: impl io::Read,
+        source: &str,
+        rejects: &mut RejectsWriter<W>,
+    ) -> Result<(), csv::Error> {
+        self.process_csv(
+            csv_reader(reader),
+            source,
+            Some(rejects),
+            None,
+            Self::process_input_record,
+        )
+    }
+
+    /// Process the JSON Lines file pointed to by `json_file_path` and update the client accounts.
+    /// Every line is an object with the fields `type`, `client`, `tx` and `amount`, like the csv
+    /// columns. Blank lines are skipped.
+    ///
+    /// Fails if the file can't be opened or read.
+    pub fn process_json_lines_file(&mut self, json_file_path: &path::Path) -> io::Result<()> {
+        let file = fs::File::open(json_file_path)?;
+        self.process_json_lines_reader(io::BufReader::new(file))
+    }
+
+    /// Same as `process_csv_file_resumable`, for a JSON Lines file.
+    pub fn process_json_lines_file_resumable<W: io::Write>(
+        &mut self,
+        json_file_path: &path::Path,
+        rejects: Option<&mut RejectsWriter<W>>,
+        start: Option<&csv::Position>,
+        interval: u64,
+        mut checkpoint: impl FnMut(&Self, &csv::Position) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut file = fs::File::open(json_file_path)?;
+        let source = json_file_path.display().to_string();
+        if let Some(start) = start {
+            io::Seek::seek(&mut file, io::SeekFrom::Start(start.byte()))?;
+        }
+        self.process_json_lines(
+            io::BufReader::new(file),
+            &source,
+            rejects,
+            start,
+            Some((interval, &mut checkpoint)),
+        )
+    }
+
+    /// Process JSON Lines transactions read from `reader`, e.g. stdin, and update the client accounts.
+    pub fn process_json_lines_reader(&mut self, reader: impl io::BufRead) -> io::Result<()> {
+        self.process_json_lines(reader, "", None::<&mut RejectsWriter<io::Sink>>, None, None)
+    }
+
+    /// Same as `process_json_lines_reader`, but every refused or malformed line is written to `rejects`.
+    /// * `source` - The name of the input written alongside each rejected line.
+    pub fn process_json_lines_reader_with_rejects<W: io::Write>(
+        &mut self,
+        reader: impl io::BufRead,
+        source: &str,
+        rejects: &mut RejectsWriter<W>,
+    ) -> io::Result<()> {
+        self.process_json_lines(reader, source, Some(rejects), None, None)
+    }
+
+    /// Process the admin actions in the csv file pointed to by `csv_file_path`. The file has the
+    /// columns `action`, `client`, `operator` and `reason`.
+    ///
+    /// This must only be given operator input, it is how locked accounts get unlocked.
+    /// * `rejects` - The sink for refused rows, if any.
+    pub fn process_admin_csv_file<W: io::Write>(
+        &mut self,
+        csv_file_path: &path::Path,
+        rejects: Option<&mut RejectsWriter<W>>,
+    ) -> Result<(), csv::Error> {
+        let file = fs::File::open(csv_file_path)?;
+        let source = csv_file_path.display().to_string();
+        self.process_csv(
+            csv_reader(io::BufReader::new(file)),
+            &source,
+            rejects,
+            None,
+            |engine, record_res: Result<AdminRecord, TxError>| engine.apply_admin(record_res?),
+        )
+    }
+
+    /// Reads every row of `csv_reader` as a `T` and hands it to `process`, reporting refused rows to
+    /// `rejects`. If there is a `checkpoint`, it is called after every `interval` rows.
+    fn process_csv<T: DeserializeOwned, R: io::Read, W: io::Write>(
+        &mut self,
+        mut csv_reader: csv::Reader<R>,
+        source: &str,
+        mut rejects: Option<&mut RejectsWriter<W>>,
+        mut checkpoint: Option<Checkpoint<'_, Self>>,
+        mut process: impl FnMut(&mut Self, Result<T, TxError>) -> Result<(), TxError>,
+    ) -> Result<(), csv::Error> {
+        let headers = csv_reader.headers()?.clone();
+
+        let mut string_record = csv::StringRecord::new();
+        let mut rows: u64 = 0;
+        while let Some((line, raw_record, record_res)) =
+            read_row(&mut csv_reader, &headers, &mut string_record)?
+        {
+            // Transactions which fail are ignored unless there is somewhere to report them.
+            if let Err(error) = process(self, record_res) {
+                if let Some(rejects) = rejects.as_deref_mut() {
+                    rejects.write(source, line, &raw_record, &error)?;
+                }
+            }
+
+            rows += 1;
+            if let Some((interval, checkpoint)) = checkpoint.as_mut() {
+                if rows.checked_rem(*interval) == Some(0) {
+                    // Everything rejected or stored so far must be written before the checkpoint
+                    // says so.
+                    if let Some(rejects) = rejects.as_deref_mut() {
+                        rejects.flush()?;
+                    }
+                    self.flush_store()?;
+                    checkpoint(self, csv_reader.position())?;
+                }
+            }
+        }
+        self.flush_store()?;
+        Ok(())
+    }
+
+    /// Reads every line of `reader` as a JSON transaction and processes it, reporting refused lines
+    /// to `rejects`. `start` is where `reader` starts in the input, if it doesn't start at the
+    /// beginning. If there is a `checkpoint`, it is called after every `interval` transactions.
+    fn process_json_lines<R: io::BufRead, W: io::Write>(
+        &mut self,
+        mut reader: R,
+        source: &str,
+        mut rejects: Option<&mut RejectsWriter<W>>,
+        start: Option<&csv::Position>,
+        mut checkpoint: Option<Checkpoint<'_, Self>>,
+    ) -> io::Result<()> {
+        // Where the next line starts. It is a csv position so that checkpoints work the same for
+        // both formats.
+        let mut position = start.cloned().unwrap_or_else(csv::Position::new);
+        let mut buf = Vec::new();
+        let mut rows: u64 = 0;
+        loop {
+            buf.clear();
+            let len = reader.read_until(b'\n', &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            let line = position.line();
+            position
+                .set_byte(position.byte() + len as u64)
+                .set_line(line + 1);
+            let raw_record = buf.trim_ascii();
+            if raw_record.is_empty() {
+                continue;
+            }
+            position.set_record(position.record() + 1);
+
+            if let Err(error) = self.process_input_record(parse_json_line(raw_record)) {
+                if let Some(rejects) = rejects.as_deref_mut() {
+                    rejects.write(source, line, &String::from_utf8_lossy(raw_record), &error)?;
+                }
+            }
+
+            rows += 1;
+            if let Some((interval, checkpoint)) = checkpoint.as_mut() {
+                if rows.checked_rem(*interval) == Some(0) {
+                    if let Some(rejects) = rejects.as_deref_mut() {
+                        rejects.flush()?;
+                    }
+                    self.flush_store()?;
+                    checkpoint(self, &position)?;
+                }
+            }
+        }
+        self.flush_store()
+    }
+
+    /// Process the input record.
+    /// # Arguments
+    ///
+    /// * `record_res` - A result from the csv deserializer. If the result is an error, the record is ignored.
+    ///
+    /// Returns the reason the transaction was refused, if it was.
+    pub fn process_input_record<E: Into<TxError>>(
+        &mut self,
+        record_res: Result<InputRecord, E>,
+    ) -> Result<(), TxError> {
+        // If there is an error parsing the input (e.g client_id is missing), we assume it's erroneous and ignore it.
+        let record = record_res.map_err(Into::into)?;
+
+        self.apply(record)
+    }
+
+    /// Applies a single transaction to the engine state.
+    pub fn apply(&mut self, record: InputRecord) -> Result<(), TxError> {
+        // The handlers take the record, keep a copy for the statement. Whether it's on the
+        // statement can depend on the transfer it refers to, so that is decided afterwards.
+        let statement_record = self
+            .statement
+            .is_some()
+            .then(|| (record.clone(), self.fee_summary.total().charged));
+        let result = match &record.tx_type {
+            TxType::Deposit => self.handle_deposit(record),
+            TxType::Withdrawal => self.handle_withdraw(record),
+            TxType::Transfer => self.handle_transfer(record),
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                self.handle_dispute_transition(&record)
+            }
+            TxType::Authorize => self.handle_authorize(record),
+            TxType::Capture | TxType::Void => self.handle_authorization_step(&record),
+        };
+        if let Some((record, charged_before)) = statement_record {
+            // The fees charged grew by whatever this transaction charged.
+            let fee = self
+                .fee_summary
+                .total()
+                .charged
+                .checked_sub(charged_before)
+                .filter(|fee| *fee != Amount::ZERO);
+            self.record_statement_line(&record, &result, fee);
+        }
+        result
+    }
+
+    /// Refuses `record` with `error` without applying it, for a transaction that was checked
+    /// somewhere else. It is still recorded on the statement.
+    pub(crate) fn refuse(&mut self, record: &InputRecord, error: TxError) -> Result<(), TxError> {
+        let result = Err(error);
+        self.record_statement_line(record, &result, None);
+        result
+    }
+
+    /// Adds `record` to the statement if it concerns the statement's client. `fee` is what the
+    /// transaction charged to the client that made it.
+    fn record_statement_line(
+        &mut self,
+        record: &InputRecord,
+        result: &Result<(), TxError>,
+        fee: Option<Amount>,
+    ) {
+        let client_id = match &self.statement {
+            Some(statement) => statement.client_id(),
+            None => return,
+        };
+        let counterparty = self.counterparty(record, result, client_id);
+        if record.client_id != client_id && counterparty.is_none() {
+            return;
+        }
+        let fee = fee.filter(|_| record.client_id == client_id);
+        if let Some(statement) = &mut self.statement {
+            statement.record(
+                record,
+                result,
+                counterparty,
+                fee,
+                self.client_map.get(&client_id),
+            );
+        }
+    }
+
+    /// Returns the other client of a transfer, or of the transfer a dispute, resolve or chargeback
+    /// is for, as seen by `client_id`. Steps on a transfer to `client_id` only count once they were
+    /// applied, since they don't concern them until then.
+    fn counterparty(
+        &self,
+        record: &InputRecord,
+        result: &Result<(), TxError>,
+        client_id: u16,
+    ) -> Option<Counterparty> {
+        let (source, destination) = match record.tx_type {
+            TxType::Deposit
+            | TxType::Withdrawal
+            | TxType::Authorize
+            | TxType::Capture
+            | TxType::Void => return None,
+            TxType::Transfer => (record.client_id, record.destination?),
+            TxType::Dispute | TxType::Resolve | TxType::Chargeback => {
+                let transfer = self.tx_store.get(record.tx_id).ok()??;
+                if transfer.tx_type != TxType::Transfer || transfer.client_id != record.client_id {
+                    return None;
+                }
+                (transfer.client_id, transfer.destination?)
+            }
+        };
+        if source == client_id {
+            Some(Counterparty::To(destination))
+        } else if destination == client_id && result.is_ok() {
+            Some(Counterparty::From(source))
+        } else {
+            None
+        }
+    }
+
+    /// Applies an admin action from an operator to a client account and records it in the admin log.
+    pub fn apply_admin(&mut self, record: AdminRecord) -> Result<(), TxError> {
+        let client_id = record.client_id;
+        // Every admin action has to be attributable.
+        if record.operator.trim().is_empty() || record.reason.trim().is_empty() {
+            return Err(TxError::MissingAuditInfo { client_id });
+        }
+        let mut output_record = *self
+            .client_map
+            .get(&client_id)
+            .ok_or(TxError::UnknownClient { client_id })?;
+        output_record.status = record.action.next_status(output_record.status).ok_or(
+            TxError::InvalidAccountStatus {
+                client_id,
+                status: output_record.status,
+            },
+        )?;
+        self.commit(
+            Effect {
+                client_id,
+                tx_type: None,
+                account: Some(output_record),
+                tx: None,
+                admin: Some(record),
+                counterparty: None,
+                fee: None,
+            },
+            0,
+        )
+    }
+
+    /// Journals `effect`, if there is a journal, and applies it.
+    /// * `tx_id` - The transaction reported if this fails.
+    fn commit(&mut self, effect: Effect, tx_id: u32) -> Result<(), TxError> {
+        let client_id = effect.client_id;
+        if let Some(journal) = self.journal.as_mut() {
+            journal
+                .append(&effect)
+                .map_err(|error| TxError::storage(client_id, tx_id, &error))?;
+        }
+        self.apply_effect(effect).map_err(|error| {
+            // Nothing was applied, so the entry mustn't be replayed either. If it can't be
+            // removed, the journal refuses any further entries.
+            if let Some(journal) = self.journal.as_mut() {
+                let _ = journal.undo_last();
+            }
+            TxError::storage(client_id, tx_id, &error)
+        })
+    }
+
+    fn apply_effect(&mut self, effect: Effect) -> io::Result<()> {
+        let stored_tx = effect
+            .tx
+            .as_ref()
+            .map(|record| (record.tx_id, record.tx_type));
+        // Storing the transaction is the only step that can fail, so nothing else is recorded
+        // unless it succeeds.
+        if let Some(record) = effect.tx {
+            self.tx_store.insert(record)?;
+        }
+        if let (Some(ledger), Some(tx_type)) = (self.ledger.as_mut(), effect.tx_type) {
+            let (tx_id, disputed_tx_type) = stored_tx.unwrap_or((0, tx_type));
+            let fee = effect
+                .fee
+                .map(|fee| fee.charged)
+                .filter(|&charged| charged != Amount::ZERO);
+            // The transaction is recorded as if the fee hadn't been taken out, and the fee after it.
+            let account = effect.account.map(|mut output_record| {
+                if let Some(fee) = fee {
+                    // The account had the fee before it was charged, so adding it back fits.
+                    output_record.available = output_record.available.checked_add(fee).unwrap();
+                    output_record.total = output_record.total.checked_add(fee).unwrap();
+                }
+                output_record
+            });
+            let accounts = account
+                .iter()
+                .map(|output_record| (effect.client_id, output_record))
+                .chain(
+                    effect
+                        .counterparty
+                        .iter()
+                        .map(|(client_id, output_record)| (*client_id, output_record)),
+                );
+            for (client_id, output_record) in accounts {
+                ledger.record(
+                    tx_id,
+                    tx_type,
+                    disputed_tx_type,
+                    client_id,
+                    self.client_map.get(&client_id),
+                    output_record,
+                );
+            }
+            if let Some(fee) = fee {
+                ledger.record_fee(tx_id, tx_type, effect.client_id, fee);
+            }
+        }
+        if let (Some(fee), Some(tx_type)) = (&effect.fee, effect.tx_type) {
+            self.fee_summary.add(tx_type, fee);
+        }
+        if let Some(output_record) = effect.account {
+            self.client_map.insert(effect.client_id, output_record);
+        }
+        if let Some((client_id, output_record)) = effect.counterparty {
+            self.client_map.insert(client_id, output_record);
+        }
+        if let Some(record) = effect.admin {
+            self.admin_log.push(record);
+        }
+        Ok(())
+    }
+
+    /// Charges the fee on `amount` of a `tx_type` transaction to `output_record`, as much of it as
+    /// the available funds cover. Returns `None` if there is no fee.
+    fn charge_fee(
+        &self,
+        tx_type: TxType,
+        amount: Amount,
+        output_record: &mut OutputRecord,
+        client_id: u16,
+        tx_id: u32,
+    ) -> Result<Option<Fee>, TxError> {
+        let fee = self
+            .fee_schedule
+            .fee(tx_type, amount)
+            .ok_or(TxError::Overflow { client_id, tx_id })?;
+        Ok((fee != Amount::ZERO).then(|| Fee::charge(output_record, fee)))
+    }
+
+    /// Handles deposit transactions
+    fn handle_deposit(&mut self, mut record: InputRecord) -> Result<(), TxError> {
+        let client_id = record.client_id;
+        let tx_id = record.tx_id;
+        // If transaction was already processed or client account refuses it, we fail the transaction.
+        if self
+            .tx_store
+            .contains(tx_id)
+            .map_err(|error| TxError::storage(client_id, tx_id, &error))?
+        {
+            return Err(TxError::DuplicateTxId { client_id, tx_id });
+        }
+        self.check_client_status(client_id, tx_id, record.tx_type)?;
+
+        // if the amount is missing in the input for a deposit, assume it's erroneous and fail the transaction.
+        let amount = match record.amount {
+            Some(amount) => {
+                if amount.is_negative() {
+                    return Err(TxError::NegativeAmount { client_id, tx_id });
+                }
+                amount
+            }
+            None => return Err(TxError::MissingAmount { client_id, tx_id }),
+        };
+
+        // Work out the new balances first so that a deposit which would overflow leaves nothing behind.
+        let mut output_record = match self.client_map.get(&client_id) {
+            Some(output_record) => output_record
+                .checked_adjust(amount, Amount::ZERO)
+                .ok_or(TxError::Overflow { client_id, tx_id })?,
+            None => OutputRecord::new(amount),
+        };
+        let fee = self.charge_fee(
+            TxType::Deposit,
+            amount,
+            &mut output_record,
+            client_id,
+            tx_id,
+        )?;
+
+        record.state = TxState::Applied;
+        // Save the record in case it's later disputed and so we don't process it more than once,
+        // and update the output records.
+        self.commit(
+            Effect {
+                client_id,
+                tx_type: Some(TxType::Deposit),
+                account: Some(output_record),
+                tx: Some(record),
+                admin: None,
+                counterparty: None,
+                fee,
+            },
+            tx_id,
+        )
+    }
+
+    /// Handles withdraw transactions
+    fn handle_withdraw(&mut self, mut record: InputRecord) -> Result<(), TxError> {
+        let client_id = record.client_id;
+        let tx_id = record.tx_id;
+        // If transaction was already processed or client account refuses it, we fail the transaction.
+        // If the client account is frozen, we do not need to store this transaction
+        if self
+            .tx_store
+            .contains(tx_id)
+            .map_err(|error| TxError::storage(client_id, tx_id, &error))?
+        {
+            return Err(TxError::DuplicateTxId { client_id, tx_id });
+        }
+        self.check_client_status(client_id, tx_id, record.tx_type)?;
+
+        // if the amount is missing in the input for a withdrawal, assume it's erroneous and fail the transaction.
+        let amount = match record.amount {
+            Some(amount) => {
+                if amount.is_negative() {
+                    return Err(TxError::NegativeAmount { client_id, tx_id });
+                }
+                amount
+            }
+            None => return Err(TxError::MissingAmount { client_id, tx_id }),
+        };
+
+        // The fee has to be available along with the amount, so it is always charged in full.
+        let fee = self
+            .fee_schedule
+            .fee(TxType::Withdrawal, amount)
+            .ok_or(TxError::Overflow { client_id, tx_id })?;
+        let debit = amount.checked_add(fee);
+        let policy = self.overdraft_policies.policy(client_id);
+
+        // Work out the new output record
+        let (output_record, result) = match self.client_map.get(&client_id) {
+            // if there is not enough funds in the account, with any overdraft, fail the transaction.
+            Some(output_record)
+                if debit.is_none_or(|debit| {
+                    policy
+                        .spendable(output_record.available, TxType::Withdrawal)
+                        .is_some_and(|spendable| debit > spendable)
+                }) =>
+            {
+                (None, Err(TxError::InsufficientFunds { client_id, tx_id }))
+            }
+            // The debit fits and isn't negative, so negating it can't overflow. The fee is debited
+            // along with the amount, since it was checked to be spendable too.
+            Some(output_record) => {
+                match output_record
+                    .checked_adjust(debit.unwrap().checked_neg().unwrap(), Amount::ZERO)
+                {
+                    Some(updated) => (Some(updated), Ok(())),
+                    None => (None, Err(TxError::Overflow { client_id, tx_id })),
+                }
+            }
+            // If there is no record of this client, their asset account may still be valid even if the
+            // transaction should fail. So include this client account in the output with 0 funds.
+            None => (
+                Some(OutputRecord::new(Amount::ZERO)),
+                Err(TxError::InsufficientFunds { client_id, tx_id }),
+            ),
+        };
+
+        // Only a withdrawal that moved funds can be disputed later.
+        if result.is_ok() {
+            record.state = TxState::Applied;
+        }
+        let fee = (result.is_ok() && fee != Amount::ZERO).then_some(Fee {
+            charged: fee,
+            waived: Amount::ZERO,
+        });
+        // Save the record so that we don't process this transaction twice in case we receive same transaction ID more than once.
+        self.commit(
+            Effect {
+                client_id,
+                tx_type: Some(TxType::Withdrawal),
+                account: output_record,
+                tx: Some(record),
+                admin: None,
+                counterparty: None,
+                fee,
+            },
+            tx_id,
+        )?;
+        result
+    }
+
+    /// Handles transfers. Both accounts are checked before anything changes, so the transfer is
+    /// either applied to both or refused as a whole.
+    fn handle_transfer(&mut self, mut record: InputRecord) -> Result<(), TxError> {
+        let client_id = record.client_id;
+        let tx_id = record.tx_id;
+        if self
+            .tx_store
+            .contains(tx_id)
+            .map_err(|error| TxError::storage(client_id, tx_id, &error))?
+        {
+            return Err(TxError::DuplicateTxId { client_id, tx_id });
+        }
+        let destination = match record.destination {
+            Some(destination) if destination != client_id => destination,
+            _ => return Err(TxError::InvalidDestination { client_id, tx_id }),
+        };
+        self.check_client_status(client_id, tx_id, record.tx_type)?;
+        self.check_client_status(destination, tx_id, record.tx_type)?;
+
+        let amount = match record.amount {
+            Some(amount) if amount.is_negative() => {
+                return Err(TxError::NegativeAmount { client_id, tx_id })
+            }
+            Some(amount) => amount,
+            None => return Err(TxError::MissingAmount { client_id, tx_id }),
+        };
+
+        // As with a withdrawal, the funds have to be available in the source account, with any
+        // overdraft.
+        let policy = self.overdraft_policies.policy(client_id);
+        let source_record = match self.client_map.get(&client_id) {
+            Some(output_record)
+                if policy
+                    .spendable(output_record.available, TxType::Transfer)
+                    .is_none_or(|spendable| amount <= spendable) =>
+            {
+                *output_record
+            }
+            source_record => {
+                // Like a withdrawal, a refused transfer is remembered so its ID isn't reused.
+                self.commit(
+                    Effect {
+                        client_id,
+                        tx_type: Some(TxType::Transfer),
+                        account: source_record
+                            .is_none()
+                            .then_some(OutputRecord::new(Amount::ZERO)),
+                        tx: Some(record),
+                        admin: None,
+                        counterparty: None,
+                        fee: None,
+                    },
+                    tx_id,
+                )?;
+                return Err(TxError::InsufficientFunds { client_id, tx_id });
+            }
+        };
+        // The amount was checked to not be negative, so negating it can't overflow.
+        let source_record = source_record
+            .checked_adjust(amount.checked_neg().unwrap(), Amount::ZERO)
+            .ok_or(TxError::Overflow { client_id, tx_id })?;
+        let destination_record = match self.client_map.get(&destination) {
+            Some(output_record) => {
+                output_record
+                    .checked_adjust(amount, Amount::ZERO)
+                    .ok_or(TxError::Overflow {
+                        client_id: destination,
+                        tx_id,
+                    })?
+            }
+            None => OutputRecord::new(amount),
+        };
+
+        record.state = TxState::Applied;
+        self.commit(
+            Effect {
+                client_id,
+                tx_type: Some(TxType::Transfer),
+                account: Some(source_record),
+                tx: Some(record),
+                admin: None,
+                counterparty: Some((destination, destination_record)),
+                fee: None,
+            },
+            tx_id,
+        )
+    }
+
+    /// Handles dispute, resolve and chargeback transactions. These move the transaction they refer
+    /// to through its `TxState`, and move funds depending on whether it was a deposit, a withdrawal
+    /// or a transfer.
+    fn handle_dispute_transition(&mut self, record: &InputRecord) -> Result<(), TxError> {
+        let client_id = record.client_id;
+        let tx_id = record.tx_id;
+        let status_check = self.check_client_status(client_id, tx_id, record.tx_type);
+        let stored_tx_record = self
+            .tx_store
+            .get(tx_id)
+            .map_err(|error| TxError::storage(client_id, tx_id, &error))?;
+        let mut disputed_tx_record = match stored_tx_record {
+            Some(input_record) => input_record,
+            // I assume that this is an erroneous transaction since it's disputing a non-existing transaction.
+            None => return Err(TxError::UnknownTx { client_id, tx_id }),
+        };
+
+        // The client should not be able to dispute transactions that do not belong to their account
+        // and the transaction has to be in a state that allows this step, e.g. only disputed
+        // transactions can be resolved. We also reject handling disputes for accounts which are locked or closed.
+        if disputed_tx_record.client_id != client_id {
+            return Err(TxError::ClientMismatch { client_id, tx_id });
+        }
+        status_check?;
+        // The funds of a transfer move on the destination account, so it has to accept this too.
+        if let (TxType::Transfer, Some(destination)) =
+            (disputed_tx_record.tx_type, disputed_tx_record.destination)
+        {
+            self.check_client_status(destination, tx_id, record.tx_type)?;
+        }
+        let next_state = disputed_tx_record
+            .state
+            .transition(record.tx_type)
+            .ok_or(TxError::InvalidDisputeState { client_id, tx_id })?;
+
+        // If the amount is missing on the input record or the client account
+        // is missing from our output records, this is an unrecoverable error.
+        let tx_amount = disputed_tx_record.amount.unwrap();
+        let client_output_record = *self.client_map.get(&disputed_tx_record.client_id).unwrap();
+
+        // A dispute can cover any part of the transaction that isn't already disputed or charged back,
+        // a resolve or chargeback any part of what is currently disputed. Without an amount on the
+        // row the step covers all of it.
+        let outstanding = disputed_tx_record.disputed;
+        let limit = match record.tx_type {
+            TxType::Dispute => tx_amount
+                .checked_sub(outstanding)
+                .and_then(|undisputed| undisputed.checked_sub(disputed_tx_record.charged_back))
+                .unwrap(),
+            _ => outstanding,
+        };
+        let amount = match record.amount {
+            Some(amount) if amount.is_negative() => {
+                return Err(TxError::NegativeAmount { client_id, tx_id })
+            }
+            Some(amount) => amount,
+            None => limit,
+        };
+        if amount == Amount::ZERO || amount > limit {
+            return Err(TxError::InvalidDisputeAmount { client_id, tx_id });
+        }
+        // Holding deposited or transferred funds takes them out of available, as far as the
+        // overdraft policy of the account holding them allows.
+        let (amount, overdraft_outcome) = match (record.tx_type, disputed_tx_record.destination) {
+            (TxType::Dispute, _) if disputed_tx_record.tx_type == TxType::Deposit => {
+                self.check_overdraft(client_id, tx_id, client_id, amount)?
+            }
+            (TxType::Dispute, Some(destination))
+                if disputed_tx_record.tx_type == TxType::Transfer =>
+            {
+                self.check_overdraft(client_id, tx_id, destination, amount)?
+            }
+            _ => (amount, None),
+        };
+
+        let (available, held) =
+            dispute_adjustment(disputed_tx_record.tx_type, record.tx_type, amount);
+        // The funds of a transfer are held on the destination account and a chargeback returns
+        // them to the source, so the destination is the account that moves.
+        let (mut updated_output_record, counterparty) =
+            match (disputed_tx_record.tx_type, disputed_tx_record.destination) {
+                (TxType::Transfer, Some(destination)) => {
+                    let destination_record = self
+                        .client_map
+                        .get(&destination)
+                        .unwrap()
+                        .checked_adjust(available, held)
+                        .ok_or(TxError::Overflow {
+                            client_id: destination,
+                            tx_id,
+                        })?;
+                    let returned = match record.tx_type {
+                        TxType::Chargeback => amount,
+                        _ => Amount::ZERO,
+                    };
+                    let source_record = client_output_record
+                        .checked_adjust(returned, Amount::ZERO)
+                        .ok_or(TxError::Overflow { client_id, tx_id })?;
+                    (source_record, Some((destination, destination_record)))
+                }
+                _ => (
+                    client_output_record
+                        .checked_adjust(available, held)
+                        .ok_or(TxError::Overflow { client_id, tx_id })?,
+                    None,
+                ),
+            };
+
+        // These stay between zero and the transaction amount, so they can't overflow.
+        match record.tx_type {
+            TxType::Dispute => {
+                disputed_tx_record.disputed = outstanding.checked_add(amount).unwrap()
+            }
+            _ => disputed_tx_record.disputed = outstanding.checked_sub(amount).unwrap(),
+        }
+        let mut fee = None;
+        if record.tx_type == TxType::Chargeback {
+            disputed_tx_record.charged_back =
+                disputed_tx_record.charged_back.checked_add(amount).unwrap();
+            // A chargeback is final, the client account is locked.
+            updated_output_record.status = AccountStatus::Locked;
+            // The penalty is on the amount charged back.
+            fee = self.charge_fee(
+                TxType::Chargeback,
+                amount,
+                &mut updated_output_record,
+                client_id,
+                tx_id,
+            )?;
+        }
+        // A partial resolve or chargeback leaves the rest of the transaction in dispute.
+        disputed_tx_record.state = if disputed_tx_record.disputed == Amount::ZERO {
+            next_state
+        } else {
+            TxState::InDispute
+        };
+
+        self.commit(
+            Effect {
+                client_id,
+                tx_type: Some(record.tx_type),
+                account: Some(updated_output_record),
+                tx: Some(disputed_tx_record),
+                admin: None,
+                counterparty,
+                fee,
+            },
+            tx_id,
+        )?;
+        if let Some(outcome) = overdraft_outcome {
+            self.overdraft_report.push(outcome);
+        }
+        Ok(())
+    }
+
+    /// Handles authorizations. These move their amount from available to held, where it stays
+    /// reserved until it is captured or voided.
+    fn handle_authorize(&mut self, mut record: InputRecord) -> Result<(), TxError> {
+        let client_id = record.client_id;
+        let tx_id = record.tx_id;
+        if self
+            .tx_store
+            .contains(tx_id)
+            .map_err(|error| TxError::storage(client_id, tx_id, &error))?
+        {
+            return Err(TxError::DuplicateTxId { client_id, tx_id });
+        }
+        self.check_client_status(client_id, tx_id, record.tx_type)?;
+
+        let amount = match record.amount {
+            Some(amount) if amount.is_negative() => {
+                return Err(TxError::NegativeAmount { client_id, tx_id })
+            }
+            Some(amount) => amount,
+            None => return Err(TxError::MissingAmount { client_id, tx_id }),
+        };
+
+        // The funds have to be available as for a withdrawal, with any overdraft.
+        let policy = self.overdraft_policies.policy(client_id);
+        let (output_record, result) = match self.client_map.get(&client_id) {
+            Some(output_record)
+                if policy
+                    .spendable(output_record.available, TxType::Authorize)
+                    .is_some_and(|spendable| amount > spendable) =>
+            {
+                (None, Err(TxError::InsufficientFunds { client_id, tx_id }))
+            }
+            // The amount was checked to not be negative, so negating it can't overflow.
+            Some(output_record) => (
+                Some(
+                    output_record
+                        .checked_adjust(amount.checked_neg().unwrap(), amount)
+                        .ok_or(TxError::Overflow { client_id, tx_id })?,
+                ),
+                Ok(()),
+            ),
+            None => (
+                Some(OutputRecord::new(Amount::ZERO)),
+                Err(TxError::InsufficientFunds { client_id, tx_id }),
+            ),
+        };
+
+        // Like a withdrawal, a refused authorization is remembered so its ID isn't reused.
+        if result.is_ok() {
+            record.state = TxState::Authorized;
+        }
+        self.commit(
+            Effect {
+                client_id,
+                tx_type: Some(TxType::Authorize),
+                account: output_record,
+                tx: Some(record),
+                admin: None,
+                counterparty: None,
+                fee: None,
+            },
+            tx_id,
+        )?;
+        result
+    }
+
+    /// Handles captures and voids of an authorization. A capture takes all or part of what is
+    /// still reserved out of held, a void releases all of it back to available.
+    fn handle_authorization_step(&mut self, record: &InputRecord) -> Result<(), TxError> {
+        let client_id = record.client_id;
+        let tx_id = record.tx_id;
+        let status_check = self.check_client_status(client_id, tx_id, record.tx_type);
+        let mut authorization = match self
+            .tx_store
+            .get(tx_id)
+            .map_err(|error| TxError::storage(client_id, tx_id, &error))?
+        {
+            Some(input_record) => input_record,
+            None => return Err(TxError::UnknownTx { client_id, tx_id }),
+        };
+        if authorization.client_id != client_id {
+            return Err(TxError::ClientMismatch { client_id, tx_id });
+        }
+        status_check?;
+        // Only an authorization that still reserves funds can be captured or voided.
+        let next_state = authorization
+            .state
+            .transition(record.tx_type)
+            .ok_or(TxError::InvalidDisputeState { client_id, tx_id })?;
+
+        // What is reserved never goes below zero, and a stored authorization has an amount.
+        let reserved = authorization
+            .amount
+            .unwrap()
+            .checked_sub(authorization.captured)
+            .unwrap();
+        // A capture without an amount takes everything still reserved. A void always releases it all.
+        let (available, captured) = match (record.tx_type, record.amount) {
+            (TxType::Capture, Some(amount)) if amount.is_negative() => {
+                return Err(TxError::NegativeAmount { client_id, tx_id })
+            }
+            (TxType::Capture, Some(amount)) if amount == Amount::ZERO => {
+                return Err(TxError::InvalidDisputeAmount { client_id, tx_id })
+            }
+            (TxType::Capture, Some(amount)) if amount > reserved => {
+                return Err(TxError::CaptureExceedsAuthorization { client_id, tx_id })
+            }
+            (TxType::Capture, amount) => (Amount::ZERO, amount.unwrap_or(reserved)),
+            _ => (reserved, Amount::ZERO),
+        };
+        let released = available.checked_add(captured).unwrap();
+        let output_record = self
+            .client_map
+            .get(&client_id)
+            .unwrap()
+            .checked_adjust(available, released.checked_neg().unwrap())
+            .ok_or(TxError::Overflow { client_id, tx_id })?;
+
+        authorization.captured = authorization.captured.checked_add(captured).unwrap();
+        // A partial capture leaves the rest of the authorization reserved.
+        authorization.state = if released == reserved {
+            next_state
+        } else {
+            TxState::Authorized
+        };
+
+        self.commit(
+            Effect {
+                client_id,
+                tx_type: Some(record.tx_type),
+                account: Some(output_record),
+                tx: Some(authorization),
+                admin: None,
+                counterparty: None,
+                fee: None,
+            },
+            tx_id,
+        )
+    }
+
+    /// Returns how much of `amount` a dispute by `client_id` may hold on `account`, as the
+    /// account's overdraft policy allows, and the outcome to report if that is less. A dispute the
+    /// policy refuses is reported and refused here.
+    fn check_overdraft(
+        &mut self,
+        client_id: u16,
+        tx_id: u32,
+        account: u16,
+        amount: Amount,
+    ) -> Result<(Amount, Option<OverdraftOutcome>), TxError> {
+        let policy = self.overdraft_policies.policy(account);
+        // The disputed transaction was applied to the account, so it exists.
+        let available = self.client_map.get(&account).unwrap().available;
+        let spendable = match policy.spendable(available, TxType::Dispute) {
+            Some(spendable) if amount > spendable => spendable,
+            _ => return Ok((amount, None)),
+        };
+        let disputed = match policy {
+            OverdraftPolicy::Limit {
+                over_limit: OverLimit::Cap,
+                ..
+            } => spendable.max(Amount::ZERO),
+            _ => Amount::ZERO,
+        };
+        let outcome = OverdraftOutcome {
+            client_id,
+            tx_id,
+            account,
+            policy,
+            requested: amount,
+            disputed,
+        };
+        if disputed == Amount::ZERO {
+            self.overdraft_report.push(outcome);
+            return Err(TxError::OverdraftLimit {
+                client_id: account,
+                tx_id,
+            });
+        }
+        Ok((disputed, Some(outcome)))
+    }
+}
+
+/// How often to call a checkpoint callback, in rows, and the callback itself.
+type Checkpoint<'a, E> = (u64, &'a mut dyn FnMut(&E, &csv::Position) -> io::Result<()>);
+
+/// A row of input: its line number, its fields as they were read and the parsed record.
+pub(crate) type Row<T> = (u64, String, Result<T, TxError>);
+
+/// Reads the next row of `csv_reader` as a `T`. Returns `None` at the end of the input.
+///
+/// Only io errors are returned as errors, a row that can't be parsed is returned as a refused record.
+pub(crate) fn read_row<T: DeserializeOwned, R: io::Read>(
+    csv_reader: &mut csv::Reader<R>,
+    headers: &csv::StringRecord,
+    string_record: &mut csv::StringRecord,
+) -> Result<Option<Row<T>>, csv::Error> {
+    match csv_reader.read_record(string_record) {
+        Ok(false) => Ok(None),
+        Ok(true) => {
+            let line = string_record.position().map_or(0, csv::Position::line);
+            let raw_record = string_record.iter().join(",");
+            // If there are less columns than expected, we assume the row is erroneous.
+            let record_res = if string_record.len() == headers.len() {
+                string_record
+                    .deserialize(Some(headers))
+                    .map_err(TxError::from)
+            } else {
+                Err(TxError::Parse {
+                    reason: format!(
+                        "expected {} fields, found {}",
+                        headers.len(),
+                        string_record.len()
+                    ),
+                })
+            };
+            Ok(Some((line, raw_record, record_res)))
+        }
+        Err(error) if error.is_io_error() => Err(error),
+        Err(error) => {
+            let line = error.position().map_or(0, csv::Position::line);
+            Ok(Some((line, String::new(), Err(error.into()))))
+        }
+    }
+}
+
+/// Parses a line of JSON Lines input. A line that isn't a valid transaction object is refused
+/// like an unparseable csv row.
+pub(crate) fn parse_json_line(line: &[u8]) -> Result<InputRecord, TxError> {
+    serde_json::from_slice::<JsonInputRecord>(line)
+        .map(InputRecord::from)
+        .map_err(TxError::from)
+}
+
+/// Creates the csv reader used for every input.
+pub(crate) fn csv_reader<R: io::Read>(reader: R) -> csv::Reader<R> {
+    // The reader is flexible so that short or long rows still come back as records we can report,
+    // the column count is checked when processing instead.
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(reader)
+}
+
+/// Returns how much `available` and `held` move by when `step` (a dispute, resolve or chargeback)
+/// covers `amount` of a `disputed_tx_type` transaction.
+///
+/// For a deposit, or the destination of a transfer, a dispute holds the deposited funds, a resolve
+/// releases them and a chargeback withdraws them. For a withdrawal, a dispute provisionally credits
+/// the withdrawn funds into held, a resolve drops that credit and a chargeback returns the funds to
+/// available.
+fn dispute_adjustment(disputed_tx_type: TxType, step: TxType, amount: Amount) -> (Amount, Amount) {
+    // Stored amounts are never negative so negating them can't overflow.
+    let negated = amount.checked_neg().unwrap();
+    match (disputed_tx_type, step) {
+        (TxType::Deposit | TxType::Transfer, TxType::Dispute) => (negated, amount),
+        (TxType::Deposit | TxType::Transfer, TxType::Resolve) => (amount, negated),
+        (TxType::Deposit | TxType::Transfer, TxType::Chargeback) => (Amount::ZERO, negated),
+        (TxType::Withdrawal, TxType::Dispute) => (Amount::ZERO, amount),
+        (TxType::Withdrawal, TxType::Resolve) => (Amount::ZERO, negated),
+        (TxType::Withdrawal, TxType::Chargeback) => (amount, negated),
+        // Only deposits, withdrawals and transfers are kept, and only disputes, resolves and chargebacks
+        // get here.
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::AdminType;
+    use crate::fees::FeeTotals;
+    use crate::ledger::LedgerAccount;
+
+    // convenience method to validate that an amount matches the expected decimal value.
+    fn assert_amount(amount: Amount, num: &str) {
+        assert_eq!(num.parse::<Amount>().unwrap(), amount);
+    }
+
+    fn amount(num: &str) -> Option<Amount> {
+        Some(num.parse().unwrap())
+    }
+
+    // Tests dispute/resolve/chargeback logic.
+    #[test]
+    fn disputes_test() {
+        let disputes_csv_file = path::Path::new("sample_data/disputes.csv");
+        let mut engine = Engine::new();
+
+        let mut csv_reader = match csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(disputes_csv_file)
+        {
+            Ok(rdr) => rdr,
+            Err(error) => panic!(
+                "Failed to read {}: {error}",
+                disputes_csv_file.to_str().unwrap()
+            ),
+        };
+
+        let mut iter = csv_reader.deserialize();
+        // process the first two deposits
+        let _ = engine.process_input_record(iter.next().unwrap());
+        let _ = engine.process_input_record(iter.next().unwrap());
+
+        // Process the first dispute
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client1_record = engine.account(1).unwrap();
+
+            assert_amount(client1_record.held, "500");
+            assert_amount(client1_record.available, "0");
+            assert_amount(client1_record.total, "500");
+            assert!(!client1_record.locked());
+
+            let tx_1 = engine.transaction(1).unwrap().unwrap();
+            assert!(tx_1.state() == TxState::InDispute);
+        }
+
+        // Process the second dispute. client 1 cannot dispute client 2 transaction -> ignored.
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client2_record = engine.account(2).unwrap();
+            assert_amount(client2_record.held, "0");
+            assert_amount(client2_record.available, "5");
+            assert_amount(client2_record.total, "5");
+            assert!(!client2_record.locked());
+
+            let tx_2 = engine.transaction(2).unwrap().unwrap();
+            assert!(tx_2.state() == TxState::Applied);
+        }
+
+        // Process the resolution of first dispute.
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.held, "0");
+            assert_amount(client1_record.available, "500");
+            assert_amount(client1_record.total, "500");
+            assert!(!client1_record.locked());
+
+            let tx_1 = engine.transaction(1).unwrap().unwrap();
+            assert!(tx_1.state() == TxState::Applied);
+        }
+
+        // Process second dispute for tx 1
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.held, "500");
+            assert_amount(client1_record.available, "0");
+            assert_amount(client1_record.total, "500");
+            assert!(!client1_record.locked());
+
+            let tx_1 = engine.transaction(1).unwrap().unwrap();
+            assert!(tx_1.state() == TxState::InDispute);
+        }
+
+        // Process another deposit while in dispute for client 1
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.held, "500");
+            assert_amount(client1_record.available, "5");
+            assert_amount(client1_record.total, "505");
+            assert!(!client1_record.locked());
+        }
+
+        // Process tx 1 chargeback
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.held, "0");
+            assert_amount(client1_record.available, "5");
+            assert_amount(client1_record.total, "5");
+            assert!(client1_record.locked());
+        }
+
+        // Process client 1 trying to deposit more funds. Rejected.
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.held, "0");
+            assert_amount(client1_record.available, "5");
+            assert_amount(client1_record.total, "5");
+            assert!(client1_record.locked());
+        }
+
+        // Process client 1 trying to withdraw funds. Rejected.
+        let _ = engine.process_input_record(iter.next().unwrap());
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.held, "0");
+            assert_amount(client1_record.available, "5");
+            assert_amount(client1_record.total, "5");
+            assert!(client1_record.locked());
+        }
+    }
+
+    // Transactions built through the public API go through the same handlers as csv input.
+    #[test]
+    fn apply_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("2.0")))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Withdrawal, 1, 2, amount("3.0"))),
+            Err(TxError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 2
+            })
+        );
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 1, None))
+            .unwrap();
+
+        let client1_record = engine.account(1).unwrap();
+        assert_amount(client1_record.available, "0");
+        assert_amount(client1_record.held, "2");
+        assert_eq!(engine.accounts().count(), 1);
+        assert!(engine.account(2).is_none());
+    }
+
+    // A deposit that would overflow a balance is refused and leaves the account and tx history untouched.
+    #[test]
+    fn overflow_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(
+                TxType::Deposit,
+                1,
+                1,
+                amount("922337203685477.5807"),
+            ))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Deposit, 1, 2, amount("0.0001"))),
+            Err(TxError::Overflow {
+                client_id: 1,
+                tx_id: 2
+            })
+        );
+        assert_amount(engine.account(1).unwrap().available, "922337203685477.5807");
+        assert!(engine.transaction(2).unwrap().is_none());
+
+        // Withdrawing everything brings the account back to zero exactly.
+        engine
+            .apply(InputRecord::new(
+                TxType::Withdrawal,
+                1,
+                3,
+                amount("922337203685477.5807"),
+            ))
+            .unwrap();
+        assert_amount(engine.account(1).unwrap().total, "0");
+
+        // available can still take this deposit but total can't, so nothing may change.
+        engine
+            .apply(InputRecord::new(
+                TxType::Deposit,
+                2,
+                4,
+                amount("500000000000000"),
+            ))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 2, 4, None))
+            .unwrap();
+        let before = *engine.account(2).unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(
+                TxType::Deposit,
+                2,
+                5,
+                amount("500000000000000")
+            )),
+            Err(TxError::Overflow {
+                client_id: 2,
+                tx_id: 5
+            })
+        );
+        assert_eq!(*engine.account(2).unwrap(), before);
+        assert!(engine.transaction(5).unwrap().is_none());
+    }
+
+    // Disputing a withdrawal credits held, a resolve drops the credit and a chargeback returns the funds.
+    #[test]
+    fn withdrawal_disputes_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("10")))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Withdrawal, 1, 2, amount("4")))
+            .unwrap();
+        assert!(engine.transaction(2).unwrap().unwrap().state() == TxState::Applied);
+
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 2, None))
+            .unwrap();
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.available, "6");
+            assert_amount(client1_record.held, "4");
+            assert_amount(client1_record.total, "10");
+            assert!(engine.transaction(2).unwrap().unwrap().state() == TxState::InDispute);
+        }
+
+        engine
+            .apply(InputRecord::new(TxType::Resolve, 1, 2, None))
+            .unwrap();
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.available, "6");
+            assert_amount(client1_record.held, "0");
+            assert_amount(client1_record.total, "6");
+            assert!(engine.transaction(2).unwrap().unwrap().state() == TxState::Applied);
+        }
+
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 2, None))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Chargeback, 1, 2, None))
+            .unwrap();
+        {
+            let client1_record = engine.account(1).unwrap();
+            assert_amount(client1_record.available, "10");
+            assert_amount(client1_record.held, "0");
+            assert_amount(client1_record.total, "10");
+            assert!(client1_record.locked());
+            assert!(engine.transaction(2).unwrap().unwrap().state() == TxState::ChargedBack);
+        }
+    }
+
+    // Withdrawals which were refused never moved funds, so they can't be disputed.
+    #[test]
+    fn refused_withdrawal_dispute_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("1")))
+            .unwrap();
+        assert!(engine
+            .apply(InputRecord::new(TxType::Withdrawal, 1, 2, amount("4")))
+            .is_err());
+        assert!(engine.transaction(2).unwrap().unwrap().state() == TxState::NotApplied);
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 2, None)),
+            Err(TxError::InvalidDisputeState {
+                client_id: 1,
+                tx_id: 2
+            })
+        );
+        assert_amount(engine.account(1).unwrap().held, "0");
+    }
+
+    // Tests disputes which only cover part of a transaction.
+    #[test]
+    fn partial_disputes_test() {
+        let mut engine = Engine::new();
+        engine
+            .process_csv_file(path::Path::new("sample_data/partial_disputes.csv"))
+            .unwrap();
+
+        // Client 1 disputed 30 then 20 of a 100 deposit, resolved 10, charged back 15 and the
+        // rest of the dispute is still held.
+        let client1_record = engine.account(1).unwrap();
+        assert_amount(client1_record.available, "60");
+        assert_amount(client1_record.held, "25");
+        assert_amount(client1_record.total, "85");
+        assert!(client1_record.locked());
+        let tx_1 = engine.transaction(1).unwrap().unwrap();
+        assert!(tx_1.state() == TxState::InDispute);
+        assert_amount(tx_1.disputed_amount(), "25");
+
+        // Client 2 couldn't dispute more than the deposit, disputed the rest with an empty amount
+        // and then resolved all of it.
+        let client2_record = engine.account(2).unwrap();
+        assert_amount(client2_record.available, "50");
+        assert_amount(client2_record.held, "0");
+        assert!(!client2_record.locked());
+        let tx_2 = engine.transaction(2).unwrap().unwrap();
+        assert!(tx_2.state() == TxState::Applied);
+        assert_amount(tx_2.disputed_amount(), "0");
+    }
+
+    // Tests transfers, which are refused without a destination other than the source or the funds
+    // for them, and whose disputes hold the funds at the destination.
+    #[test]
+    fn transfers_test() {
+        let mut engine = Engine::new();
+        engine
+            .process_csv_file(path::Path::new("sample_data/transfers.csv"))
+            .unwrap();
+
+        // Client 1 sent 30 to client 2, which was disputed and resolved.
+        let client1_record = engine.account(1).unwrap();
+        assert_amount(client1_record.available, "70");
+        assert_amount(client1_record.total, "70");
+        let tx_3 = engine.transaction(3).unwrap().unwrap();
+        assert!(tx_3.state() == TxState::Applied);
+        assert_eq!(tx_3.destination, Some(2));
+        // Refused transfers are remembered only when the funds were missing.
+        assert!(engine.transaction(4).unwrap().unwrap().state() == TxState::NotApplied);
+        assert!(engine.transaction(5).unwrap().is_none());
+
+        // Client 2 sent 25 to client 3 and charged it back, which takes it back from client 3 and
+        // locks client 2. Client 3 can't send to the locked account either.
+        let client2_record = engine.account(2).unwrap();
+        assert_amount(client2_record.available, "50");
+        assert_amount(client2_record.held, "0");
+        assert!(client2_record.locked());
+        let client3_record = engine.account(3).unwrap();
+        assert_amount(client3_record.available, "0");
+        assert_amount(client3_record.held, "0");
+        assert!(!client3_record.locked());
+        assert!(engine.transaction(8).unwrap().unwrap().state() == TxState::ChargedBack);
+        assert_eq!(
+            engine.apply(InputRecord::transfer(3, 1, 11, amount("0").unwrap())),
+            Ok(())
+        );
+        assert_eq!(
+            engine.apply(InputRecord::transfer(1, 2, 12, amount("1").unwrap())),
+            Err(TxError::AccountLocked {
+                client_id: 2,
+                tx_id: 12
+            })
+        );
+    }
+
+    // Deposits pay 1%, withdrawals 0.5 and chargebacks 25, see sample_data/fee_schedule.csv.
+    // Disputing a transfer holds funds on the destination, which can't happen once it is closed.
+    #[test]
+    fn closed_transfer_destination_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("100")))
+            .unwrap();
+        engine
+            .apply(InputRecord::transfer(1, 2, 2, amount("30").unwrap()))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 2, amount("10")))
+            .unwrap();
+        engine
+            .apply_admin(AdminRecord::new(AdminType::Close, 2, "ops", "closed"))
+            .unwrap();
+        for tx_type in [TxType::Dispute, TxType::Resolve, TxType::Chargeback] {
+            assert_eq!(
+                engine.apply(InputRecord::new(tx_type, 1, 2, None)),
+                Err(TxError::AccountClosed {
+                    client_id: 2,
+                    tx_id: 2
+                })
+            );
+        }
+        let destination = engine.account(2).unwrap();
+        assert_amount(destination.available, "20");
+        assert_amount(destination.held, "10");
+        assert_amount(engine.account(1).unwrap().available, "70");
+    }
+
+    #[test]
+    fn fees_test() {
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        engine.set_fee_schedule(
+            FeeSchedule::from_csv_file(path::Path::new("sample_data/fee_schedule.csv")).unwrap(),
+        );
+        engine
+            .process_csv_file(path::Path::new("sample_data/fees.csv"))
+            .unwrap();
+
+        // 1% of 10.005 rounds up to 0.1001, and withdrawing 9.5 needs 10 with the fee.
+        let client1_record = engine.account(1).unwrap();
+        assert_amount(client1_record.available, "9.9049");
+        assert_amount(client1_record.total, "9.9049");
+        assert!(engine.transaction(4).unwrap().unwrap().state() == TxState::NotApplied);
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Withdrawal, 1, 7, amount("9.4049"))),
+            Ok(())
+        );
+        assert_amount(engine.account(1).unwrap().available, "0");
+
+        // The chargeback takes 50 back and only the 19.3 left of its 25 penalty can be charged.
+        let client2_record = engine.account(2).unwrap();
+        assert_amount(client2_record.available, "0");
+        assert_amount(client2_record.total, "0");
+        assert!(client2_record.locked());
+
+        let totals: Vec<_> = engine.fee_summary().totals().collect();
+        assert_eq!(
+            totals,
+            [
+                (
+                    TxType::Deposit,
+                    &FeeTotals {
+                        count: 4,
+                        charged: amount("1.8001").unwrap(),
+                        waived: Amount::ZERO,
+                    }
+                ),
+                (
+                    TxType::Withdrawal,
+                    &FeeTotals {
+                        count: 2,
+                        charged: amount("1").unwrap(),
+                        waived: Amount::ZERO,
+                    }
+                ),
+                (
+                    TxType::Chargeback,
+                    &FeeTotals {
+                        count: 1,
+                        charged: amount("19.3").unwrap(),
+                        waived: amount("5.7").unwrap(),
+                    }
+                ),
+            ]
+        );
+
+        // Every fee charged is the house's revenue.
+        let trial_balance = engine.trial_balance().unwrap();
+        assert_eq!(
+            trial_balance.balance(LedgerAccount::FeeRevenue),
+            amount("-22.1001").unwrap()
+        );
+    }
+
+    // See sample_data/overdraft_policies.csv for each client's policy.
+    // A withdrawal into an overdraft pays its fee in full, the overdraft covers it too.
+    #[test]
+    fn overdrawn_withdrawal_fee_test() {
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        engine.set_fee_schedule(
+            FeeSchedule::from_csv_reader("type,flat,percent\nwithdrawal,1,\n".as_bytes()).unwrap(),
+        );
+        engine.set_overdraft_policies(
+            OverdraftPolicies::from_csv_reader("client,policy\n1,overdraft:100\n".as_bytes())
+                .unwrap(),
+        );
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("5")))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Withdrawal, 1, 2, amount("10")))
+            .unwrap();
+        assert_amount(engine.account(1).unwrap().available, "-6");
+        assert_eq!(
+            engine.fee_summary().totals().collect::<Vec<_>>(),
+            [(
+                TxType::Withdrawal,
+                &FeeTotals {
+                    count: 1,
+                    charged: amount("1").unwrap(),
+                    waived: Amount::ZERO,
+                }
+            )]
+        );
+        // The fee has to fit in the overdraft along with the amount.
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Withdrawal, 1, 3, amount("94"))),
+            Err(TxError::InsufficientFunds {
+                client_id: 1,
+                tx_id: 3
+            })
+        );
+        engine
+            .apply(InputRecord::new(TxType::Withdrawal, 1, 4, amount("93")))
+            .unwrap();
+        assert_amount(engine.account(1).unwrap().available, "-100");
+        engine.trial_balance().unwrap();
+    }
+
+    #[test]
+    fn overdraft_test() {
+        let mut engine = Engine::new();
+        engine.set_overdraft_policies(
+            OverdraftPolicies::from_csv_file(path::Path::new("sample_data/overdraft_policies.csv"))
+                .unwrap(),
+        );
+        engine
+            .process_csv_file(path::Path::new("sample_data/overdraft.csv"))
+            .unwrap();
+        let balances = |client_id| {
+            let output_record = engine.account(client_id).unwrap();
+            [
+                output_record.available,
+                output_record.held,
+                output_record.total,
+            ]
+            .map(|amount| amount.to_string())
+        };
+
+        // Never negative: the full dispute is refused but a partial one fits.
+        assert_eq!(balances(1), ["5.0000", "15.0000", "20.0000"]);
+        // Never negative, capping disputes: only the 30 available is held, then released.
+        assert_eq!(balances(2), ["30.0000", "0.0000", "30.0000"]);
+        // Overdrawn by 30 of 50, so withdrawing 30 more is refused, but a dispute can hold 100
+        // after depositing it.
+        assert_eq!(balances(3), ["-30.0000", "100.0000", "70.0000"]);
+        assert!(engine.transaction(7).unwrap().unwrap().state() == TxState::NotApplied);
+        // Transferring into the overdraft, and capping a dispute at the 20 limit.
+        assert_eq!(balances(4), ["-20.0000", "30.0000", "10.0000"]);
+        // Permissive: disputes have no limit.
+        assert_eq!(balances(5), ["-10.0000", "10.0000", "0.0000"]);
+        // The dispute of the transfer would have held 60 on client 6, who only had 10 left.
+        assert_eq!(balances(6), ["10.0000", "0.0000", "10.0000"]);
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 4, 10, None)),
+            Err(TxError::OverdraftLimit {
+                client_id: 6,
+                tx_id: 10
+            })
+        );
+
+        let report: Vec<_> = engine
+            .overdraft_report()
+            .iter()
+            .map(|outcome| {
+                (
+                    outcome.client_id,
+                    outcome.tx_id,
+                    outcome.account,
+                    outcome.policy.to_string(),
+                    outcome.requested.to_string(),
+                    outcome.disputed.to_string(),
+                )
+            })
+            .collect();
+        let outcome = |client_id, tx_id, account, policy: &str, requested: &str, disputed: &str| {
+            (
+                client_id,
+                tx_id,
+                account,
+                policy.to_owned(),
+                requested.to_owned(),
+                disputed.to_owned(),
+            )
+        };
+        assert_eq!(
+            report,
+            [
+                outcome(1, 1, 1, "no-negative", "100.0000", "0.0000"),
+                outcome(2, 3, 2, "no-negative:cap", "100.0000", "30.0000"),
+                outcome(4, 10, 6, "no-negative", "60.0000", "0.0000"),
+                outcome(4, 14, 4, "overdraft:20:cap", "100.0000", "30.0000"),
+                outcome(4, 10, 6, "no-negative", "60.0000", "0.0000"),
+            ]
+        );
+    }
+
+    // A transfer is applied to both accounts or not at all.
+    #[test]
+    fn authorizations_test() {
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        engine
+            .process_csv_file(path::Path::new("sample_data/authorizations.csv"))
+            .unwrap();
+        let balances = |engine: &Engine, client_id| {
+            let output_record = engine.account(client_id).unwrap();
+            [
+                output_record.available,
+                output_record.held,
+                output_record.total,
+            ]
+            .map(|amount| amount.to_string())
+        };
+
+        // Client 1 captured 40 in two parts and voided a 20 authorization, 70 was more than it had.
+        assert_eq!(balances(&engine, 1), ["60.0000", "0.0000", "60.0000"]);
+        let tx_2 = engine.transaction(2).unwrap().unwrap();
+        assert!(tx_2.state() == TxState::Captured);
+        assert_amount(tx_2.captured_amount(), "40");
+        assert!(engine.transaction(3).unwrap().unwrap().state() == TxState::NotApplied);
+        let tx_4 = engine.transaction(4).unwrap().unwrap();
+        assert!(tx_4.state() == TxState::Voided);
+        assert_amount(tx_4.captured_amount(), "0");
+        // Client 2 reserved everything so couldn't withdraw, then captured 4 and voided the rest.
+        assert_eq!(balances(&engine, 2), ["6.0000", "0.0000", "6.0000"]);
+        assert_eq!(balances(&engine, 3), ["0.0000", "0.0000", "0.0000"]);
+
+        // Reserved funds are held until the authorization is captured in full or voided.
+        engine
+            .apply(InputRecord::new(TxType::Authorize, 1, 10, amount("25")))
+            .unwrap();
+        assert_eq!(balances(&engine, 1), ["35.0000", "25.0000", "60.0000"]);
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Capture, 1, 10, amount("25.0001"))),
+            Err(TxError::CaptureExceedsAuthorization {
+                client_id: 1,
+                tx_id: 10
+            })
+        );
+        engine
+            .apply(InputRecord::new(TxType::Capture, 1, 10, amount("10")))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Capture, 1, 10, amount("0"))),
+            Err(TxError::InvalidDisputeAmount {
+                client_id: 1,
+                tx_id: 10
+            })
+        );
+        assert_eq!(balances(&engine, 1), ["35.0000", "15.0000", "50.0000"]);
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Capture, 1, 10, amount("15.0001"))),
+            Err(TxError::CaptureExceedsAuthorization {
+                client_id: 1,
+                tx_id: 10
+            })
+        );
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 10, None)),
+            Err(TxError::InvalidDisputeState {
+                client_id: 1,
+                tx_id: 10
+            })
+        );
+        engine
+            .apply(InputRecord::new(TxType::Void, 1, 10, None))
+            .unwrap();
+        assert_eq!(balances(&engine, 1), ["50.0000", "0.0000", "50.0000"]);
+        // Captures leave through cash out like withdrawals, so the ledger still balances.
+        engine.trial_balance().unwrap();
+    }
+
+    // A store that refuses every insert, as a full disk would.
+    struct FullTxStore;
+
+    impl TxStore for FullTxStore {
+        fn get(&self, _tx_id: u32) -> io::Result<Option<InputRecord>> {
+            Ok(None)
+        }
+
+        fn insert(&mut self, _record: InputRecord) -> io::Result<()> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn for_each(&self, _f: &mut dyn FnMut(InputRecord) -> io::Result<()>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A transaction the store can't keep isn't applied, so it is in neither the ledger, the fee
+    // summary nor the journal.
+    #[test]
+    fn store_failure_test() {
+        let journal_path = std::env::temp_dir().join(format!(
+            "toy_payment_engine_store_failure_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&journal_path);
+        let mut engine = Engine::with_store(FullTxStore);
+        engine.enable_ledger();
+        engine
+            .open_journal(&journal_path, FsyncPolicy::EveryWrite)
+            .unwrap();
+        engine.set_fee_schedule(
+            FeeSchedule::from_csv_file(path::Path::new("sample_data/fee_schedule.csv")).unwrap(),
+        );
+        assert_eq!(
+            engine
+                .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("10")))
+                .unwrap_err()
+                .category(),
+            "storage"
+        );
+        assert!(engine.account(1).is_none());
+        assert!(engine.ledger().unwrap().entries().is_empty());
+        assert_eq!(engine.fee_summary().total().count, 0);
+        engine.trial_balance().unwrap();
+        assert_eq!(engine.journal.as_ref().unwrap().entries(), 0);
+        drop(engine);
+        assert_eq!(
+            Engine::new()
+                .open_journal(&journal_path, FsyncPolicy::Never)
+                .unwrap(),
+            0
+        );
+        fs::remove_file(journal_path).unwrap();
+    }
+
+    #[test]
+    fn transfer_overflow_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(
+                TxType::Deposit,
+                1,
+                1,
+                amount("922337203685477.5807"),
+            ))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 2, 2, amount("1")))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::transfer(2, 1, 3, amount("1").unwrap())),
+            Err(TxError::Overflow {
+                client_id: 1,
+                tx_id: 3
+            })
+        );
+        assert_amount(engine.account(2).unwrap().available, "1");
+        assert_amount(engine.account(1).unwrap().available, "922337203685477.5807");
+        assert!(engine.transaction(3).unwrap().is_none());
+
+        // Disputing the transfer holds the funds at the destination, even if they were spent.
+        engine
+            .apply(InputRecord::transfer(2, 3, 4, amount("1").unwrap()))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Withdrawal, 3, 5, amount("1")))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 2, 4, None))
+            .unwrap();
+        let client3_record = engine.account(3).unwrap();
+        assert_amount(client3_record.available, "-1");
+        assert_amount(client3_record.held, "1");
+        assert_amount(engine.account(2).unwrap().available, "0");
+    }
+
+    #[test]
+    fn dispute_amount_limits_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("10")))
+            .unwrap();
+        let invalid_amount = Err(TxError::InvalidDisputeAmount {
+            client_id: 1,
+            tx_id: 1,
+        });
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 1, amount("10.0001"))),
+            invalid_amount
+        );
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 1, amount("0"))),
+            invalid_amount
+        );
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 1, amount("-1"))),
+            Err(TxError::NegativeAmount {
+                client_id: 1,
+                tx_id: 1
+            })
+        );
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 1, amount("4")))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Resolve, 1, 1, amount("5"))),
+            invalid_amount
+        );
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Chargeback, 1, 1, amount("4.0001"))),
+            invalid_amount
+        );
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 1, amount("6.0001"))),
+            invalid_amount
+        );
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 1, None))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 1, None)),
+            invalid_amount
+        );
+        assert_amount(engine.account(1).unwrap().held, "10");
+        assert_amount(engine.account(1).unwrap().available, "0");
+    }
+
+    // Operators can unlock, freeze and close accounts, and every action they take is recorded.
+    #[test]
+    fn admin_actions_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("10")))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 2, amount("5")))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Chargeback, 1, 1, None))
+            .unwrap();
+        assert_eq!(engine.account(1).unwrap().status, AccountStatus::Locked);
+
+        // An unlocked account can transact again.
+        engine
+            .apply_admin(AdminRecord::new(AdminType::Unlock, 1, "alice", "reviewed"))
+            .unwrap();
+        assert!(!engine.account(1).unwrap().locked());
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 3, amount("1")))
+            .unwrap();
+
+        // A frozen account refuses deposits and withdrawals but still accepts disputes.
+        engine
+            .apply_admin(AdminRecord::new(AdminType::Freeze, 1, "bob", "fraud check"))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Deposit, 1, 4, amount("1"))),
+            Err(TxError::AccountFrozen {
+                client_id: 1,
+                tx_id: 4
+            })
+        );
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 2, None))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Resolve, 1, 2, None))
+            .unwrap();
+        assert_amount(engine.account(1).unwrap().available, "6");
+
+        // A closed account refuses everything and can't be reopened.
+        engine
+            .apply_admin(AdminRecord::new(
+                AdminType::Close,
+                1,
+                "alice",
+                "client request",
+            ))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Dispute, 1, 2, None)),
+            Err(TxError::AccountClosed {
+                client_id: 1,
+                tx_id: 2
+            })
+        );
+        assert_eq!(
+            engine.apply_admin(AdminRecord::new(AdminType::Unlock, 1, "alice", "reopen")),
+            Err(TxError::InvalidAccountStatus {
+                client_id: 1,
+                status: AccountStatus::Closed
+            })
+        );
+
+        assert_eq!(
+            engine.apply_admin(AdminRecord::new(AdminType::Freeze, 2, "alice", "fraud")),
+            Err(TxError::UnknownClient { client_id: 2 })
+        );
+        assert_eq!(
+            engine.apply_admin(AdminRecord::new(AdminType::Close, 1, " ", "no operator")),
+            Err(TxError::MissingAuditInfo { client_id: 1 })
+        );
+
+        let log: Vec<(AdminType, &str)> = engine
+            .admin_log()
+            .iter()
+            .map(|record| (record.action, record.operator.as_str()))
+            .collect();
+        assert_eq!(
+            log,
+            [
+                (AdminType::Unlock, "alice"),
+                (AdminType::Freeze, "bob"),
+                (AdminType::Close, "alice")
+            ]
+        );
+    }
+
+    // Admin actions can be read from an operator csv file, between transaction files.
+    #[test]
+    fn admin_csv_test() {
+        let mut engine = Engine::new();
+        engine
+            .process_csv_file(path::Path::new("sample_data/disputes.csv"))
+            .unwrap();
+        let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+        engine
+            .process_admin_csv_file(
+                path::Path::new("sample_data/admin_actions.csv"),
+                Some(&mut rejects),
+            )
+            .unwrap();
+
+        assert_eq!(engine.account(1).unwrap().status, AccountStatus::Active);
+        assert_eq!(engine.account(2).unwrap().status, AccountStatus::Frozen);
+        assert_eq!(engine.admin_log().len(), 2);
+
+        let bytes = rejects.into_inner().unwrap();
+        let mut rdr = csv::Reader::from_reader(&*bytes);
+        let categories: Vec<String> = rdr
+            .records()
+            .map(|row| row.unwrap().get(3).unwrap().to_owned())
+            .collect();
+        assert_eq!(categories, ["unknown_client", "missing_audit_info"]);
+    }
+
+    // State carries over between inputs, so a later file can dispute an earlier file's deposit.
+    #[test]
+    fn multiple_inputs_test() {
+        let mut engine = Engine::new();
+        engine
+            .process_csv_reader("type,client,tx,amount\ndeposit,1,1,10\n".as_bytes())
+            .unwrap();
+        engine
+            .process_csv_reader("type,client,tx,amount\ndispute,1,1,\n".as_bytes())
+            .unwrap();
+        assert_amount(engine.account(1).unwrap().held, "10");
+
+        assert!(engine
+            .process_csv_file(path::Path::new("sample_data/does_not_exist.csv"))
+            .is_err());
+    }
+
+    // The disk store is written once an input is processed, not only when the engine is dropped.
+    #[test]
+    fn flush_store_test() {
+        let dir = std::env::temp_dir().join(format!(
+            "toy_payment_engine_flush_store_{}",
+            std::process::id()
+        ));
+        let mut engine =
+            Engine::with_store(crate::disk_store::DiskTxStore::create(&dir, 0).unwrap());
+        engine
+            .process_csv_reader("type,client,tx,amount\ndeposit,1,1,10\n".as_bytes())
+            .unwrap();
+        let log_len = fs::metadata(dir.join("tx.log")).unwrap().len();
+        assert_eq!(log_len, crate::disk_store::RECORD_LEN as u64);
+        drop(engine);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    // Each refused transaction reports why it was refused.
+    #[test]
+    fn rejection_reasons_test() {
+        let mut engine = Engine::new();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, amount("2.0")))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 2, 2, amount("2.0")))
+            .unwrap();
+
+        let cases = [
+            (
+                InputRecord::new(TxType::Deposit, 1, 1, amount("1.0")),
+                TxError::DuplicateTxId {
+                    client_id: 1,
+                    tx_id: 1,
+                },
+            ),
+            (
+                InputRecord::new(TxType::Deposit, 1, 3, amount("-1.0")),
+                TxError::NegativeAmount {
+                    client_id: 1,
+                    tx_id: 3,
+                },
+            ),
+            (
+                InputRecord::new(TxType::Withdrawal, 1, 4, None),
+                TxError::MissingAmount {
+                    client_id: 1,
+                    tx_id: 4,
+                },
+            ),
+            (
+                InputRecord::new(TxType::Dispute, 1, 99, None),
+                TxError::UnknownTx {
+                    client_id: 1,
+                    tx_id: 99,
+                },
+            ),
+            (
+                InputRecord::new(TxType::Dispute, 1, 2, None),
+                TxError::ClientMismatch {
+                    client_id: 1,
+                    tx_id: 2,
+                },
+            ),
+            (
+                InputRecord::new(TxType::Resolve, 1, 1, None),
+                TxError::InvalidDisputeState {
+                    client_id: 1,
+                    tx_id: 1,
+                },
+            ),
+        ];
+        for (record, expected) in cases {
+            assert_eq!(engine.apply(record), Err(expected));
+        }
+
+        engine
+            .apply(InputRecord::new(TxType::Dispute, 1, 1, None))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(TxType::Chargeback, 1, 1, None))
+            .unwrap();
+        assert_eq!(
+            engine.apply(InputRecord::new(TxType::Deposit, 1, 5, amount("1.0"))),
+            Err(TxError::AccountLocked {
+                client_id: 1,
+                tx_id: 5
+            })
+        );
+        assert!(matches!(
+            engine.process_input_record(Err(csv::Error::from(std::io::Error::other("bad row")))),
+            Err(TxError::Parse { .. })
+        ));
+    }
+}
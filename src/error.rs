@@ -0,0 +1,220 @@
+use crate::account::AccountStatus;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The reason a transaction was refused by the engine.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TxError {
+    /// The input could not be parsed into a transaction.
+    Parse { reason: String },
+    /// A deposit, withdrawal, transfer or authorization reused a transaction ID that was already processed.
+    DuplicateTxId { client_id: u16, tx_id: u32 },
+    /// The client account is locked and refuses further transactions.
+    AccountLocked { client_id: u16, tx_id: u32 },
+    /// The client account is frozen and refuses deposits and withdrawals.
+    AccountFrozen { client_id: u16, tx_id: u32 },
+    /// The client account is closed and refuses further transactions.
+    AccountClosed { client_id: u16, tx_id: u32 },
+    /// The client does not have enough available funds for a withdrawal or an authorization.
+    InsufficientFunds { client_id: u16, tx_id: u32 },
+    /// A dispute would take the client's available funds past their overdraft limit.
+    OverdraftLimit { client_id: u16, tx_id: u32 },
+    /// A deposit, withdrawal, transfer, authorization or capture had a negative amount.
+    NegativeAmount { client_id: u16, tx_id: u32 },
+    /// A deposit, withdrawal, transfer or authorization had no amount.
+    MissingAmount { client_id: u16, tx_id: u32 },
+    /// A dispute, resolve, chargeback, capture or void referenced a transaction that does not exist.
+    UnknownTx { client_id: u16, tx_id: u32 },
+    /// A dispute, resolve, chargeback, capture or void referenced another client's transaction.
+    ClientMismatch { client_id: u16, tx_id: u32 },
+    /// The referenced transaction is not in a state that allows this operation.
+    InvalidDisputeState { client_id: u16, tx_id: u32 },
+    /// A dispute, resolve, chargeback or capture amount was zero or more than it could cover.
+    InvalidDisputeAmount { client_id: u16, tx_id: u32 },
+    /// A capture was for more than its authorization still reserves.
+    CaptureExceedsAuthorization { client_id: u16, tx_id: u32 },
+    /// A transfer had no destination client, or was to the client sending it.
+    InvalidDestination { client_id: u16, tx_id: u32 },
+    /// Applying the transaction would overflow one of the account's balances.
+    Overflow { client_id: u16, tx_id: u32 },
+    /// The transaction store failed to read or write the transaction.
+    Storage {
+        client_id: u16,
+        tx_id: u32,
+        reason: String,
+    },
+    /// An admin action referenced a client the engine has never seen.
+    UnknownClient { client_id: u16 },
+    /// An admin action didn't say who took it or why.
+    MissingAuditInfo { client_id: u16 },
+    /// An admin action isn't allowed from the account's current status, e.g. reopening a closed account.
+    InvalidAccountStatus {
+        client_id: u16,
+        status: AccountStatus,
+    },
+}
+
+impl fmt::Display for TxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Parse { reason } => write!(f, "failed to parse transaction: {reason}"),
+            Self::DuplicateTxId { client_id, tx_id } => {
+                write!(f, "client {client_id}: tx {tx_id} was already processed")
+            }
+            Self::AccountLocked { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: account is locked, tx {tx_id} refused"
+                )
+            }
+            Self::AccountFrozen { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: account is frozen, tx {tx_id} refused"
+                )
+            }
+            Self::AccountClosed { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: account is closed, tx {tx_id} refused"
+                )
+            }
+            Self::InsufficientFunds { client_id, tx_id } => {
+                write!(f, "client {client_id}: insufficient funds for tx {tx_id}")
+            }
+            Self::OverdraftLimit { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: tx {tx_id} would go past the overdraft limit"
+                )
+            }
+            Self::NegativeAmount { client_id, tx_id } => {
+                write!(f, "client {client_id}: tx {tx_id} has a negative amount")
+            }
+            Self::MissingAmount { client_id, tx_id } => {
+                write!(f, "client {client_id}: tx {tx_id} is missing an amount")
+            }
+            Self::UnknownTx { client_id, tx_id } => {
+                write!(f, "client {client_id}: tx {tx_id} does not exist")
+            }
+            Self::ClientMismatch { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: tx {tx_id} belongs to another client"
+                )
+            }
+            Self::InvalidDisputeState { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: tx {tx_id} is not in a valid state for this operation"
+                )
+            }
+            Self::InvalidDisputeAmount { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: amount is zero or more than can be covered on tx {tx_id}"
+                )
+            }
+            Self::CaptureExceedsAuthorization { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: capture is more than tx {tx_id} still authorizes"
+                )
+            }
+            Self::InvalidDestination { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: tx {tx_id} needs a destination other than the source"
+                )
+            }
+            Self::Overflow { client_id, tx_id } => {
+                write!(
+                    f,
+                    "client {client_id}: tx {tx_id} would overflow the balance"
+                )
+            }
+            Self::Storage {
+                client_id,
+                tx_id,
+                reason,
+            } => write!(
+                f,
+                "client {client_id}: failed to store tx {tx_id}: {reason}"
+            ),
+            Self::UnknownClient { client_id } => write!(f, "client {client_id} does not exist"),
+            Self::MissingAuditInfo { client_id } => {
+                write!(
+                    f,
+                    "client {client_id}: admin action needs an operator and a reason"
+                )
+            }
+            Self::InvalidAccountStatus { client_id, status } => {
+                write!(
+                    f,
+                    "client {client_id}: action not allowed on a {status} account"
+                )
+            }
+        }
+    }
+}
+
+impl Error for TxError {}
+
+impl From<csv::Error> for TxError {
+    fn from(error: csv::Error) -> Self {
+        Self::Parse {
+            reason: error.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for TxError {
+    fn from(error: serde_json::Error) -> Self {
+        // JSON input is parsed a line at a time, so only the column says anything.
+        let reason = error.to_string();
+        let location = format!(" at line {} column {}", error.line(), error.column());
+        Self::Parse {
+            reason: match reason.strip_suffix(&location) {
+                Some(message) => format!("{message} at column {}", error.column()),
+                None => reason,
+            },
+        }
+    }
+}
+
+impl TxError {
+    pub(crate) fn storage(client_id: u16, tx_id: u32, error: &io::Error) -> Self {
+        Self::Storage {
+            client_id,
+            tx_id,
+            reason: error.to_string(),
+        }
+    }
+
+    /// A short, stable name for the kind of rejection.
+    pub const fn category(&self) -> &'static str {
+        match self {
+            Self::Parse { .. } => "parse",
+            Self::DuplicateTxId { .. } => "duplicate_tx_id",
+            Self::AccountLocked { .. } => "account_locked",
+            Self::AccountFrozen { .. } => "account_frozen",
+            Self::AccountClosed { .. } => "account_closed",
+            Self::InsufficientFunds { .. } => "insufficient_funds",
+            Self::OverdraftLimit { .. } => "overdraft_limit",
+            Self::NegativeAmount { .. } => "negative_amount",
+            Self::MissingAmount { .. } => "missing_amount",
+            Self::UnknownTx { .. } => "unknown_tx",
+            Self::ClientMismatch { .. } => "client_mismatch",
+            Self::InvalidDisputeState { .. } => "invalid_dispute_state",
+            Self::InvalidDisputeAmount { .. } => "invalid_dispute_amount",
+            Self::CaptureExceedsAuthorization { .. } => "capture_exceeds_authorization",
+            Self::InvalidDestination { .. } => "invalid_destination",
+            Self::Overflow { .. } => "overflow",
+            Self::Storage { .. } => "storage",
+            Self::UnknownClient { .. } => "unknown_client",
+            Self::MissingAuditInfo { .. } => "missing_audit_info",
+            Self::InvalidAccountStatus { .. } => "invalid_account_status",
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use crate::account::OutputRecord;
+use crate::amount::{Amount, DecimalStyle};
+use crate::transaction::TxType;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path;
+
+/// The transaction types a fee can be charged on.
+pub(crate) const FEE_TYPES: [TxType; 3] = [TxType::Deposit, TxType::Withdrawal, TxType::Chargeback];
+
+/// A fee of a flat amount plus a percentage of the transaction amount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FeeRate {
+    pub flat: Amount,
+    /// Between 0 and 100, e.g. 1.5 for 1.5%.
+    pub percent: Amount,
+}
+
+impl FeeRate {
+    /// The fee on `amount`. The percentage is rounded to the nearest ten-thousandth, halves up.
+    /// Returns `None` if the fee doesn't fit in an amount.
+    pub fn fee(&self, amount: Amount) -> Option<Amount> {
+        // Both are ten-thousandths, so the product is in units of 10^-8 percent.
+        let scaled =
+            i128::from(amount.ten_thousandths()) * i128::from(self.percent.ten_thousandths());
+        let percentage = (scaled + 500_000).div_euclid(1_000_000);
+        let percentage = Amount::from_ten_thousandths(i64::try_from(percentage).ok()?);
+        self.flat.checked_add(percentage)
+    }
+}
+
+/// The fees charged on deposits, withdrawals and chargebacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FeeSchedule {
+    pub deposit: FeeRate,
+    pub withdrawal: FeeRate,
+    /// A penalty on the client who charged back, on the amount charged back.
+    pub chargeback: FeeRate,
+}
+
+#[derive(serde::Deserialize)]
+struct FeeRow {
+    #[serde(rename = "type")]
+    tx_type: TxType,
+    flat: Option<Amount>,
+    percent: Option<Amount>,
+}
+
+impl FeeSchedule {
+    /// Reads a fee schedule from a csv file, see `from_csv_reader`.
+    pub fn from_csv_file(path: &path::Path) -> Result<Self, Box<dyn Error>> {
+        Self::from_csv_reader(io::BufReader::new(fs::File::open(path)?))
+    }
+
+    /// Reads a fee schedule from csv with the columns `type`, `flat` and `percent`, one row for
+    /// each of `deposit`, `withdrawal` and `chargeback` that has a fee. An empty field is zero.
+    pub fn from_csv_reader(reader: impl io::Read) -> Result<Self, Box<dyn Error>> {
+        let mut schedule = Self::default();
+        let mut seen = Vec::new();
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in csv_reader.deserialize() {
+            let row: FeeRow = row?;
+            let rate = FeeRate {
+                flat: row.flat.unwrap_or(Amount::ZERO),
+                percent: row.percent.unwrap_or(Amount::ZERO),
+            };
+            if rate.flat.is_negative() || rate.percent.is_negative() {
+                return Err(format!("the {} fee is negative", row.tx_type).into());
+            }
+            if rate.percent > Amount::from_ten_thousandths(100 * 10_000) {
+                return Err(format!("the {} fee is over 100%", row.tx_type).into());
+            }
+            if seen.contains(&row.tx_type) {
+                return Err(format!("the {} fee is given twice", row.tx_type).into());
+            }
+            seen.push(row.tx_type);
+            *schedule.rate_mut(row.tx_type).ok_or_else(|| {
+                format!(
+                    "no fee can be charged on {}, only on deposit, withdrawal or chargeback",
+                    row.tx_type
+                )
+            })? = rate;
+        }
+        Ok(schedule)
+    }
+
+    /// The fee on `amount` of a `tx_type` transaction, zero if none is charged on that type.
+    /// Returns `None` if the fee doesn't fit in an amount.
+    pub fn fee(&self, tx_type: TxType, amount: Amount) -> Option<Amount> {
+        match tx_type {
+            TxType::Deposit => self.deposit.fee(amount),
+            TxType::Withdrawal => self.withdrawal.fee(amount),
+            TxType::Chargeback => self.chargeback.fee(amount),
+            TxType::Dispute
+            | TxType::Resolve
+            | TxType::Transfer
+            | TxType::Authorize
+            | TxType::Capture
+            | TxType::Void => Some(Amount::ZERO),
+        }
+    }
+
+    fn rate_mut(&mut self, tx_type: TxType) -> Option<&mut FeeRate> {
+        match tx_type {
+            TxType::Deposit => Some(&mut self.deposit),
+            TxType::Withdrawal => Some(&mut self.withdrawal),
+            TxType::Chargeback => Some(&mut self.chargeback),
+            TxType::Dispute
+            | TxType::Resolve
+            | TxType::Transfer
+            | TxType::Authorize
+            | TxType::Capture
+            | TxType::Void => None,
+        }
+    }
+}
+
+/// A fee on one transaction: what the client was charged and what couldn't be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fee {
+    pub charged: Amount,
+    /// The part of the fee the client's available funds didn't cover, which isn't charged.
+    pub waived: Amount,
+}
+
+impl Fee {
+    /// Charges `fee` to `output_record`, but never takes its available funds below zero. Whatever
+    /// isn't covered is waived.
+    pub(crate) fn charge(output_record: &mut OutputRecord, fee: Amount) -> Self {
+        let charged = fee.min(output_record.available.max(Amount::ZERO));
+        // The charge is between zero and both the fee and the available funds, so none of this
+        // can overflow.
+        output_record.available = output_record.available.checked_sub(charged).unwrap();
+        output_record.total = output_record.total.checked_sub(charged).unwrap();
+        Self {
+            charged,
+            waived: fee.checked_sub(charged).unwrap(),
+        }
+    }
+}
+
+/// The fees of one transaction type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FeeTotals {
+    /// How many transactions had a fee.
+    pub count: u64,
+    pub charged: Amount,
+    pub waived: Amount,
+}
+
+impl FeeTotals {
+    fn add(&mut self, other: &Self) {
+        self.count += other.count;
+        self.charged = self.charged.saturating_add(other.charged);
+        self.waived = self.waived.saturating_add(other.waived);
+    }
+}
+
+/// Every fee the engine charged, by transaction type. Everything charged went to the house.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FeeSummary {
+    // The totals of each of `FEE_TYPES`.
+    totals: [FeeTotals; FEE_TYPES.len()],
+}
+
+impl FeeSummary {
+    /// The totals of every transaction type that had a fee.
+    pub fn totals(&self) -> impl Iterator<Item = (TxType, &FeeTotals)> {
+        FEE_TYPES
+            .into_iter()
+            .zip(&self.totals)
+            .filter(|(_, totals)| totals.count > 0)
+    }
+
+    /// The totals over every transaction type, what the house earned is `charged`.
+    pub fn total(&self) -> FeeTotals {
+        let mut total = FeeTotals::default();
+        for totals in &self.totals {
+            total.add(totals);
+        }
+        total
+    }
+
+    /// Adds a fee on a `tx_type` transaction, which is one a fee can be charged on.
+    pub(crate) fn add(&mut self, tx_type: TxType, fee: &Fee) {
+        let totals = FeeTotals {
+            count: 1,
+            charged: fee.charged,
+            waived: fee.waived,
+        };
+        self.insert(tx_type, totals)
+            .expect("no fee can be charged on this transaction type");
+    }
+
+    /// Adds `totals` to the totals of `tx_type`, e.g. ones restored from a checkpoint. Fails if no
+    /// fee can be charged on `tx_type`.
+    pub(crate) fn insert(&mut self, tx_type: TxType, totals: FeeTotals) -> Result<(), String> {
+        let index = FEE_TYPES
+            .iter()
+            .position(|fee_type| *fee_type == tx_type)
+            .ok_or_else(|| format!("no fee can be charged on {tx_type}"))?;
+        self.totals[index].add(&totals);
+        Ok(())
+    }
+
+    /// Adds every total of `other`.
+    pub(crate) fn merge(&mut self, other: &Self) {
+        for (totals, other) in self.totals.iter_mut().zip(&other.totals) {
+            totals.add(other);
+        }
+    }
+}
+
+/// Writes the fee summary to writer in csv format, one row per transaction type that had a fee and
+/// a final row for the totals.
+/// * `style` - How trailing zeros are written in the amount columns.
+pub fn write_fee_summary(
+    summary: &FeeSummary,
+    writer: impl io::Write,
+    style: DecimalStyle,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["fee", "count", "charged", "waived"])?;
+    let total = summary.total();
+    for (fee, totals) in summary
+        .totals()
+        .map(|(tx_type, totals)| (tx_type.to_string(), totals))
+        .chain([("total".to_owned(), &total)])
+    {
+        wtr.write_record([
+            fee,
+            totals.count.to_string(),
+            totals.charged.to_string_with(style),
+            totals.waived.to_string_with(style),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(num: &str) -> Amount {
+        num.parse().unwrap()
+    }
+
+    #[test]
+    fn rounding_test() {
+        let rate = FeeRate {
+            flat: amount("0.5"),
+            percent: amount("1.5"),
+        };
+        assert_eq!(rate.fee(amount("100")), Some(amount("2")));
+        // 1.5% of 0.0033 is 0.0000495, which rounds down, and of 0.0034 0.000051, which rounds up.
+        assert_eq!(rate.fee(amount("0.0033")), Some(amount("0.5")));
+        assert_eq!(rate.fee(amount("0.0034")), Some(amount("0.5001")));
+        // Exactly half way rounds up.
+        let rate = FeeRate {
+            flat: Amount::ZERO,
+            percent: amount("50"),
+        };
+        assert_eq!(rate.fee(amount("0.0001")), Some(amount("0.0001")));
+        assert_eq!(
+            rate.fee(amount("922337203685477.5807")),
+            Some(amount("461168601842738.7904"))
+        );
+        let rate = FeeRate {
+            flat: amount("1"),
+            percent: amount("100"),
+        };
+        assert_eq!(rate.fee(amount("922337203685477.5807")), None);
+    }
+
+    #[test]
+    fn charge_test() {
+        let mut output_record = OutputRecord::new(amount("3"));
+        assert_eq!(
+            Fee::charge(&mut output_record, amount("2")),
+            Fee {
+                charged: amount("2"),
+                waived: Amount::ZERO
+            }
+        );
+        assert_eq!(
+            Fee::charge(&mut output_record, amount("2")),
+            Fee {
+                charged: amount("1"),
+                waived: amount("1")
+            }
+        );
+        assert_eq!(output_record, OutputRecord::new(Amount::ZERO));
+
+        // Nothing is charged to an account that is already negative.
+        let mut output_record = OutputRecord::new(amount("-1"));
+        assert_eq!(
+            Fee::charge(&mut output_record, amount("2")).waived,
+            amount("2")
+        );
+        assert_eq!(output_record.available, amount("-1"));
+    }
+
+    #[test]
+    fn schedule_test() {
+        let schedule = FeeSchedule::from_csv_reader(
+            "type, flat, percent\n\
+             withdrawal, 0.5,\n\
+             deposit, , 1.5\n\
+             chargeback, 15, 0\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            schedule.fee(TxType::Deposit, amount("10")),
+            Some(amount("0.15"))
+        );
+        assert_eq!(
+            schedule.fee(TxType::Withdrawal, amount("10")),
+            Some(amount("0.5"))
+        );
+        assert_eq!(
+            schedule.fee(TxType::Chargeback, amount("10")),
+            Some(amount("15"))
+        );
+        assert_eq!(
+            schedule.fee(TxType::Dispute, amount("10")),
+            Some(Amount::ZERO)
+        );
+
+        for (csv, error) in [
+            (
+                "type,flat,percent\ndispute,1,\n",
+                "no fee can be charged on dispute",
+            ),
+            (
+                "type,flat,percent\ndeposit,-1,\n",
+                "the deposit fee is negative",
+            ),
+            (
+                "type,flat,percent\ndeposit,,100.0001\n",
+                "the deposit fee is over 100%",
+            ),
+            (
+                "type,flat,percent\ndeposit,1,\ndeposit,2,\n",
+                "the deposit fee is given twice",
+            ),
+        ] {
+            let result = FeeSchedule::from_csv_reader(csv.as_bytes());
+            assert!(result.unwrap_err().to_string().starts_with(error), "{csv}");
+        }
+        assert!(FeeSchedule::from_csv_reader("type,flat,percent\nfee,1,\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn summary_test() {
+        let mut summary = FeeSummary::default();
+        summary.add(
+            TxType::Withdrawal,
+            &Fee {
+                charged: amount("0.5"),
+                waived: Amount::ZERO,
+            },
+        );
+        summary.add(
+            TxType::Chargeback,
+            &Fee {
+                charged: amount("10"),
+                waived: amount("5"),
+            },
+        );
+        summary.add(
+            TxType::Deposit,
+            &Fee {
+                charged: amount("0.15"),
+                waived: Amount::ZERO,
+            },
+        );
+        let mut merged = FeeSummary::default();
+        merged.merge(&summary);
+        merged.merge(&summary);
+
+        let mut bytes = Vec::new();
+        write_fee_summary(&merged, &mut bytes, DecimalStyle::Minimal).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "fee,count,charged,waived\n\
+             deposit,2,0.3,0\n\
+             withdrawal,2,1,0\n\
+             chargeback,2,20,10\n\
+             total,6,21.3,10\n"
+        );
+    }
+}
@@ -0,0 +1,408 @@
+use crate::amount::DecimalStyle;
+use crate::engine::{parse_json_line, Engine};
+use crate::error::TxError;
+use crate::output::{json_accounts, JsonAccount};
+use crate::server::lock;
+use crate::store::TxStore;
+use crate::transaction::{InputRecord, TxType};
+use serde::Serialize;
+use std::io::{self, Read};
+use std::net;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Method, Request, Response};
+
+/// The largest request body that is read, a transaction is a lot smaller.
+const MAX_BODY_LEN: u64 = 64 * 1024;
+
+/// How many requests are answered at once, the others wait for a free worker.
+const WORKERS: usize = 8;
+
+/// The answer to a posted transaction.
+#[derive(Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+enum TxResult {
+    Applied,
+    Rejected {
+        /// The `TxError::category` of the rejection.
+        category: &'static str,
+        reason: String,
+    },
+}
+
+/// A remembered transaction and how much of it is disputed, or captured for an authorization.
+#[derive(Serialize)]
+struct JsonTransaction {
+    tx: u32,
+    client: u16,
+    #[serde(rename = "type")]
+    tx_type: String,
+    amount: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<u16>,
+    state: String,
+    disputed: String,
+    charged_back: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    captured: Option<String>,
+}
+
+impl JsonTransaction {
+    fn new(record: &InputRecord, style: DecimalStyle) -> Self {
+        Self {
+            tx: record.tx_id,
+            client: record.client_id,
+            tx_type: record.tx_type.to_string(),
+            amount: record.amount.map(|amount| amount.to_string_with(style)),
+            destination: record.destination,
+            state: record.state().to_string(),
+            disputed: record.disputed_amount().to_string_with(style),
+            charged_back: record.charged_back_amount().to_string_with(style),
+            captured: (record.tx_type == TxType::Authorize)
+                .then(|| record.captured_amount().to_string_with(style)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonError {
+    error: String,
+}
+
+/// Serves `engine` over HTTP on `listener`, answering requests on a fixed number of worker
+/// threads. Only returns if accepting a request fails.
+///
+/// Every body is JSON and amounts are decimal strings written in `style`.
+/// * `POST /transactions` applies a transaction given as an object with the fields `type`,
+///   `client`, `tx` and `amount`, like a line of JSON Lines input. It answers
+///   `{"result":"applied"}`, or `{"result":"rejected","category":...,"reason":...}` with status
+///   400 if the body isn't a transaction, 413 if it is longer than `MAX_BODY_LEN` and 422 if the
+///   engine refused it.
+/// * `GET /accounts` answers every account as `write_output_as` writes them in JSON.
+/// * `GET /accounts/{client}` answers one account.
+/// * `GET /transactions/{tx}` answers a deposit, withdrawal, transfer or authorization with its
+///   state and how much of it is disputed and charged back. Transfers also have their
+///   `destination`, and authorizations how much of them was `captured`.
+///
+/// Anything that doesn't exist answers 404 with `{"error":...}`.
+pub fn serve_http<S: TxStore + Send + 'static>(
+    listener: net::TcpListener,
+    engine: Arc<Mutex<Engine<S>>>,
+    style: DecimalStyle,
+) -> io::Result<()> {
+    let server = tiny_http::Server::from_listener(listener, None)
+        .map_err(|error| io::Error::other(error.to_string()))?;
+    let server = Arc::new(server);
+    let workers: Vec<_> = (0..WORKERS)
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let engine = Arc::clone(&engine);
+            thread::spawn(move || -> io::Result<()> {
+                loop {
+                    let mut request = server.recv()?;
+                    let (status, body) = route(&mut request, &engine, style);
+                    let content_type =
+                        Header::from_bytes("Content-Type", "application/json").unwrap();
+                    let response = Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(content_type);
+                    // The client went away, there is nobody left to tell.
+                    let _ = request.respond(response);
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        // A worker only stops when accepting fails, or by panicking, which is passed on.
+        worker.join().unwrap()?;
+    }
+    Ok(())
+}
+
+/// Answers `request` with a status code and a JSON body.
+fn route<S: TxStore>(
+    request: &mut Request,
+    engine: &Mutex<Engine<S>>,
+    style: DecimalStyle,
+) -> (u16, String) {
+    let url = request.url().to_owned();
+    let path = url.split_once('?').map_or(url.as_str(), |(path, _)| path);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (request.method(), segments.as_slice()) {
+        (Method::Post, ["transactions"]) => post_transaction(request, engine),
+        (Method::Get, ["accounts"]) => {
+            let engine = lock(engine);
+            let accounts: Vec<_> = json_accounts(&engine, style).collect();
+            (200, to_json(&accounts))
+        }
+        (Method::Get, ["accounts", client_id]) => match client_id.parse() {
+            Ok(client_id) => match lock(engine).account(client_id) {
+                Some(account) => (200, to_json(&JsonAccount::new(client_id, account, style))),
+                None => error(404, format!("no account for client {client_id}")),
+            },
+            Err(_) => error(404, format!("invalid client id {client_id:?}")),
+        },
+        (Method::Get, ["transactions", tx_id]) => match tx_id.parse() {
+            Ok(tx_id) => match lock(engine).transaction(tx_id) {
+                Ok(Some(record)) => (200, to_json(&JsonTransaction::new(&record, style))),
+                Ok(None) => error(404, format!("no transaction {tx_id}")),
+                Err(io_error) => error(500, format!("failed to read tx {tx_id}: {io_error}")),
+            },
+            Err(_) => error(404, format!("invalid transaction id {tx_id:?}")),
+        },
+        (_, ["transactions" | "accounts"] | ["transactions" | "accounts", _]) => {
+            error(405, format!("{} isn't allowed on {path}", request.method()))
+        }
+        _ => error(404, format!("no such resource {path}")),
+    }
+}
+
+fn post_transaction<S: TxStore>(request: &mut Request, engine: &Mutex<Engine<S>>) -> (u16, String) {
+    let mut body = Vec::new();
+    // One byte more than allowed is read to tell a body at the limit from a longer one.
+    if let Err(io_error) = request
+        .as_reader()
+        .take(MAX_BODY_LEN + 1)
+        .read_to_end(&mut body)
+    {
+        return error(400, format!("failed to read the body: {io_error}"));
+    }
+    if body.len() as u64 > MAX_BODY_LEN {
+        return error(413, format!("the body is longer than {MAX_BODY_LEN} bytes"));
+    }
+    let result = {
+        let mut engine = lock(engine);
+        let result = engine.process_input_record(parse_json_line(&body));
+        // Only acknowledge the transaction once the store and the journal say it's kept.
+        if let Err(io_error) = engine.flush_store() {
+            return error(
+                500,
+                format!("failed to write the transaction store: {io_error}"),
+            );
+        }
+        if let Err(io_error) = engine.sync_journal() {
+            return error(500, format!("failed to sync the journal: {io_error}"));
+        }
+        result
+    };
+    match result {
+        Ok(()) => (200, to_json(&TxResult::Applied)),
+        Err(tx_error) => {
+            let status = match tx_error {
+                TxError::Parse { .. } => 400,
+                TxError::Storage { .. } => 500,
+                _ => 422,
+            };
+            let rejection = TxResult::Rejected {
+                category: tx_error.category(),
+                reason: tx_error.to_string(),
+            };
+            (status, to_json(&rejection))
+        }
+    }
+}
+
+fn error(status: u16, error: String) -> (u16, String) {
+    (status, to_json(&JsonError { error }))
+}
+
+fn to_json(value: &impl Serialize) -> String {
+    // These are plain structs with string keys, serializing them can't fail.
+    serde_json::to_string(value).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::{write_output_as, OutputFormat};
+    use std::io::Write;
+    use std::path;
+
+    // Starts a server on a free port, the server thread lives as long as the test process.
+    fn start(engine: Engine) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = Arc::new(Mutex::new(engine));
+        thread::spawn(move || serve_http(listener, engine, DecimalStyle::Fixed));
+        addr
+    }
+
+    // Sends a request on a new connection and returns the status code and JSON body.
+    fn request(
+        addr: net::SocketAddr,
+        method: &str,
+        path: &str,
+        body: &str,
+    ) -> (u16, serde_json::Value) {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    fn post(addr: net::SocketAddr, body: &str) -> (u16, serde_json::Value) {
+        request(addr, "POST", "/transactions", body)
+    }
+
+    #[test]
+    fn endpoints_test() {
+        let addr = start(Engine::new());
+        let applied = serde_json::json!({"result": "applied"});
+        assert_eq!(
+            post(
+                addr,
+                r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10"}"#
+            ),
+            (200, applied.clone())
+        );
+        assert_eq!(
+            post(
+                addr,
+                r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": 20}"#
+            ),
+            (
+                422,
+                serde_json::json!({
+                    "result": "rejected",
+                    "category": "insufficient_funds",
+                    "reason": "client 1: insufficient funds for tx 2",
+                })
+            )
+        );
+        let (status, body) = post(addr, r#"{"type": "deposit", "client": 1}"#);
+        assert_eq!(status, 400);
+        assert_eq!(body["category"], "parse");
+        assert_eq!(
+            post(
+                addr,
+                r#"{"type": "dispute", "client": 1, "tx": 1, "amount": "4"}"#
+            ),
+            (200, applied)
+        );
+
+        assert_eq!(
+            request(addr, "GET", "/accounts/1", ""),
+            (
+                200,
+                serde_json::json!({
+                    "client": 1,
+                    "available": "6.0000",
+                    "held": "4.0000",
+                    "total": "10.0000",
+                    "locked": false,
+                })
+            )
+        );
+        assert_eq!(request(addr, "GET", "/accounts/2", "").0, 404);
+        assert_eq!(request(addr, "GET", "/accounts/x", "").0, 404);
+        let (status, accounts) = request(addr, "GET", "/accounts", "");
+        assert_eq!(status, 200);
+        assert_eq!(accounts.as_array().unwrap().len(), 1);
+
+        assert_eq!(
+            request(addr, "GET", "/transactions/1", ""),
+            (
+                200,
+                serde_json::json!({
+                    "tx": 1,
+                    "client": 1,
+                    "type": "deposit",
+                    "amount": "10.0000",
+                    "state": "in_dispute",
+                    "disputed": "4.0000",
+                    "charged_back": "0.0000",
+                })
+            )
+        );
+        // Refused withdrawals are remembered so their ID can't be reused, but never applied.
+        assert_eq!(
+            request(addr, "GET", "/transactions/2", "").1["state"],
+            "not_applied"
+        );
+        assert_eq!(request(addr, "GET", "/transactions/3", "").0, 404);
+
+        // An authorization shows how much of it was captured, the rest is still held.
+        for body in [
+            r#"{"type": "authorize", "client": 1, "tx": 3, "amount": "5"}"#,
+            r#"{"type": "capture", "client": 1, "tx": 3, "amount": "2"}"#,
+        ] {
+            assert_eq!(post(addr, body).0, 200);
+        }
+        let (status, authorization) = request(addr, "GET", "/transactions/3", "");
+        assert_eq!(status, 200);
+        assert_eq!(authorization["state"], "authorized");
+        assert_eq!(authorization["captured"], "2.0000");
+        assert_eq!(request(addr, "GET", "/accounts/1", "").1["held"], "7.0000");
+
+        let too_long = " ".repeat(MAX_BODY_LEN as usize + 1);
+        assert_eq!(post(addr, &too_long).0, 413);
+
+        assert_eq!(request(addr, "DELETE", "/accounts/1", "").0, 405);
+        assert_eq!(request(addr, "GET", "/balances", "").0, 404);
+    }
+
+    // Posting a sample file gives the same accounts as processing it in a batch.
+    #[test]
+    fn sample_file_test() {
+        let json_file = "sample_data/disputes.jsonl";
+        let mut engine = Engine::new();
+        engine
+            .process_json_lines_file(path::Path::new(json_file))
+            .unwrap();
+        let mut expected = Vec::new();
+        write_output_as(
+            &engine,
+            &mut expected,
+            OutputFormat::Json,
+            DecimalStyle::Fixed,
+        )
+        .unwrap();
+        let expected: serde_json::Value = serde_json::from_slice(&expected).unwrap();
+
+        let addr = start(Engine::new());
+        for line in std::fs::read_to_string(json_file).unwrap().lines() {
+            if !line.trim().is_empty() {
+                post(addr, line);
+            }
+        }
+        assert_eq!(request(addr, "GET", "/accounts", ""), (200, expected));
+    }
+
+    // Requests from many clients at once all apply to one engine.
+    #[test]
+    fn concurrent_requests_test() {
+        let addr = start(Engine::new());
+        let clients: Vec<_> = (0..8u32)
+            .map(|client| {
+                thread::spawn(move || {
+                    for row in 0..20 {
+                        let tx_id = client * 1000 + row;
+                        let body = format!(
+                            r#"{{"type": "deposit", "client": 1, "tx": {tx_id}, "amount": "0.5"}}"#
+                        );
+                        assert_eq!(post(addr, &body).0, 200);
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        assert_eq!(
+            request(addr, "GET", "/accounts/1", "").1["total"],
+            "80.0000"
+        );
+    }
+}
@@ -0,0 +1,729 @@
+use crate::account::{AccountStatus, OutputRecord};
+use crate::admin::{AdminRecord, AdminType};
+use crate::amount::Amount;
+use crate::disk_store::{self, RECORD_LEN};
+use crate::fees::{Fee, FEE_TYPES};
+use crate::transaction::{InputRecord, TxType};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path;
+use std::str::FromStr;
+
+/// Written at the start of every journal file.
+const MAGIC: &[u8; 8] = b"TXJRNL05";
+/// The length, sequence number and checksum around every entry.
+const HEADER_LEN: usize = 4 + 8;
+const CHECKSUM_LEN: usize = 4;
+/// No entry comes close to this, a longer length means the entry is corrupt.
+const MAX_PAYLOAD_LEN: u32 = 1 << 20;
+
+const HAS_ACCOUNT: u8 = 1;
+const HAS_TX: u8 = 1 << 1;
+const HAS_ADMIN: u8 = 1 << 2;
+const HAS_COUNTERPARTY: u8 = 1 << 3;
+const HAS_FEE: u8 = 1 << 4;
+
+/// When the journal is synced to disk. Entries are always written to the file before their change
+/// is applied, this decides whether they also survive the machine going down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FsyncPolicy {
+    /// Every entry is synced before its change is applied.
+    #[default]
+    EveryWrite,
+    /// Synced after this many entries, and whenever the journal is synced explicitly.
+    Batch(u64),
+    /// Never synced by the engine, the OS decides when entries reach the disk.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "always" => Ok(Self::EveryWrite),
+            None if s == "never" => Ok(Self::Never),
+            Some(("batch", entries)) => match entries.parse() {
+                Ok(entries) if entries > 0 => Ok(Self::Batch(entries)),
+                _ => Err(format!("invalid batch size {entries:?}")),
+            },
+            _ => Err(format!(
+                "unknown fsync policy {s:?}, expected always, batch:N or never"
+            )),
+        }
+    }
+}
+
+/// Everything one transaction or admin action changes in the engine state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Effect {
+    pub client_id: u16,
+    /// The type of transaction that made the change, `None` for admin actions.
+    pub tx_type: Option<TxType>,
+    /// The client account after the change.
+    pub account: Option<OutputRecord>,
+    /// The new version of a remembered transaction.
+    pub tx: Option<InputRecord>,
+    /// An admin action to add to the admin log.
+    pub admin: Option<AdminRecord>,
+    /// The other client account a transfer changed and its client id, changed together with
+    /// `account` so a transfer is never half applied.
+    pub counterparty: Option<(u16, OutputRecord)>,
+    /// The fee the transaction charged to `account`, already taken out of it.
+    pub fee: Option<Fee>,
+}
+
+/// An append-only log of every change the engine applies, so the engine state can be rebuilt
+/// after a crash.
+///
+/// The file starts with a magic number, followed by entries of a little endian `u32` payload
+/// length, a `u64` sequence number, the payload and a CRC-32 of the sequence number and payload.
+pub(crate) struct Journal {
+    file: fs::File,
+    fsync: FsyncPolicy,
+    // The length of the file up to the end of the last complete entry.
+    len: u64,
+    // The length of the file before the last entry was appended.
+    last_entry_start: u64,
+    next_seq: u64,
+    unsynced: u64,
+    // Set when a failed write couldn't be rolled back, so the file can't be appended to safely.
+    broken: bool,
+}
+
+impl Journal {
+    /// Opens or creates the journal at `path` and calls `replay` with every complete entry in order.
+    ///
+    /// A last entry that is cut short or doesn't check out, e.g. one that was being written when
+    /// the process died, is truncated. A bad entry with more of the journal after it is corruption,
+    /// the journal is left as it is and opening it fails with `InvalidData`.
+    pub(crate) fn open(
+        path: &path::Path,
+        fsync: FsyncPolicy,
+        mut replay: impl FnMut(Effect) -> io::Result<()>,
+    ) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let file_len = file.metadata()?.len();
+        if file_len == 0 {
+            file.write_all(MAGIC)?;
+            file.sync_all()?;
+        } else {
+            let mut magic = [0; MAGIC.len()];
+            if file.read_exact(&mut magic).is_err() || &magic != MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a transaction journal",
+                ));
+            }
+        }
+
+        let mut reader = io::BufReader::new(&file);
+        let mut len = MAGIC.len() as u64;
+        let mut next_seq = 0;
+        while len < file_len {
+            let entry_len = match read_entry(&mut reader)? {
+                ReadEntry::Valid(seq, payload) => {
+                    let entry_len = (HEADER_LEN + payload.len() + CHECKSUM_LEN) as u64;
+                    match decode_effect(&payload) {
+                        Some(effect) if seq == next_seq => {
+                            replay(effect)?;
+                            len += entry_len;
+                            next_seq += 1;
+                            continue;
+                        }
+                        _ => entry_len,
+                    }
+                }
+                ReadEntry::Invalid(entry_len) => entry_len,
+            };
+            // Only the entry being written when the process died can be torn, and nothing follows
+            // it. Truncating anything else would throw away the entries after it.
+            if len + entry_len < file_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt journal entry {next_seq} at byte {len}"),
+                ));
+            }
+            break;
+        }
+        drop(reader);
+        if len < file_len {
+            file.set_len(len)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(len))?;
+        Ok(Self {
+            file,
+            fsync,
+            len,
+            last_entry_start: len,
+            next_seq,
+            unsynced: 0,
+            broken: false,
+        })
+    }
+
+    /// Appends `effect` to the journal, syncing it if the policy says so.
+    pub(crate) fn append(&mut self, effect: &Effect) -> io::Result<()> {
+        if self.broken {
+            return Err(io::Error::other(
+                "journal is broken after an earlier write failed",
+            ));
+        }
+        let payload = encode_effect(effect);
+        let seq = self.next_seq.to_le_bytes();
+        let mut entry = Vec::with_capacity(HEADER_LEN + payload.len() + CHECKSUM_LEN);
+        entry.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        entry.extend_from_slice(&seq);
+        entry.extend_from_slice(&payload);
+        entry.extend_from_slice(&crc32(&[&seq, &payload]).to_le_bytes());
+
+        if let Err(error) = self.file.write_all(&entry) {
+            // Drop whatever part of the entry was written so later entries aren't lost behind it.
+            let rolled_back = self
+                .file
+                .set_len(self.len)
+                .and_then(|()| self.file.seek(SeekFrom::Start(self.len)));
+            self.broken = rolled_back.is_err();
+            return Err(error);
+        }
+        self.last_entry_start = self.len;
+        self.len += entry.len() as u64;
+        self.next_seq += 1;
+        self.unsynced += 1;
+        match self.fsync {
+            FsyncPolicy::EveryWrite => self.sync(),
+            FsyncPolicy::Batch(entries) if self.unsynced >= entries => self.sync(),
+            FsyncPolicy::Batch(_) | FsyncPolicy::Never => Ok(()),
+        }
+    }
+
+    /// Removes the entry appended last, for a change that couldn't be applied after all, so that it
+    /// isn't replayed. The removal is synced unless the policy is `Never`, since a replayed entry
+    /// that was never applied is worse than a lost one.
+    pub(crate) fn undo_last(&mut self) -> io::Result<()> {
+        if self.broken || self.last_entry_start == self.len {
+            return Err(io::Error::other("no journal entry to undo"));
+        }
+        let start = self.last_entry_start;
+        let undone = self
+            .file
+            .set_len(start)
+            .and_then(|()| self.file.seek(SeekFrom::Start(start)))
+            .and_then(|_| match self.fsync {
+                FsyncPolicy::Never => Ok(()),
+                _ => self.file.sync_data(),
+            });
+        if let Err(error) = undone {
+            self.broken = true;
+            return Err(error);
+        }
+        self.len = start;
+        self.next_seq -= 1;
+        self.unsynced = self.unsynced.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Syncs every entry written so far to disk, unless the policy is `Never`.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 && self.fsync != FsyncPolicy::Never {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// The number of entries in the journal.
+    pub const fn entries(&self) -> u64 {
+        self.next_seq
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+/// Reads the entry that starts at the reader's position, which must be before the end of the file.
+fn read_entry(reader: &mut impl Read) -> io::Result<ReadEntry> {
+    let mut header = [0; HEADER_LEN];
+    if !read_exact_or_eof(reader, &mut header)? {
+        return Ok(ReadEntry::Invalid(HEADER_LEN as u64));
+    }
+    let payload_len = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    if payload_len > MAX_PAYLOAD_LEN {
+        // The length itself is corrupt, so it says nothing about where the entry ends.
+        return Ok(ReadEntry::Invalid(HEADER_LEN as u64));
+    }
+    let entry_len = (HEADER_LEN + payload_len as usize + CHECKSUM_LEN) as u64;
+    let mut payload = vec![0; payload_len as usize];
+    let mut checksum = [0; CHECKSUM_LEN];
+    if !read_exact_or_eof(reader, &mut payload)? || !read_exact_or_eof(reader, &mut checksum)? {
+        return Ok(ReadEntry::Invalid(entry_len));
+    }
+    if crc32(&[&header[4..], &payload]) != u32::from_le_bytes(checksum) {
+        return Ok(ReadEntry::Invalid(entry_len));
+    }
+    Ok(ReadEntry::Valid(
+        u64::from_le_bytes(header[4..].try_into().unwrap()),
+        payload,
+    ))
+}
+
+/// An entry as it was read from the journal.
+enum ReadEntry {
+    /// A complete entry with a matching checksum, its sequence number and payload.
+    Valid(u64, Vec<u8>),
+    /// An entry that is cut short or corrupt, and how many bytes its header says it takes up.
+    Invalid(u64),
+}
+
+/// Fills `buf`, returning false if the reader ended first.
+fn read_exact_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(error) => Err(error),
+    }
+}
+
+fn encode_effect(effect: &Effect) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    buf.extend_from_slice(&effect.client_id.to_le_bytes());
+    let mut flags = 0;
+    if effect.account.is_some() {
+        flags |= HAS_ACCOUNT;
+    }
+    if effect.tx.is_some() {
+        flags |= HAS_TX;
+    }
+    if effect.admin.is_some() {
+        flags |= HAS_ADMIN;
+    }
+    if effect.counterparty.is_some() {
+        flags |= HAS_COUNTERPARTY;
+    }
+    if effect.fee.is_some() {
+        flags |= HAS_FEE;
+    }
+    buf.push(flags);
+    buf.push(match effect.tx_type {
+        None => 0,
+        Some(TxType::Deposit) => 1,
+        Some(TxType::Withdrawal) => 2,
+        Some(TxType::Dispute) => 3,
+        Some(TxType::Resolve) => 4,
+        Some(TxType::Chargeback) => 5,
+        Some(TxType::Transfer) => 6,
+        Some(TxType::Authorize) => 7,
+        Some(TxType::Capture) => 8,
+        Some(TxType::Void) => 9,
+    });
+    if let Some(account) = &effect.account {
+        encode_account(&mut buf, account);
+    }
+    if let Some(tx) = &effect.tx {
+        buf.extend_from_slice(&disk_store::encode(tx));
+    }
+    if let Some(admin) = &effect.admin {
+        buf.push(match admin.action {
+            AdminType::Unlock => 0,
+            AdminType::Freeze => 1,
+            AdminType::Close => 2,
+        });
+        for text in [&admin.operator, &admin.reason] {
+            buf.extend_from_slice(&(text.len() as u32).to_le_bytes());
+            buf.extend_from_slice(text.as_bytes());
+        }
+    }
+    if let Some((client_id, account)) = &effect.counterparty {
+        buf.extend_from_slice(&client_id.to_le_bytes());
+        encode_account(&mut buf, account);
+    }
+    if let Some(fee) = &effect.fee {
+        for amount in [fee.charged, fee.waived] {
+            buf.extend_from_slice(&amount.ten_thousandths().to_le_bytes());
+        }
+    }
+    buf
+}
+
+fn encode_account(buf: &mut Vec<u8>, account: &OutputRecord) {
+    for amount in [account.available, account.held, account.total] {
+        buf.extend_from_slice(&amount.ten_thousandths().to_le_bytes());
+    }
+    buf.push(match account.status {
+        AccountStatus::Active => 0,
+        AccountStatus::Frozen => 1,
+        AccountStatus::Locked => 2,
+        AccountStatus::Closed => 3,
+    });
+}
+
+/// Decodes a payload, returning `None` if it isn't a valid entry.
+fn decode_effect(mut buf: &[u8]) -> Option<Effect> {
+    fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+        if buf.len() < len {
+            return None;
+        }
+        let (taken, rest) = buf.split_at(len);
+        *buf = rest;
+        Some(taken)
+    }
+    let take_amount = |buf: &mut &[u8]| {
+        let bytes = take(buf, 8)?.try_into().ok()?;
+        Some(Amount::from_ten_thousandths(i64::from_le_bytes(bytes)))
+    };
+    let take_text = |buf: &mut &[u8]| {
+        let len = u32::from_le_bytes(take(buf, 4)?.try_into().ok()?);
+        String::from_utf8(take(buf, len as usize)?.to_vec()).ok()
+    };
+    let take_account = |buf: &mut &[u8]| {
+        Some(OutputRecord {
+            available: take_amount(buf)?,
+            held: take_amount(buf)?,
+            total: take_amount(buf)?,
+            status: match take(buf, 1)?[0] {
+                0 => AccountStatus::Active,
+                1 => AccountStatus::Frozen,
+                2 => AccountStatus::Locked,
+                3 => AccountStatus::Closed,
+                _ => return None,
+            },
+        })
+    };
+
+    let client_id = u16::from_le_bytes(take(&mut buf, 2)?.try_into().ok()?);
+    let flags = take(&mut buf, 1)?[0];
+    let tx_type = match take(&mut buf, 1)?[0] {
+        0 => None,
+        1 => Some(TxType::Deposit),
+        2 => Some(TxType::Withdrawal),
+        3 => Some(TxType::Dispute),
+        4 => Some(TxType::Resolve),
+        5 => Some(TxType::Chargeback),
+        6 => Some(TxType::Transfer),
+        7 => Some(TxType::Authorize),
+        8 => Some(TxType::Capture),
+        9 => Some(TxType::Void),
+        _ => return None,
+    };
+    let account = if flags & HAS_ACCOUNT == 0 {
+        None
+    } else {
+        Some(take_account(&mut buf)?)
+    };
+    let tx = if flags & HAS_TX == 0 {
+        None
+    } else {
+        Some(disk_store::decode(take(&mut buf, RECORD_LEN)?.try_into().ok()?).ok()?)
+    };
+    let admin = if flags & HAS_ADMIN == 0 {
+        None
+    } else {
+        let action = match take(&mut buf, 1)?[0] {
+            0 => AdminType::Unlock,
+            1 => AdminType::Freeze,
+            2 => AdminType::Close,
+            _ => return None,
+        };
+        let operator = take_text(&mut buf)?;
+        let reason = take_text(&mut buf)?;
+        Some(AdminRecord::new(action, client_id, &operator, &reason))
+    };
+    let counterparty = if flags & HAS_COUNTERPARTY == 0 {
+        None
+    } else {
+        let counterparty_id = u16::from_le_bytes(take(&mut buf, 2)?.try_into().ok()?);
+        Some((counterparty_id, take_account(&mut buf)?))
+    };
+    let fee = if flags & HAS_FEE == 0 {
+        None
+    } else if FEE_TYPES.contains(&tx_type?) {
+        Some(Fee {
+            charged: take_amount(&mut buf)?,
+            waived: take_amount(&mut buf)?,
+        })
+    } else {
+        return None;
+    };
+    if !buf.is_empty() {
+        return None;
+    }
+    Some(Effect {
+        client_id,
+        tx_type,
+        account,
+        tx,
+        admin,
+        counterparty,
+        fee,
+    })
+}
+
+/// The CRC-32 (IEEE) of the concatenated `parts`.
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::TxState;
+
+    fn temp_file(name: &str) -> path::PathBuf {
+        std::env::temp_dir().join(format!("toy_payment_engine_{name}_{}", std::process::id()))
+    }
+
+    fn effects() -> Vec<Effect> {
+        let mut tx = InputRecord::new(
+            TxType::Deposit,
+            7,
+            42,
+            Some(Amount::from_ten_thousandths(15_000)),
+        );
+        tx.state = TxState::InDispute;
+        tx.disputed = Amount::from_ten_thousandths(5_000);
+        let mut account = OutputRecord::new(Amount::from_ten_thousandths(-1));
+        account.status = AccountStatus::Closed;
+        vec![
+            Effect {
+                client_id: 7,
+                tx_type: Some(TxType::Deposit),
+                account: Some(OutputRecord::new(Amount::from_ten_thousandths(i64::MAX))),
+                tx: Some(tx),
+                admin: None,
+                counterparty: None,
+                fee: None,
+            },
+            Effect {
+                client_id: 7,
+                tx_type: Some(TxType::Chargeback),
+                account: None,
+                tx: Some(InputRecord::new(TxType::Withdrawal, 7, 43, None)),
+                admin: None,
+                counterparty: None,
+                fee: Some(Fee {
+                    charged: Amount::from_ten_thousandths(15_000),
+                    waived: Amount::from_ten_thousandths(i64::MAX),
+                }),
+            },
+            Effect {
+                client_id: 7,
+                tx_type: Some(TxType::Transfer),
+                account: Some(OutputRecord::new(Amount::ZERO)),
+                tx: Some(InputRecord::transfer(
+                    7,
+                    u16::MAX,
+                    44,
+                    Amount::from_ten_thousandths(1),
+                )),
+                admin: None,
+                counterparty: Some((u16::MAX, OutputRecord::new(Amount::from_ten_thousandths(1)))),
+                fee: None,
+            },
+            Effect {
+                client_id: 7,
+                tx_type: None,
+                account: Some(account),
+                tx: None,
+                admin: Some(AdminRecord::new(AdminType::Close, 7, "ops ✓", "")),
+                counterparty: None,
+                fee: None,
+            },
+        ]
+    }
+
+    fn reopen(path: &path::Path) -> (Journal, Vec<Effect>) {
+        let mut replayed = Vec::new();
+        let journal = Journal::open(path, FsyncPolicy::Never, |effect| {
+            replayed.push(effect);
+            Ok(())
+        })
+        .unwrap();
+        (journal, replayed)
+    }
+
+    #[test]
+    fn crc32_test() {
+        assert_eq!(crc32(&[b"123456789"]), 0xCBF4_3926);
+        assert_eq!(crc32(&[b"1234", b"56789"]), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn fsync_policy_test() {
+        assert_eq!("always".parse(), Ok(FsyncPolicy::EveryWrite));
+        assert_eq!("batch:100".parse(), Ok(FsyncPolicy::Batch(100)));
+        assert_eq!("never".parse(), Ok(FsyncPolicy::Never));
+        assert!("batch:0".parse::<FsyncPolicy>().is_err());
+        assert!("batch".parse::<FsyncPolicy>().is_err());
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+
+    // Entries are replayed in order, and a torn or corrupt tail is dropped so that new entries
+    // follow the last good one.
+    #[test]
+    fn replay_test() {
+        let path = temp_file("journal");
+        let _ = fs::remove_file(&path);
+        let effects = effects();
+        {
+            let mut journal =
+                Journal::open(&path, FsyncPolicy::Batch(2), |_| unreachable!()).unwrap();
+            for effect in &effects {
+                journal.append(effect).unwrap();
+            }
+            assert_eq!(journal.entries(), 4);
+        }
+        let (journal, replayed) = reopen(&path);
+        assert_eq!(replayed, effects);
+        drop(journal);
+
+        // Tear the last entry.
+        let full_len = fs::metadata(&path).unwrap().len();
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(full_len - 3).unwrap();
+        drop(file);
+        let (mut journal, replayed) = reopen(&path);
+        assert_eq!(replayed, effects[..3]);
+        assert_eq!(journal.entries(), 3);
+        journal.append(&effects[3]).unwrap();
+        drop(journal);
+        assert_eq!(fs::metadata(&path).unwrap().len(), full_len);
+        assert_eq!(reopen(&path).1, effects);
+
+        // An undone entry is gone, and the next one takes its place.
+        let (mut journal, _) = reopen(&path);
+        journal.append(&effects[0]).unwrap();
+        journal.undo_last().unwrap();
+        assert!(journal.undo_last().is_err());
+        assert_eq!(journal.entries(), 4);
+        drop(journal);
+        assert_eq!(fs::metadata(&path).unwrap().len(), full_len);
+        assert_eq!(reopen(&path).1, effects);
+
+        // A corrupt entry in the middle fails the open and leaves the journal as it is.
+        let mut bytes = fs::read(&path).unwrap();
+        let second_entry = MAGIC.len() + HEADER_LEN + encode_effect(&effects[0]).len() + 4;
+        bytes[second_entry + HEADER_LEN] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let error = Journal::open(&path, FsyncPolicy::Never, |_| Ok(()))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::read(&path).unwrap(), bytes);
+
+        // A corrupt last entry is taken for a torn one and truncated.
+        bytes[second_entry + HEADER_LEN] ^= 1;
+        let last_byte = bytes.len() - 1;
+        bytes[last_byte] ^= 1;
+        fs::write(&path, &bytes).unwrap();
+        let (journal, replayed) = reopen(&path);
+        assert_eq!(replayed, effects[..3]);
+        assert_eq!(journal.entries(), 3);
+        drop(journal);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            full_len - (HEADER_LEN + encode_effect(&effects[3]).len() + CHECKSUM_LEN) as u64
+        );
+
+        fs::write(&path, b"not a journal").unwrap();
+        assert!(Journal::open(&path, FsyncPolicy::Never, |_| Ok(())).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    // An engine rebuilt from the journal ends up with the same accounts, admin log and
+    // transactions as the engine that wrote it.
+    #[test]
+    fn engine_replay_test() {
+        use crate::engine::Engine;
+        use crate::error::TxError;
+        use crate::fees::FeeSchedule;
+        use crate::output::{write_admin_log, write_output};
+        use crate::DecimalStyle;
+
+        let output_of = |engine: &Engine| {
+            let mut bytes = Vec::new();
+            write_output(engine, &mut bytes, DecimalStyle::Fixed).unwrap();
+            write_admin_log(engine, &mut bytes).unwrap();
+            String::from_utf8(bytes).unwrap()
+        };
+        let path = temp_file("engine_journal");
+        let _ = fs::remove_file(&path);
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        engine.set_fee_schedule(
+            FeeSchedule::from_csv_file(path::Path::new("sample_data/fee_schedule.csv")).unwrap(),
+        );
+        assert_eq!(engine.open_journal(&path, FsyncPolicy::Never).unwrap(), 0);
+        for csv_file in [
+            "sample_data/disputes.csv",
+            "sample_data/partial_disputes.csv",
+            "sample_data/transfers.csv",
+        ] {
+            engine.process_csv_file(path::Path::new(csv_file)).unwrap();
+        }
+        engine
+            .process_admin_csv_file(
+                path::Path::new("sample_data/admin_actions.csv"),
+                None::<&mut crate::RejectsWriter<io::Sink>>,
+            )
+            .unwrap();
+        engine.sync_journal().unwrap();
+
+        let mut restored = Engine::new();
+        restored.enable_ledger();
+        assert!(
+            restored
+                .open_journal(&path, FsyncPolicy::EveryWrite)
+                .unwrap()
+                > 0
+        );
+        assert_eq!(output_of(&restored), output_of(&engine));
+        assert_eq!(
+            restored.ledger().unwrap().entries(),
+            engine.ledger().unwrap().entries()
+        );
+        // The fees charged are replayed, without the schedule that charged them.
+        assert!(restored.fee_summary().total().charged > Amount::ZERO);
+        assert_eq!(restored.fee_summary(), engine.fee_summary());
+        for tx_id in 1..=10 {
+            assert_eq!(
+                restored.transaction(tx_id).unwrap(),
+                engine.transaction(tx_id).unwrap()
+            );
+        }
+
+        // The replayed transactions still block duplicates, and a transaction refused before it
+        // changed anything isn't journaled.
+        let entries = restored.journal.as_ref().unwrap().entries();
+        assert_eq!(
+            restored.apply(InputRecord::new(
+                TxType::Deposit,
+                1,
+                1,
+                Some(Amount::from_ten_thousandths(1))
+            )),
+            Err(TxError::DuplicateTxId {
+                client_id: 1,
+                tx_id: 1
+            })
+        );
+        assert_eq!(restored.journal.as_ref().unwrap().entries(), entries);
+        drop((engine, restored));
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,525 @@
+use crate::account::OutputRecord;
+use crate::amount::Amount;
+use crate::engine::Engine;
+use crate::store::TxStore;
+use crate::transaction::TxType;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// An account in the general ledger.
+///
+/// Client balances are what the platform owes its clients, so they are credited when they grow.
+/// The system accounts are debited for money coming in and credited for money going out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LedgerAccount {
+    /// A client's available funds.
+    Available(u16),
+    /// A client's held funds.
+    Held(u16),
+    /// Deposits received.
+    CashIn,
+    /// Withdrawals paid out.
+    CashOut,
+    /// Deposits that were charged back and returned to the payer.
+    Chargebacks,
+    /// Withdrawals that were disputed, which the platform covers until they are resolved.
+    ChargebackLosses,
+    /// Funds moving between clients. Both sides of a transfer are posted here, so it nets to zero.
+    Transfers,
+    /// Fees charged to clients, the house's revenue.
+    FeeRevenue,
+}
+
+impl fmt::Display for LedgerAccount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Available(client_id) => write!(f, "client:{client_id}:available"),
+            Self::Held(client_id) => write!(f, "client:{client_id}:held"),
+            Self::CashIn => f.write_str("system:cash_in"),
+            Self::CashOut => f.write_str("system:cash_out"),
+            Self::Chargebacks => f.write_str("system:chargebacks"),
+            Self::ChargebackLosses => f.write_str("system:chargeback_losses"),
+            Self::Transfers => f.write_str("system:transfers"),
+            Self::FeeRevenue => f.write_str("system:fee_revenue"),
+        }
+    }
+}
+
+impl FromStr for LedgerAccount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("unknown ledger account {s:?}");
+        match s {
+            "system:cash_in" => Ok(Self::CashIn),
+            "system:cash_out" => Ok(Self::CashOut),
+            "system:chargebacks" => Ok(Self::Chargebacks),
+            "system:chargeback_losses" => Ok(Self::ChargebackLosses),
+            "system:transfers" => Ok(Self::Transfers),
+            "system:fee_revenue" => Ok(Self::FeeRevenue),
+            _ => {
+                let (client_id, balance) = s
+                    .strip_prefix("client:")
+                    .and_then(|rest| rest.split_once(':'))
+                    .ok_or_else(invalid)?;
+                let client_id = client_id.parse().map_err(|_| invalid())?;
+                match balance {
+                    "available" => Ok(Self::Available(client_id)),
+                    "held" => Ok(Self::Held(client_id)),
+                    _ => Err(invalid()),
+                }
+            }
+        }
+    }
+}
+
+/// One side of a ledger entry. A positive amount is a debit, a negative one a credit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Posting {
+    pub account: LedgerAccount,
+    pub amount: Amount,
+}
+
+/// The postings for one applied transaction. They always sum to zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerEntry {
+    /// Entries are numbered from 1 in the order they were applied.
+    pub id: u64,
+    pub tx_id: u32,
+    pub tx_type: TxType,
+    pub postings: Vec<Posting>,
+}
+
+/// Every ledger entry the engine made, in order.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    pub(crate) entries: Vec<LedgerEntry>,
+}
+
+impl Ledger {
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.entries
+    }
+
+    /// Records the change from `old` to `new` of a client account, made by a `tx_type` step on a
+    /// `disputed_tx_type` transaction (the same type for deposits and withdrawals, an authorization
+    /// for captures and voids).
+    pub(crate) fn record(
+        &mut self,
+        tx_id: u32,
+        tx_type: TxType,
+        disputed_tx_type: TxType,
+        client_id: u16,
+        old: Option<&OutputRecord>,
+        new: &OutputRecord,
+    ) {
+        let old = old.copied().unwrap_or(OutputRecord::new(Amount::ZERO));
+        // The engine only applies changes that fit, so the differences fit too.
+        let available = new.available.checked_sub(old.available).unwrap();
+        let held = new.held.checked_sub(old.held).unwrap();
+        let total = new.total.checked_sub(old.total).unwrap();
+        let system_account = match (tx_type, disputed_tx_type) {
+            (TxType::Transfer, _) | (_, TxType::Transfer) => LedgerAccount::Transfers,
+            (TxType::Deposit, _) => LedgerAccount::CashIn,
+            (TxType::Withdrawal | TxType::Capture, _) => LedgerAccount::CashOut,
+            (_, TxType::Withdrawal) => LedgerAccount::ChargebackLosses,
+            _ => LedgerAccount::Chargebacks,
+        };
+
+        // Client balances are credited as they grow, the system account takes the other side.
+        let postings: Vec<Posting> = [
+            (
+                LedgerAccount::Available(client_id),
+                available.checked_neg().unwrap(),
+            ),
+            (LedgerAccount::Held(client_id), held.checked_neg().unwrap()),
+            (system_account, total),
+        ]
+        .into_iter()
+        .filter(|(_, amount)| *amount != Amount::ZERO)
+        .map(|(account, amount)| Posting { account, amount })
+        .collect();
+        if !postings.is_empty() {
+            self.push(tx_id, tx_type, postings);
+        }
+    }
+
+    /// Records `fee` moving from a client's available funds to the house, charged by `tx_id`.
+    pub(crate) fn record_fee(&mut self, tx_id: u32, tx_type: TxType, client_id: u16, fee: Amount) {
+        // Only fees that were charged are recorded, which are never negative.
+        let postings = vec![
+            Posting {
+                account: LedgerAccount::Available(client_id),
+                amount: fee,
+            },
+            Posting {
+                account: LedgerAccount::FeeRevenue,
+                amount: fee.checked_neg().unwrap(),
+            },
+        ];
+        self.push(tx_id, tx_type, postings);
+    }
+
+    /// Adds an entry, e.g. one restored from a checkpoint.
+    pub(crate) fn push(&mut self, tx_id: u32, tx_type: TxType, postings: Vec<Posting>) {
+        self.entries.push(LedgerEntry {
+            id: self.entries.len() as u64 + 1,
+            tx_id,
+            tx_type,
+            postings,
+        });
+    }
+
+    /// Adds up the postings of every account.
+    pub fn trial_balance(&self) -> Result<TrialBalance, LedgerError> {
+        let mut accounts: BTreeMap<LedgerAccount, (Amount, Amount)> = BTreeMap::new();
+        let mut total_debits = Amount::ZERO;
+        let mut total_credits = Amount::ZERO;
+        for posting in self.entries.iter().flat_map(|entry| &entry.postings) {
+            let (debits, credits) = accounts.entry(posting.account).or_default();
+            if posting.amount.is_negative() {
+                let credit = posting.amount.checked_neg().ok_or(LedgerError::Overflow)?;
+                *credits = credits.checked_add(credit).ok_or(LedgerError::Overflow)?;
+                total_credits = total_credits
+                    .checked_add(credit)
+                    .ok_or(LedgerError::Overflow)?;
+            } else {
+                *debits = debits
+                    .checked_add(posting.amount)
+                    .ok_or(LedgerError::Overflow)?;
+                total_debits = total_debits
+                    .checked_add(posting.amount)
+                    .ok_or(LedgerError::Overflow)?;
+            }
+        }
+        Ok(TrialBalance {
+            accounts,
+            total_debits,
+            total_credits,
+        })
+    }
+}
+
+/// The debits and credits of every ledger account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrialBalance {
+    /// The total debits and credits of each account.
+    pub accounts: BTreeMap<LedgerAccount, (Amount, Amount)>,
+    pub total_debits: Amount,
+    pub total_credits: Amount,
+}
+
+impl TrialBalance {
+    /// The debits minus credits of `account`.
+    pub fn balance(&self, account: LedgerAccount) -> Amount {
+        self.accounts
+            .get(&account)
+            .and_then(|(debits, credits)| debits.checked_sub(*credits))
+            .unwrap_or(Amount::ZERO)
+    }
+}
+
+/// Why the ledger doesn't add up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LedgerError {
+    /// The engine isn't keeping a ledger.
+    Disabled,
+    /// The sum of all debits and credits doesn't come to zero.
+    Unbalanced { debits: Amount, credits: Amount },
+    /// A client balance in the ledger is different from the client account.
+    ClientMismatch { client_id: u16 },
+    /// The totals are too large to add up.
+    Overflow,
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disabled => f.write_str("the engine is not keeping a ledger"),
+            Self::Unbalanced { debits, credits } => {
+                write!(f, "debits of {debits} don't match credits of {credits}")
+            }
+            Self::ClientMismatch { client_id } => {
+                write!(f, "client {client_id}: ledger doesn't match the account")
+            }
+            Self::Overflow => f.write_str("ledger totals overflow"),
+        }
+    }
+}
+
+impl Error for LedgerError {}
+
+/// Writes every ledger entry to writer in csv format, one row per posting.
+pub fn write_ledger<S: TxStore>(
+    engine: &Engine<S>,
+    writer: impl io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let ledger = engine.ledger().ok_or(LedgerError::Disabled)?;
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["entry", "tx", "type", "account", "debit", "credit"])?;
+    for entry in ledger.entries() {
+        for posting in &entry.postings {
+            let (debit, credit) = if posting.amount.is_negative() {
+                (
+                    String::new(),
+                    posting.amount.checked_neg().unwrap().to_string(),
+                )
+            } else {
+                (posting.amount.to_string(), String::new())
+            };
+            wtr.write_record([
+                entry.id.to_string(),
+                entry.tx_id.to_string(),
+                entry.tx_type.to_string(),
+                posting.account.to_string(),
+                debit,
+                credit,
+            ])?;
+        }
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Writes the trial balance to writer in csv format, with a final row for the totals.
+pub fn write_trial_balance(
+    trial_balance: &TrialBalance,
+    writer: impl io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["account", "debit", "credit", "balance"])?;
+    for (account, (debits, credits)) in &trial_balance.accounts {
+        wtr.write_record([
+            account.to_string(),
+            debits.to_string(),
+            credits.to_string(),
+            trial_balance.balance(*account).to_string(),
+        ])?;
+    }
+    let total = trial_balance
+        .total_debits
+        .checked_sub(trial_balance.total_credits)
+        .ok_or(LedgerError::Overflow)?;
+    wtr.write_record([
+        "total".to_owned(),
+        trial_balance.total_debits.to_string(),
+        trial_balance.total_credits.to_string(),
+        total.to_string(),
+    ])?;
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fees::FeeSchedule;
+    use crate::transaction::InputRecord;
+    use std::path;
+
+    fn amount(num: &str) -> Amount {
+        num.parse().unwrap()
+    }
+
+    #[test]
+    fn account_names_test() {
+        for account in [
+            LedgerAccount::Available(0),
+            LedgerAccount::Held(u16::MAX),
+            LedgerAccount::CashIn,
+            LedgerAccount::CashOut,
+            LedgerAccount::Chargebacks,
+            LedgerAccount::ChargebackLosses,
+            LedgerAccount::Transfers,
+            LedgerAccount::FeeRevenue,
+        ] {
+            assert_eq!(account.to_string().parse(), Ok(account));
+        }
+        assert!("client:1:total".parse::<LedgerAccount>().is_err());
+        assert!("client:x:held".parse::<LedgerAccount>().is_err());
+        assert!("system:fees".parse::<LedgerAccount>().is_err());
+    }
+
+    // Each kind of transaction posts to the expected accounts.
+    #[test]
+    fn postings_test() {
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        for (tx_type, tx_id, amount_str) in [
+            (TxType::Deposit, 1, Some("10")),
+            (TxType::Withdrawal, 2, Some("3")),
+            (TxType::Dispute, 1, Some("4")),
+            (TxType::Chargeback, 1, None),
+        ] {
+            engine
+                .apply(InputRecord::new(tx_type, 1, tx_id, amount_str.map(amount)))
+                .unwrap();
+        }
+        engine
+            .apply(InputRecord::new(
+                TxType::Withdrawal,
+                2,
+                3,
+                Some(amount("1")),
+            ))
+            .unwrap_err();
+
+        let postings: Vec<_> = engine
+            .ledger()
+            .unwrap()
+            .entries()
+            .iter()
+            .map(|entry| {
+                let postings: Vec<_> = entry
+                    .postings
+                    .iter()
+                    .map(|posting| (posting.account, posting.amount.to_string()))
+                    .collect();
+                (entry.id, entry.tx_id, entry.tx_type, postings)
+            })
+            .collect();
+        use LedgerAccount::{Available, CashIn, CashOut, Chargebacks, Held};
+        assert_eq!(
+            postings,
+            vec![
+                (
+                    1,
+                    1,
+                    TxType::Deposit,
+                    vec![
+                        (Available(1), "-10.0000".to_owned()),
+                        (CashIn, "10.0000".to_owned())
+                    ]
+                ),
+                (
+                    2,
+                    2,
+                    TxType::Withdrawal,
+                    vec![
+                        (Available(1), "3.0000".to_owned()),
+                        (CashOut, "-3.0000".to_owned())
+                    ]
+                ),
+                (
+                    3,
+                    1,
+                    TxType::Dispute,
+                    vec![
+                        (Available(1), "4.0000".to_owned()),
+                        (Held(1), "-4.0000".to_owned())
+                    ]
+                ),
+                (
+                    4,
+                    1,
+                    TxType::Chargeback,
+                    vec![
+                        (Held(1), "4.0000".to_owned()),
+                        (Chargebacks, "-4.0000".to_owned())
+                    ]
+                ),
+            ]
+        );
+    }
+
+    // After every sample file the debits match the credits and the client balances in the ledger
+    // match the client accounts.
+    // A fee is an entry of its own after the transaction that charged it.
+    #[test]
+    fn fee_postings_test() {
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        engine.set_fee_schedule(
+            FeeSchedule::from_csv_reader("type,flat,percent\nwithdrawal,0.25,\n".as_bytes())
+                .unwrap(),
+        );
+        engine
+            .apply(InputRecord::new(TxType::Deposit, 1, 1, Some(amount("10"))))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(
+                TxType::Withdrawal,
+                1,
+                2,
+                Some(amount("3")),
+            ))
+            .unwrap();
+
+        let entries = &engine.ledger().unwrap().entries()[1..];
+        assert_eq!(
+            entries,
+            [
+                LedgerEntry {
+                    id: 2,
+                    tx_id: 2,
+                    tx_type: TxType::Withdrawal,
+                    postings: vec![
+                        Posting {
+                            account: LedgerAccount::Available(1),
+                            amount: amount("3"),
+                        },
+                        Posting {
+                            account: LedgerAccount::CashOut,
+                            amount: amount("-3"),
+                        },
+                    ],
+                },
+                LedgerEntry {
+                    id: 3,
+                    tx_id: 2,
+                    tx_type: TxType::Withdrawal,
+                    postings: vec![
+                        Posting {
+                            account: LedgerAccount::Available(1),
+                            amount: amount("0.25"),
+                        },
+                        Posting {
+                            account: LedgerAccount::FeeRevenue,
+                            amount: amount("-0.25"),
+                        },
+                    ],
+                },
+            ]
+        );
+        let trial_balance = engine.trial_balance().unwrap();
+        assert_eq!(
+            trial_balance.balance(LedgerAccount::Available(1)),
+            amount("-6.75")
+        );
+    }
+
+    #[test]
+    fn trial_balance_test() {
+        for csv_file in [
+            "sample_data/deposit_withdraw.csv",
+            "sample_data/disputes.csv",
+            "sample_data/partial_disputes.csv",
+            "sample_data/negative_account.csv",
+            "sample_data/bad_fields.csv",
+            "sample_data/transfers.csv",
+        ] {
+            let mut engine = Engine::new();
+            engine.enable_ledger();
+            engine.process_csv_file(path::Path::new(csv_file)).unwrap();
+            let trial_balance = engine.trial_balance().unwrap();
+            assert_eq!(trial_balance.total_debits, trial_balance.total_credits);
+            assert!(trial_balance.total_debits > Amount::ZERO, "{csv_file}");
+        }
+
+        let mut engine = Engine::new();
+        engine.enable_ledger();
+        engine
+            .process_csv_file(path::Path::new("sample_data/disputes.csv"))
+            .unwrap();
+        let mut bytes = Vec::new();
+        write_trial_balance(&engine.trial_balance().unwrap(), &mut bytes).unwrap();
+        let trial_balance = String::from_utf8(bytes).unwrap();
+        assert!(trial_balance.starts_with("account,debit,credit,balance\n"));
+        assert!(trial_balance.ends_with(",0.0000\n"));
+
+        assert_eq!(Engine::new().trial_balance(), Err(LedgerError::Disabled));
+    }
+}
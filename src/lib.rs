@@ -0,0 +1,48 @@
+#![allow(clippy::cast_possible_truncation)]
+
+pub mod account;
+pub mod admin;
+pub mod amount;
+pub mod checkpoint;
+pub mod dense_store;
+pub mod disk_store;
+pub mod engine;
+pub mod error;
+pub mod fees;
+pub mod http;
+pub mod journal;
+pub mod ledger;
+pub mod output;
+pub mod overdraft;
+pub mod parallel;
+pub mod rejects;
+pub mod server;
+pub mod statement;
+pub mod store;
+pub mod transaction;
+
+pub use account::{AccountStatus, OutputRecord};
+pub use admin::{AdminRecord, AdminType};
+pub use amount::{Amount, DecimalStyle};
+pub use checkpoint::{read_checkpoint, write_checkpoint, Checkpoint};
+pub use dense_store::DenseTxStore;
+pub use disk_store::DiskTxStore;
+pub use engine::Engine;
+pub use error::TxError;
+pub use fees::{write_fee_summary, Fee, FeeRate, FeeSchedule, FeeSummary, FeeTotals};
+pub use http::serve_http;
+pub use journal::FsyncPolicy;
+pub use ledger::{
+    write_ledger, write_trial_balance, Ledger, LedgerAccount, LedgerEntry, LedgerError, Posting,
+    TrialBalance,
+};
+pub use output::{write_admin_log, write_output, write_output_as, OutputFormat};
+pub use overdraft::{
+    write_overdraft_report, OverLimit, OverdraftOutcome, OverdraftPolicies, OverdraftPolicy,
+};
+pub use parallel::ParallelEngine;
+pub use rejects::RejectsWriter;
+pub use server::serve;
+pub use statement::{write_statement, Counterparty, Statement, StatementFormat, StatementLine};
+pub use store::{MemoryTxStore, TxStore};
+pub use transaction::{InputFormat, InputRecord, TxState, TxType};
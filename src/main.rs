@@ -1,530 +1,670 @@
-#![allow(clippy::let_underscore_drop)]
-#![allow(clippy::cast_possible_truncation)]
-
-use itertools::Itertools;
-use std::collections::HashMap;
 use std::env;
 use std::error::Error;
+use std::fs;
 use std::io;
+use std::net;
+use std::num::NonZeroUsize;
 use std::path;
-
-#[derive(serde::Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-enum TxType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
-}
-#[derive(PartialEq)]
-enum DepositState {
-    NotApplicable,
-    Deposited,
-    InDispute,
+use std::process;
+use std::sync::{Arc, Mutex};
+use toy_payment_engine::{
+    read_checkpoint, serve, serve_http, write_admin_log, write_checkpoint, write_fee_summary,
+    write_ledger, write_output_as, write_overdraft_report, write_statement, write_trial_balance,
+    Checkpoint, DecimalStyle, DenseTxStore, DiskTxStore, Engine, FeeSchedule, FsyncPolicy,
+    InputFormat, MemoryTxStore, OutputFormat, OverdraftPolicies, ParallelEngine, RejectsWriter,
+    StatementFormat, TxStore,
+};
+
+const USAGE: &str = "Run like `cargo run -- [--rejects rejects.csv] [--input csv|jsonl] [--output csv|json|jsonl] [--decimals fixed|minimal] [--admin-log admin_log.csv] \
+     [--store memory|dense|disk:DIR] [--threads N] [--checkpoint checkpoint.csv [--checkpoint-every ROWS] [--resume]] \
+     [--journal journal.bin [--fsync always|batch:N|never]] [--ledger ledger.csv] [--trial-balance trial_balance.csv] \
+     [--fees fees.csv [--fee-summary fee_summary.csv]] [--overdraft overdraft.csv [--overdraft-report overdraft_report.csv]] \
+     [transaction.csv | --admin admin.csv ...] > accounts.csv`. \
+     Input files are processed in order, `-` or no transaction file reads from stdin. \
+     Transaction files ending in .jsonl or .ndjson are read as JSON Lines unless --input is given. \
+     `cargo run -- statement CLIENT [--format csv|text] [options] [transaction.csv ...] > statement.csv` \
+     writes the statement of one client instead of the accounts. \
+     `cargo run -- serve ADDR [options] [transaction.csv ...]` processes the inputs and then serves \
+     the accounts on ADDR, e.g. 127.0.0.1:7878, taking transactions over TCP, or with --http as a JSON API.";
+
+/// How many transactions the disk store keeps in memory.
+const DISK_CACHE_CAPACITY: usize = 1 << 16;
+
+/// How many rows are processed between checkpoints by default.
+const CHECKPOINT_EVERY: u64 = 1_000_000;
+
+/// The name used for stdin on the command line and in the rejects file.
+const STDIN: &str = "-";
+
+enum Input {
+    Transactions(String),
+    /// Operator input with admin actions, e.g. unlocking accounts.
+    Admin(String),
 }
 
-impl Default for DepositState {
-    fn default() -> Self {
-        Self::NotApplicable
-    }
-}
-#[derive(serde::Deserialize)]
-struct InputRecord {
-    #[serde(rename(deserialize = "type"))]
-    tx_type: TxType,
-    #[serde(skip_deserializing)]
-    deposit_state: DepositState,
-    #[serde(rename(deserialize = "client"))]
-    client_id: u16,
-    #[serde(rename(deserialize = "tx"))]
-    tx_id: u32,
-    amount: Option<f32>,
+/// Where the engine keeps processed transactions.
+enum Store {
+    Memory,
+    /// Compact in-memory storage for mostly sequential transaction IDs.
+    Dense,
+    /// A directory for `DiskTxStore`, for histories that don't fit in memory.
+    Disk(String),
 }
 
-#[derive(Copy, Clone)]
-struct OutputRecord {
-    available: i64,
-    held: i64,
-    total: i64,
-    locked: bool,
-}
-
-impl OutputRecord {
-    const fn new(amount: i64) -> Self {
-        Self {
-            available: amount,
-            held: 0,
-            total: amount,
-            locked: false,
+impl std::str::FromStr for Store {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "memory" => Ok(Self::Memory),
+            None if s == "dense" => Ok(Self::Dense),
+            Some(("disk", dir)) if !dir.is_empty() => Ok(Self::Disk(dir.to_owned())),
+            _ => Err(format!(
+                "unknown store {s:?}, expected memory, dense or disk:DIR"
+            )),
         }
     }
 }
 
-/// Returns true if the client account is locked, false otherwise.
-fn is_client_locked(client_id: u16, client_map: &HashMap<u16, OutputRecord>) -> bool {
-    if let Some(output_record) = client_map.get(&client_id) {
-        if output_record.locked {
-            return true;
-        }
+impl Input {
+    fn name(&self) -> &str {
+        let (Self::Transactions(input_file) | Self::Admin(input_file)) = self;
+        input_file
     }
-    false
 }
 
-/// Process the input record.
-/// # Arguments
-///
-/// * `record_res` - A result from the csv deserializer. If the result is an error, the record is ignored.
-/// * `tx_map` - A map of transaction IDs to their associated input record. Invalid transactions are not kept.
-/// * `client_map` - A map from a client ID to their associated output record. This map holds all the processed output records.
-fn process_input_record(
-    record_res: Result<InputRecord, csv::Error>,
-    tx_map: &mut HashMap<u32, InputRecord>,
-    client_map: &mut HashMap<u16, OutputRecord>,
-) {
-    let record = match record_res {
-        Ok(record_res) => record_res,
-        // If there is an error parsing the input (e.g client_id is missing), we assume it's erroneous and ignore it.
-        Err(_) => return,
-    };
-
-    // handle the transaction. Just ignore transactions which fail and continue
-    let _ = match &record.tx_type {
-        TxType::Deposit => handle_deposit(record, tx_map, client_map),
-        TxType::Withdrawal => handle_withdraw(record, tx_map, client_map),
-        TxType::Dispute => handle_dispute(&record, tx_map, client_map),
-        TxType::Resolve => handle_resolve(&record, tx_map, client_map),
-        TxType::Chargeback => handle_chargeback(&record, tx_map, client_map),
-    };
+struct Args {
+    inputs: Vec<Input>,
+    /// The format of every transaction input, instead of guessing it from the file name.
+    input_format: Option<InputFormat>,
+    rejects_file: Option<String>,
+    admin_log_file: Option<String>,
+    output_format: OutputFormat,
+    decimal_style: DecimalStyle,
+    store: Store,
+    checkpoint_file: Option<String>,
+    checkpoint_every: u64,
+    resume: bool,
+    journal_file: Option<String>,
+    fsync: FsyncPolicy,
+    ledger_file: Option<String>,
+    trial_balance_file: Option<String>,
+    /// The fee schedule to charge, no fees without one.
+    fees_file: Option<String>,
+    fee_summary_file: Option<String>,
+    /// The overdraft policies, permissive for everyone without them.
+    overdraft_file: Option<String>,
+    overdraft_report_file: Option<String>,
+    /// Write the statement of this client instead of the accounts.
+    statement_client: Option<u16>,
+    statement_format: StatementFormat,
+    /// Process on this many worker threads.
+    threads: Option<NonZeroUsize>,
+    /// Serve the engine on this address once the inputs are processed.
+    serve_addr: Option<String>,
+    /// Serve an HTTP API rather than csv lines.
+    http: bool,
 }
 
-/// Handles deposit transactions
-fn handle_deposit(
-    mut record: InputRecord,
-    tx_map: &mut HashMap<u32, InputRecord>,
-    client_map: &mut HashMap<u16, OutputRecord>,
-) -> Result<(), Box<dyn Error>> {
-    // If transaction was already processed or client account is frozen, we fail the transaction.
-    if tx_map.contains_key(&record.tx_id) || is_client_locked(record.client_id, client_map) {
-        Err("invalid")?;
+fn parse_args() -> Result<Args, Box<dyn Error>> {
+    let mut inputs = Vec::new();
+    let mut input_format = None;
+    let mut rejects_file = None;
+    let mut admin_log_file = None;
+    let mut output_format = OutputFormat::default();
+    let mut decimal_style = DecimalStyle::default();
+    let mut store = Store::Memory;
+    let mut checkpoint_file = None;
+    let mut checkpoint_every = CHECKPOINT_EVERY;
+    let mut resume = false;
+    let mut journal_file = None;
+    let mut fsync = FsyncPolicy::default();
+    let mut ledger_file = None;
+    let mut trial_balance_file = None;
+    let mut fees_file = None;
+    let mut fee_summary_file = None;
+    let mut overdraft_file = None;
+    let mut overdraft_report_file = None;
+    let mut statement_client = None;
+    let mut statement_format = StatementFormat::default();
+    let mut threads = None;
+    let mut serve_addr = None;
+    let mut http = false;
+    let mut args = env::args().skip(1).peekable();
+    if args.next_if(|arg| arg == "statement").is_some() {
+        let client_id = args.next().ok_or("statement needs a client id")?;
+        statement_client = Some(
+            client_id
+                .parse()
+                .map_err(|_| format!("invalid client id {client_id:?}"))?,
+        );
+    } else if args.next_if(|arg| arg == "serve").is_some() {
+        serve_addr = Some(args.next().ok_or("serve needs an address")?);
     }
-
-    let client_id = record.client_id;
-
-    // if the amount is missing in the input for a deposit, assume it's erroneous and fail the transaction.
-    let amount = match record.amount {
-        Some(amount) => {
-            if amount < 0f32 {
-                Err("negative")?;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--rejects" => rejects_file = Some(args.next().ok_or("--rejects needs a file")?),
+            "--input" => input_format = Some(args.next().ok_or("--input needs a format")?.parse()?),
+            "--output" => output_format = args.next().ok_or("--output needs a format")?.parse()?,
+            "--decimals" => {
+                decimal_style = args.next().ok_or("--decimals needs a style")?.parse()?;
+            }
+            "--admin" => inputs.push(Input::Admin(args.next().ok_or("--admin needs a file")?)),
+            "--store" => store = args.next().ok_or("--store needs a store")?.parse()?,
+            "--admin-log" => admin_log_file = Some(args.next().ok_or("--admin-log needs a file")?),
+            "--checkpoint" => {
+                checkpoint_file = Some(args.next().ok_or("--checkpoint needs a file")?);
+            }
+            "--checkpoint-every" => {
+                checkpoint_every = args
+                    .next()
+                    .ok_or("--checkpoint-every needs a number of rows")?
+                    .parse()?;
+            }
+            "--resume" => resume = true,
+            "--journal" => journal_file = Some(args.next().ok_or("--journal needs a file")?),
+            "--ledger" => ledger_file = Some(args.next().ok_or("--ledger needs a file")?),
+            "--trial-balance" => {
+                trial_balance_file = Some(args.next().ok_or("--trial-balance needs a file")?);
             }
-            (amount * 1e4).round() as i64
+            "--fees" => fees_file = Some(args.next().ok_or("--fees needs a file")?),
+            "--fee-summary" => {
+                fee_summary_file = Some(args.next().ok_or("--fee-summary needs a file")?);
+            }
+            "--overdraft" => {
+                overdraft_file = Some(args.next().ok_or("--overdraft needs a file")?);
+            }
+            "--overdraft-report" => {
+                overdraft_report_file = Some(args.next().ok_or("--overdraft-report needs a file")?);
+            }
+            "--threads" => {
+                threads = Some(args.next().ok_or("--threads needs a number")?.parse()?);
+            }
+            "--fsync" => fsync = args.next().ok_or("--fsync needs a policy")?.parse()?,
+            "--format" if statement_client.is_some() => {
+                statement_format = args.next().ok_or("--format needs a format")?.parse()?;
+            }
+            "--http" if serve_addr.is_some() => http = true,
+            "--format" => return Err("--format can only be used with statement".into()),
+            "--http" => return Err("--http can only be used with serve".into()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {arg}").into()),
+            _ => inputs.push(Input::Transactions(arg)),
         }
-        None => Err("missing amount")?,
-    };
-
-    record.deposit_state = DepositState::Deposited;
-    // Save the record in case it's later disputed and so we don't process it more than once.
-    tx_map.insert(record.tx_id, record);
-
-    // Update the output records
-    match client_map.get_mut(&client_id) {
-        Some(output_record) => {
-            output_record.available += amount;
-            output_record.total += amount;
+    }
+    // A server takes its transactions from its connections, stdin is only read if asked for.
+    if serve_addr.is_none()
+        && !inputs
+            .iter()
+            .any(|input| matches!(input, Input::Transactions(_)))
+    {
+        inputs.insert(0, Input::Transactions(STDIN.to_owned()));
+    }
+    if resume && checkpoint_file.is_none() {
+        return Err("--resume needs --checkpoint".into());
+    }
+    // Both restore the engine state, so the same changes would be applied twice.
+    if resume && journal_file.is_some() {
+        return Err("--resume can't be used with --journal".into());
+    }
+    if fee_summary_file.is_some() && fees_file.is_none() {
+        return Err("--fee-summary needs --fees".into());
+    }
+    if overdraft_report_file.is_some() && overdraft_file.is_none() {
+        return Err("--overdraft-report needs --overdraft".into());
+    }
+    // The workers keep everything in memory and only the accounts are put back together.
+    if threads.is_some() {
+        let unsupported = [
+            ("--journal", journal_file.is_some()),
+            ("--checkpoint", checkpoint_file.is_some()),
+            ("--ledger", ledger_file.is_some()),
+            ("--trial-balance", trial_balance_file.is_some()),
+            ("--store", !matches!(store, Store::Memory)),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, used)| *used) {
+            return Err(format!("--threads can't be used with {flag}").into());
         }
-        None => {
-            let output_record = OutputRecord::new(amount);
-            client_map.insert(client_id, output_record);
+    }
+    // A statement and the overdraft report only cover what is processed in this run.
+    if resume && statement_client.is_some() {
+        return Err("--resume can't be used with statement".into());
+    }
+    if resume && overdraft_report_file.is_some() {
+        return Err("--resume can't be used with --overdraft-report".into());
+    }
+    // A server never finishes, so there is no end to write the results or checkpoint at.
+    if serve_addr.is_some() {
+        let unsupported = [
+            ("--threads", threads.is_some()),
+            ("--checkpoint", checkpoint_file.is_some()),
+            ("--admin-log", admin_log_file.is_some()),
+            ("--ledger", ledger_file.is_some()),
+            ("--trial-balance", trial_balance_file.is_some()),
+            ("--fee-summary", fee_summary_file.is_some()),
+            ("--overdraft-report", overdraft_report_file.is_some()),
+        ];
+        if let Some((flag, _)) = unsupported.iter().find(|(_, used)| *used) {
+            return Err(format!("serve can't be used with {flag}").into());
         }
     }
-    Ok(())
+    Ok(Args {
+        inputs,
+        input_format,
+        rejects_file,
+        admin_log_file,
+        output_format,
+        decimal_style,
+        store,
+        checkpoint_file,
+        checkpoint_every,
+        resume,
+        journal_file,
+        fsync,
+        ledger_file,
+        trial_balance_file,
+        fees_file,
+        fee_summary_file,
+        overdraft_file,
+        overdraft_report_file,
+        statement_client,
+        statement_format,
+        threads,
+        serve_addr,
+        http,
+    })
 }
 
-/// Handles withdraw transactions
-fn handle_withdraw(
-    record: InputRecord,
-    tx_map: &mut HashMap<u32, InputRecord>,
-    client_map: &mut HashMap<u16, OutputRecord>,
+/// Processes one input file, or stdin for `-`, against the engine.
+/// * `input_format` - The format of a transaction input, guessed from its name if there is none.
+/// * `start` - Where to continue in the input when resuming.
+/// * `checkpoint` - Called every `checkpoint_every` rows of a transaction file.
+fn process_input<S: TxStore, W: io::Write>(
+    engine: &mut Engine<S>,
+    input: &Input,
+    input_format: Option<InputFormat>,
+    rejects: Option<&mut RejectsWriter<W>>,
+    start: Option<&csv::Position>,
+    checkpoint_every: u64,
+    checkpoint: impl FnMut(&Engine<S>, &csv::Position) -> io::Result<()>,
 ) -> Result<(), Box<dyn Error>> {
-    // If transaction was already processed or client account is frozen, we fail the transaction.
-    // If the client account is frozen, we do not need to store this transaction
-    if tx_map.contains_key(&record.tx_id) || is_client_locked(record.client_id, client_map) {
-        Err("invalid")?;
-    }
-
-    let client_id = record.client_id;
-
-    // if the amount is missing in the input for a withdrawal, assume it's erroneous and fail the transaction.
-    let amount = match record.amount {
-        Some(amount) => {
-            if amount < 0f32 {
-                Err("negative")?;
-            }
-            (amount * 1e4).round() as i64
+    let input_file = match input {
+        Input::Transactions(input_file) => input_file.as_str(),
+        Input::Admin(input_file) => {
+            engine.process_admin_csv_file(path::Path::new(input_file), rejects)?;
+            return Ok(());
         }
-        None => Err("missing amount")?,
     };
-
-    // Save the record so that we don't process this transaction twice in case we receive same transaction ID more than once.
-    tx_map.insert(record.tx_id, record);
-
-    // Update the output records
-    match client_map.get_mut(&client_id) {
-        Some(output_record) => {
-            // if there is not enough funds in the account, fail the transaction.
-            if amount > output_record.available {
-                Err("rejected")?;
-            }
-            output_record.available -= amount;
-            output_record.total -= amount;
+    let input_format =
+        input_format.unwrap_or_else(|| InputFormat::from_path(path::Path::new(input_file)));
+    match (input_format, input_file, rejects) {
+        (InputFormat::Csv, STDIN, Some(rejects)) => {
+            engine.process_csv_reader_with_rejects(io::stdin().lock(), STDIN, rejects)?;
+        }
+        (InputFormat::Csv, STDIN, None) => engine.process_csv_reader(io::stdin().lock())?,
+        (InputFormat::Csv, _, rejects) => engine.process_csv_file_resumable(
+            path::Path::new(input_file),
+            rejects,
+            start,
+            checkpoint_every,
+            checkpoint,
+        )?,
+        (InputFormat::JsonLines, STDIN, Some(rejects)) => {
+            engine.process_json_lines_reader_with_rejects(io::stdin().lock(), STDIN, rejects)?;
         }
-        // If there is no record of this client, their asset account may still be valid even if the
-        // transaction should fail. So include this client account in the output with 0 funds.
-        None => {
-            let output_record = OutputRecord::new(0);
-            client_map.insert(client_id, output_record);
-            Err("rejected")?;
+        (InputFormat::JsonLines, STDIN, None) => {
+            engine.process_json_lines_reader(io::stdin().lock())?;
         }
+        (InputFormat::JsonLines, _, rejects) => engine.process_json_lines_file_resumable(
+            path::Path::new(input_file),
+            rejects,
+            start,
+            checkpoint_every,
+            checkpoint,
+        )?,
     }
     Ok(())
 }
 
-/// Handles dispute transactions
-fn handle_dispute(
-    record: &InputRecord,
-    tx_map: &mut HashMap<u32, InputRecord>,
-    client_map: &mut HashMap<u16, OutputRecord>,
-) -> Result<(), Box<dyn Error>> {
-    let disputed_tx_record = match tx_map.get_mut(&record.tx_id) {
-        Some(input_record) => input_record,
-        // I assume that this is an erroneous transaction since it's disputing a non-existing transaction.
-        None => Err("invalid")?,
-    };
-
-    // The client should not be able to dispute transactions that do not belong to their account
-    // and the only valid transactions to process are deposits that are not in dispute.
-    // We also reject handling disputes for accounts which are locked/frozen.
-    if disputed_tx_record.client_id != record.client_id
-        || disputed_tx_record.deposit_state != DepositState::Deposited
-        || is_client_locked(record.client_id, client_map)
-    {
-        Err("rejected")?;
-    }
-
-    // If the amount is missing on the input record or the client account
-    // is missing from our output records, this is an unrecoverable error.
-    let amount_to_hold = (disputed_tx_record.amount.unwrap() * 1e4) as i64;
-    let client_output_record = client_map.get_mut(&disputed_tx_record.client_id).unwrap();
-
-    disputed_tx_record.deposit_state = DepositState::InDispute;
-
-    client_output_record.available -= amount_to_hold;
-    client_output_record.held += amount_to_hold;
-
-    Ok(())
+/// Writes a checkpoint to `checkpoint_file`, replacing the previous one only once it is complete.
+fn save_checkpoint<S: TxStore>(
+    engine: &Engine<S>,
+    checkpoint: &Checkpoint,
+    checkpoint_file: &str,
+) -> io::Result<()> {
+    let tmp_file = format!("{checkpoint_file}.tmp");
+    let mut writer = io::BufWriter::new(fs::File::create(&tmp_file)?);
+    write_checkpoint(engine, checkpoint, &mut writer)
+        .map_err(|error| io::Error::other(error.to_string()))?;
+    writer.into_inner()?.sync_all()?;
+    fs::rename(tmp_file, checkpoint_file)
 }
 
-/// Handles resolve transactions
-fn handle_resolve(
-    record: &InputRecord,
-    tx_map: &mut HashMap<u32, InputRecord>,
-    client_map: &mut HashMap<u16, OutputRecord>,
-) -> Result<(), Box<dyn Error>> {
-    let disputed_tx_record = match tx_map.get_mut(&record.tx_id) {
-        Some(input_record) => input_record,
-        // I assume that this is an erroneous transaction since it's disputing a non-existing transaction.
-        None => Err("invalid")?,
+/// Loads the checkpoint from an earlier run into `engine`, if there is one, and checks that it
+/// was taken with the same inputs.
+fn load_checkpoint<S: TxStore>(
+    engine: &mut Engine<S>,
+    args: &Args,
+    checkpoint_file: &str,
+) -> Result<Option<Checkpoint>, Box<dyn Error>> {
+    let file = match fs::File::open(checkpoint_file) {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(format!("Failed to open {checkpoint_file}: {error}").into()),
     };
-
-    // The client should not be able to resolve transactions that do not belong to their account
-    // and the only valid transactions to process are deposits that are in dispute.
-    // We also reject handling disputes for accounts which are locked/frozen.
-    if disputed_tx_record.client_id != record.client_id
-        || disputed_tx_record.deposit_state != DepositState::InDispute
-        || is_client_locked(record.client_id, client_map)
-    {
-        Err("rejected")?;
+    let checkpoint = read_checkpoint(engine, io::BufReader::new(file))
+        .map_err(|error| format!("Failed to read {checkpoint_file}: {error}"))?;
+    let source = args.inputs.get(checkpoint.input).map_or("", Input::name);
+    if checkpoint.input > args.inputs.len() || source != checkpoint.source {
+        return Err(format!("{checkpoint_file} was taken with different inputs").into());
     }
-
-    // If the amount is missing this is a programming error, unrecoverable error.
-    let amount_to_resolve = (disputed_tx_record.amount.unwrap() * 1e4) as i64;
-    // If the client account is missing this is a programming error, unrecoverable error.
-    let client_output_record = client_map.get_mut(&disputed_tx_record.client_id).unwrap();
-
-    disputed_tx_record.deposit_state = DepositState::Deposited;
-    client_output_record.available += amount_to_resolve;
-    client_output_record.held -= amount_to_resolve;
-    Ok(())
+    if source == STDIN && checkpoint.position.is_some() {
+        return Err("Can't resume part way through stdin".into());
+    }
+    Ok(Some(checkpoint))
 }
 
-/// Handles chargeback transactions
-fn handle_chargeback(
-    record: &InputRecord,
-    tx_map: &mut HashMap<u32, InputRecord>,
-    client_map: &mut HashMap<u16, OutputRecord>,
-) -> Result<(), Box<dyn Error>> {
-    let disputed_tx_record = match tx_map.get_mut(&record.tx_id) {
-        Some(input_record) => input_record,
-        // I assume that this is an erroneous transaction since it's disputing a non-existing transaction.
-        None => Err("invalid")?,
-    };
-
-    // The client should not be able to issue chargebacks on transactions which do not belong to their account
-    // and the only valid transactions to process are deposits that are in dispute.
-    // We also reject handling disputes for accounts which are locked/frozen.
-    if disputed_tx_record.client_id != record.client_id
-        || disputed_tx_record.deposit_state != DepositState::InDispute
-        || is_client_locked(record.client_id, client_map)
-    {
-        Err("rejected")?;
+/// Opens the rejects file. When resuming, whatever was written after the checkpoint is dropped
+/// since those rows are processed again.
+fn open_rejects(
+    rejects_file: &str,
+    resume_from: Option<&Checkpoint>,
+) -> io::Result<RejectsWriter<io::BufWriter<fs::File>>> {
+    match resume_from {
+        Some(checkpoint) => {
+            let mut file = fs::OpenOptions::new().write(true).open(rejects_file)?;
+            file.set_len(checkpoint.rejects_len)?;
+            io::Seek::seek(&mut file, io::SeekFrom::End(0))?;
+            Ok(RejectsWriter::append(io::BufWriter::new(file)))
+        }
+        None => Ok(RejectsWriter::new(io::BufWriter::new(fs::File::create(
+            rejects_file,
+        )?))?),
     }
-
-    // If the amount is missing on the input record or the client account
-    // is missing from our output records, this is an unrecoverable error.
-    let amount_to_withdraw = (disputed_tx_record.amount.unwrap() * 1e4) as i64;
-    let client_output_record = client_map.get_mut(&disputed_tx_record.client_id).unwrap();
-
-    // Just update the client account and mark as frozen, the transactions' state no longer matters.
-    client_output_record.held -= amount_to_withdraw;
-    client_output_record.total -= amount_to_withdraw;
-    client_output_record.locked = true;
-    Ok(())
 }
 
-// Writes the client_map's output records to writer.
-fn write_output(
-    client_map: &HashMap<u16, OutputRecord>,
-    writer: impl io::Write,
-) -> Result<(), Box<dyn Error>> {
-    #![allow(clippy::cast_precision_loss)]
-    let mut wtr = csv::Writer::from_writer(writer);
-    wtr.write_record(&["client", "available", "held", "total", "locked"])?;
-    // There's no requirement to sort by client id but I find that it's easier to read this way.
-    for client_id in client_map.keys().sorted() {
-        let output_record = client_map.get(client_id).unwrap();
-        wtr.write_record(&[
-            format!("{}", client_id),
-            format!("{:.4}", (output_record.available as f32) / 1e4),
-            format!("{:.4}", (output_record.held as f32) / 1e4),
-            format!("{:.4}", (output_record.total as f32) / 1e4),
-            format!("{}", output_record.locked),
-        ])?;
-    }
-    Ok(())
+/// Loads the fee schedule, if there is one.
+fn load_fee_schedule(args: &Args) -> Result<Option<FeeSchedule>, Box<dyn Error>> {
+    args.fees_file
+        .as_ref()
+        .map(|fees_file| {
+            FeeSchedule::from_csv_file(path::Path::new(fees_file))
+                .map_err(|error| format!("Failed to read {fees_file}: {error}").into())
+        })
+        .transpose()
 }
 
-/// Process the csv file pointed to by `csv_file_path` and populate `client_map` with the output records
-/// * `csv_file_path` - A path to the csv file.
-/// * `client_map` - A map from a client ID to their associated output record. This map holds all the processed output records.
-fn process_csv_file(csv_file_path: &path::Path, client_map: &mut HashMap<u16, OutputRecord>) {
-    // This maps tx_ids to previously processed transactions. Invalid transactions are not kept.
-    let mut tx_map: HashMap<u32, InputRecord> = HashMap::new();
+/// Loads the overdraft policies, if there are any.
+fn load_overdraft_policies(args: &Args) -> Result<Option<OverdraftPolicies>, Box<dyn Error>> {
+    args.overdraft_file
+        .as_ref()
+        .map(|overdraft_file| {
+            OverdraftPolicies::from_csv_file(path::Path::new(overdraft_file))
+                .map_err(|error| format!("Failed to read {overdraft_file}: {error}").into())
+        })
+        .transpose()
+}
 
-    let mut csv_reader = match csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .from_path(csv_file_path)
-    {
-        Ok(rdr) => rdr,
-        Err(error) => panic!(
-            "Failed to read {}: {error}",
-            csv_file_path.to_str().unwrap()
+/// Processes every input on worker threads, see `ParallelEngine`.
+fn process_parallel(args: &Args, threads: NonZeroUsize) -> Result<Engine, Box<dyn Error>> {
+    let mut engine = ParallelEngine::new(threads);
+    if let Some(fee_schedule) = load_fee_schedule(args)? {
+        engine.set_fee_schedule(fee_schedule);
+    }
+    if let Some(overdraft_policies) = load_overdraft_policies(args)? {
+        engine.set_overdraft_policies(&overdraft_policies);
+    }
+    if let Some(client_id) = args.statement_client {
+        engine.record_statement(client_id);
+    }
+    let mut rejects = match &args.rejects_file {
+        Some(rejects_file) => Some(
+            open_rejects(rejects_file, None)
+                .map_err(|error| format!("Failed to open {rejects_file}: {error}"))?,
         ),
+        None => None,
     };
 
-    for record in csv_reader.deserialize() {
-        process_input_record(record, &mut tx_map, client_map);
+    for input in &args.inputs {
+        let read = |engine: &mut ParallelEngine,
+                    rejects: Option<&mut RejectsWriter<_>>|
+         -> Result<(), Box<dyn Error>> {
+            let input_file = input.name();
+            let reader: Box<dyn io::BufRead> = match input_file {
+                STDIN => Box::new(io::stdin().lock()),
+                _ => Box::new(io::BufReader::new(fs::File::open(input_file)?)),
+            };
+            let input_format = args
+                .input_format
+                .unwrap_or_else(|| InputFormat::from_path(path::Path::new(input_file)));
+            match (input, input_format) {
+                (Input::Admin(_), _) => {
+                    engine.process_admin_csv_reader(reader, input_file, rejects)?;
+                }
+                (Input::Transactions(_), InputFormat::Csv) => {
+                    engine.process_csv_reader(reader, input_file, rejects)?;
+                }
+                (Input::Transactions(_), InputFormat::JsonLines) => {
+                    engine.process_json_lines_reader(reader, input_file, rejects)?;
+                }
+            }
+            Ok(())
+        };
+        read(&mut engine, rejects.as_mut())
+            .map_err(|error| format!("Failed to read {}: {error}", input.name()))?;
+        if let Some(rejects) = rejects.as_mut() {
+            rejects.flush()?;
+        }
     }
+    Ok(engine.finish())
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    assert_eq!(args.len(),2, "Requires one and only one argument. Run like `cargo run -- transaction.csv > accounts.csv`");
-    let csv_file = &args[1][..];
-
-    // This maps the client_id to an output record which will be output at the end of the program in csv format to stdout.
-    let mut client_map: HashMap<u16, OutputRecord> = HashMap::new();
-
-    process_csv_file(path::Path::new(csv_file), &mut client_map);
+/// Processes every input in order on this thread.
+fn process(args: &Args) -> Result<Engine<Box<dyn TxStore + Send>>, Box<dyn Error>> {
+    let tx_store: Box<dyn TxStore + Send> = match &args.store {
+        Store::Memory => Box::new(MemoryTxStore::default()),
+        Store::Dense => Box::new(DenseTxStore::new()),
+        Store::Disk(dir) => Box::new(
+            DiskTxStore::create(path::Path::new(dir), DISK_CACHE_CAPACITY)
+                .map_err(|error| format!("Failed to create a store in {dir}: {error}"))?,
+        ),
+    };
+    let mut engine = Engine::with_store(tx_store);
+    if let Some(fee_schedule) = load_fee_schedule(args)? {
+        engine.set_fee_schedule(fee_schedule);
+    }
+    if let Some(overdraft_policies) = load_overdraft_policies(args)? {
+        engine.set_overdraft_policies(overdraft_policies);
+    }
+    if args.ledger_file.is_some() || args.trial_balance_file.is_some() {
+        engine.enable_ledger();
+    }
+    if let Some(journal_file) = &args.journal_file {
+        engine
+            .open_journal(path::Path::new(journal_file), args.fsync)
+            .map_err(|error| format!("Failed to open {journal_file}: {error}"))?;
+    }
 
-    if let Err(err) = write_output(&client_map, io::stdout()) {
-        eprintln!("Error writing to stdout: {}", err);
+    if let Some(client_id) = args.statement_client {
+        engine.record_statement(client_id);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    // convenience method to validate that internal i64 representation matches expected float value.
-    fn assert_amount(amount: i64, num: f32) {
-        assert_eq!((num * 1e4) as i64, amount);
-    }
-
-    // Test the output for a basic withdraw/deposit cases with different amounts
-    // Client 2 will decline a withdrawal because they are short 0.0001
-    // Client 1 will receive a duplicate deposit (tx 1), it will be ignored
-    // Client 3 will deposit and withdraw to the smallest decimal precision
-    // Client 4 will deposit 1 billion dollars and then reject a withdrawal / deposit for negative amounts
-    #[test]
-    fn basic_test() {
-        let basic_csv_file = path::Path::new("sample_data/deposit_withdraw.csv");
-        let mut client_map: HashMap<u16, OutputRecord> = HashMap::new();
-        process_csv_file(basic_csv_file, &mut client_map);
-
-        let mut writer = io::BufWriter::new(Vec::new());
-
-        write_output(&client_map, &mut writer).unwrap();
-
-        let bytes = writer.into_inner().unwrap();
-
-        let mut rdr = csv::Reader::from_reader(io::BufReader::new(&*bytes));
-        for result in rdr.records() {
-            let record: csv::StringRecord = result.unwrap();
-            let client_id = record.get(0).unwrap();
-            let available = record.get(1).unwrap();
-            let held = record.get(2).unwrap();
-            let total = record.get(3).unwrap();
-            // client 1
-            if client_id == "1" {
-                assert_eq!(available, "0.0001");
-                assert_eq!(total, "0.0001");
-                assert_eq!(held, "0.0000");
-            }
-            // client 2
-            if client_id == "2" {
-                assert!(available == "2.0000");
-                assert!(total == "2.0000");
-                assert_eq!(held, "0.0000");
-            }
-            // client 3
-            if client_id == "3" {
-                assert_eq!(available, "0.0000");
-                assert_eq!(total, "0.0000");
-                assert_eq!(held, "0.0000");
-            }
-            // client 4
-            if client_id == "4" {
-                assert_eq!(available, "1000000000.0000");
-                assert_eq!(total, "1000000000.0000");
-                assert_eq!(held, "0.0000");
-            }
+    let resume_from = match &args.checkpoint_file {
+        Some(checkpoint_file) if args.resume => {
+            load_checkpoint(&mut engine, args, checkpoint_file)?
         }
-    }
+        _ => None,
+    };
 
-    // Tests dispute/resolve/chargeback logic.
-    #[test]
-    fn disputes_test() {
-        let disputes_csv_file = path::Path::new("sample_data/disputes.csv");
-        let mut client_map: HashMap<u16, OutputRecord> = HashMap::new();
-        let mut tx_map: HashMap<u32, InputRecord> = HashMap::new();
-
-        let mut csv_reader = match csv::ReaderBuilder::new()
-            .trim(csv::Trim::All)
-            .from_path(disputes_csv_file)
-        {
-            Ok(rdr) => rdr,
-            Err(error) => panic!(
-                "Failed to read {}: {error}",
-                disputes_csv_file.to_str().unwrap()
-            ),
-        };
+    let mut rejects = match &args.rejects_file {
+        Some(rejects_file) => Some(
+            open_rejects(rejects_file, resume_from.as_ref())
+                .map_err(|error| format!("Failed to open {rejects_file}: {error}"))?,
+        ),
+        None => None,
+    };
+    let rejects_len = || -> io::Result<u64> {
+        match &args.rejects_file {
+            Some(rejects_file) => Ok(fs::metadata(rejects_file)?.len()),
+            None => Ok(0),
+        }
+    };
 
-        let mut iter = csv_reader.deserialize();
-        // process the first two deposits
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
+    let first_input = resume_from
+        .as_ref()
+        .map_or(0, |checkpoint| checkpoint.input);
+    for (index, input) in args.inputs.iter().enumerate().skip(first_input) {
+        let start = resume_from
+            .as_ref()
+            .filter(|checkpoint| checkpoint.input == index)
+            .and_then(|checkpoint| checkpoint.position.as_ref());
+        // Only take checkpoints part way through an input when there is somewhere to save them.
+        let checkpoint_every = if args.checkpoint_file.is_some() {
+            args.checkpoint_every
+        } else {
+            0
+        };
+        let checkpoint = |engine: &Engine<_>, position: &csv::Position| {
+            let checkpoint = Checkpoint {
+                input: index,
+                source: input.name().to_owned(),
+                position: Some(position.clone()),
+                rejects_len: rejects_len()?,
+            };
+            save_checkpoint(engine, &checkpoint, args.checkpoint_file.as_ref().unwrap())
+        };
+        process_input(
+            &mut engine,
+            input,
+            args.input_format,
+            rejects.as_mut(),
+            start,
+            checkpoint_every,
+            checkpoint,
+        )
+        .map_err(|error| format!("Failed to read {}: {error}", input.name()))?;
+
+        if let Some(rejects) = rejects.as_mut() {
+            rejects.flush()?;
+        }
+        engine.sync_journal()?;
+        if let Some(checkpoint_file) = &args.checkpoint_file {
+            let checkpoint = Checkpoint {
+                input: index + 1,
+                source: args
+                    .inputs
+                    .get(index + 1)
+                    .map_or("", Input::name)
+                    .to_owned(),
+                position: None,
+                rejects_len: rejects_len()?,
+            };
+            save_checkpoint(&engine, &checkpoint, checkpoint_file)
+                .map_err(|error| format!("Failed to write {checkpoint_file}: {error}"))?;
+        }
+    }
 
-        // Process the first dispute
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client1_record = client_map.get(&1).unwrap();
+    Ok(engine)
+}
 
-            assert_amount(client1_record.held, 500_f32);
-            assert_amount(client1_record.available, 0_f32);
-            assert_amount(client1_record.total, 500_f32);
-            assert!(!client1_record.locked);
+/// Writes the admin log, ledger, trial balance, fee summary and overdraft report if they were asked
+/// for, and the accounts or statement to stdout.
+fn write_results<S: TxStore>(engine: &Engine<S>, args: &Args) -> Result<(), Box<dyn Error>> {
+    if let Some(admin_log_file) = &args.admin_log_file {
+        let file = fs::File::create(admin_log_file)
+            .map_err(|error| format!("Failed to create {admin_log_file}: {error}"))?;
+        write_admin_log(engine, io::BufWriter::new(file))
+            .map_err(|error| format!("Error writing to {admin_log_file}: {error}"))?;
+    }
 
-            let tx_1 = tx_map.get(&1).unwrap();
-            assert!(tx_1.deposit_state == DepositState::InDispute);
-        }
+    if let Some(ledger_file) = &args.ledger_file {
+        let file = fs::File::create(ledger_file)
+            .map_err(|error| format!("Failed to create {ledger_file}: {error}"))?;
+        write_ledger(engine, io::BufWriter::new(file))
+            .map_err(|error| format!("Error writing to {ledger_file}: {error}"))?;
+    }
 
-        // Process the second dispute. client 1 cannot dispute client 2 transaction -> ignored.
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client2_record = client_map.get(&2).unwrap();
-            assert_amount(client2_record.held, 0_f32);
-            assert_amount(client2_record.available, 5_f32);
-            assert_amount(client2_record.total, 5_f32);
-            assert!(!client2_record.locked);
-
-            let tx_2 = tx_map.get(&2).unwrap();
-            assert!(tx_2.deposit_state == DepositState::Deposited);
-        }
+    if let Some(trial_balance_file) = &args.trial_balance_file {
+        let trial_balance = engine
+            .trial_balance()
+            .map_err(|error| format!("Trial balance failed: {error}"))?;
+        let file = fs::File::create(trial_balance_file)
+            .map_err(|error| format!("Failed to create {trial_balance_file}: {error}"))?;
+        write_trial_balance(&trial_balance, io::BufWriter::new(file))
+            .map_err(|error| format!("Error writing to {trial_balance_file}: {error}"))?;
+    }
 
-        // Process the resolution of first dispute.
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client1_record = client_map.get(&1).unwrap();
-            assert_amount(client1_record.held, 0_f32);
-            assert_amount(client1_record.available, 500_f32);
-            assert_amount(client1_record.total, 500_f32);
-            assert!(!client1_record.locked);
-
-            let tx_1 = tx_map.get(&1).unwrap();
-            assert!(tx_1.deposit_state == DepositState::Deposited);
-        }
+    if let Some(fee_summary_file) = &args.fee_summary_file {
+        let file = fs::File::create(fee_summary_file)
+            .map_err(|error| format!("Failed to create {fee_summary_file}: {error}"))?;
+        write_fee_summary(
+            engine.fee_summary(),
+            io::BufWriter::new(file),
+            args.decimal_style,
+        )
+        .map_err(|error| format!("Error writing to {fee_summary_file}: {error}"))?;
+    }
 
-        // Process second dispute for tx 1
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client1_record = client_map.get(&1).unwrap();
-            assert_amount(client1_record.held, 500_f32);
-            assert_amount(client1_record.available, 0_f32);
-            assert_amount(client1_record.total, 500_f32);
-            assert!(!client1_record.locked);
-
-            let tx_1 = tx_map.get(&1).unwrap();
-            assert!(tx_1.deposit_state == DepositState::InDispute);
-        }
+    if let Some(overdraft_report_file) = &args.overdraft_report_file {
+        let file = fs::File::create(overdraft_report_file)
+            .map_err(|error| format!("Failed to create {overdraft_report_file}: {error}"))?;
+        write_overdraft_report(
+            engine.overdraft_report(),
+            io::BufWriter::new(file),
+            args.decimal_style,
+        )
+        .map_err(|error| format!("Error writing to {overdraft_report_file}: {error}"))?;
+    }
 
-        // Process another deposit while in dispute for client 1
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client1_record = client_map.get(&1).unwrap();
-            assert_amount(client1_record.held, 500_f32);
-            assert_amount(client1_record.available, 5_f32);
-            assert_amount(client1_record.total, 505_f32);
-            assert!(!client1_record.locked);
-        }
+    match engine.statement() {
+        Some(statement) => write_statement(
+            statement,
+            io::stdout(),
+            args.statement_format,
+            args.decimal_style,
+        ),
+        None => write_output_as(engine, io::stdout(), args.output_format, args.decimal_style),
+    }
+    .map_err(|error| format!("Error writing to stdout: {error}"))?;
+    Ok(())
+}
 
-        // Process tx 1 chargeback
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client1_record = client_map.get(&1).unwrap();
-            assert_amount(client1_record.held, 0_f32);
-            assert_amount(client1_record.available, 5_f32);
-            assert_amount(client1_record.total, 5_f32);
-            assert!(client1_record.locked);
-        }
+/// Serves the engine on `addr` until accepting a connection fails, see `serve`.
+fn run_server<S: TxStore + Send + 'static>(
+    engine: Engine<S>,
+    addr: &str,
+    args: &Args,
+) -> Result<(), Box<dyn Error>> {
+    let listener = net::TcpListener::bind(addr)
+        .map_err(|error| format!("Failed to listen on {addr}: {error}"))?;
+    eprintln!("Listening on {}", listener.local_addr()?);
+    let engine = Arc::new(Mutex::new(engine));
+    if args.http {
+        serve_http(listener, engine, args.decimal_style)
+    } else {
+        serve(&listener, engine, args.output_format, args.decimal_style)
+    }
+    .map_err(|error| format!("Failed to accept a connection: {error}"))?;
+    Ok(())
+}
 
-        // Process client 1 trying to deposit more funds. Rejected.
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client1_record = client_map.get(&1).unwrap();
-            assert_amount(client1_record.held, 0_f32);
-            assert_amount(client1_record.available, 5_f32);
-            assert_amount(client1_record.total, 5_f32);
-            assert!(client1_record.locked);
-        }
+fn run(args: &Args) -> Result<(), Box<dyn Error>> {
+    match (&args.serve_addr, args.threads) {
+        (Some(addr), _) => run_server(process(args)?, addr, args),
+        (None, Some(threads)) => write_results(&process_parallel(args, threads)?, args),
+        (None, None) => write_results(&process(args)?, args),
+    }
+}
 
-        // Process client 1 trying to withdraw funds. Rejected.
-        process_input_record(iter.next().unwrap(), &mut tx_map, &mut client_map);
-        {
-            let client1_record = client_map.get(&1).unwrap();
-            assert_amount(client1_record.held, 0_f32);
-            assert_amount(client1_record.available, 5_f32);
-            assert_amount(client1_record.total, 5_f32);
-            assert!(client1_record.locked);
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}\n{USAGE}");
+            process::exit(2);
         }
+    };
+    if let Err(error) = run(&args) {
+        eprintln!("{error}");
+        process::exit(1);
     }
 }
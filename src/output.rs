@@ -0,0 +1,406 @@
+use crate::account::OutputRecord;
+use crate::amount::DecimalStyle;
+use crate::engine::Engine;
+use crate::store::TxStore;
+use serde::Serializer;
+use std::error::Error;
+use std::io;
+use std::str::FromStr;
+
+/// The format the client accounts are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A single JSON array with an object for every account.
+    Json,
+    /// A JSON object for every account, one per line.
+    JsonLines,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "jsonl" => Ok(Self::JsonLines),
+            _ => Err(format!(
+                "unknown output format {s:?}, expected csv, json or jsonl"
+            )),
+        }
+    }
+}
+
+/// An account as it is written in JSON. Amounts are decimal strings so they stay exact.
+#[derive(serde::Serialize)]
+pub(crate) struct JsonAccount {
+    client: u16,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+impl JsonAccount {
+    pub(crate) fn new(client_id: u16, output_record: &OutputRecord, style: DecimalStyle) -> Self {
+        Self {
+            client: client_id,
+            available: output_record.available.to_string_with(style),
+            held: output_record.held.to_string_with(style),
+            total: output_record.total.to_string_with(style),
+            locked: output_record.locked(),
+        }
+    }
+}
+
+/// Writes the engine's client accounts to writer in csv format.
+/// * `style` - How trailing zeros are written in the amount columns.
+pub fn write_output<S: TxStore>(
+    engine: &Engine<S>,
+    writer: impl io::Write,
+    style: DecimalStyle,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record(["client", "available", "held", "total", "locked"])?;
+    // There's no requirement to sort by client id but I find that it's easier to read this way.
+    for (client_id, output_record) in engine.accounts() {
+        wtr.write_record(&[
+            format!("{}", client_id),
+            output_record.available.to_string_with(style),
+            output_record.held.to_string_with(style),
+            output_record.total.to_string_with(style),
+            format!("{}", output_record.locked()),
+        ])?;
+    }
+    Ok(())
+}
+
+/// Writes the engine's client accounts to writer in `format`.
+/// * `style` - How trailing zeros are written in the amounts.
+pub fn write_output_as<S: TxStore>(
+    engine: &Engine<S>,
+    mut writer: impl io::Write,
+    format: OutputFormat,
+    style: DecimalStyle,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        OutputFormat::Csv => return write_output(engine, writer, style),
+        OutputFormat::Json => {
+            serde_json::Serializer::new(&mut writer).collect_seq(json_accounts(engine, style))?;
+            writeln!(writer)?;
+        }
+        OutputFormat::JsonLines => {
+            for json_account in json_accounts(engine, style) {
+                serde_json::to_writer(&mut writer, &json_account)?;
+                writeln!(writer)?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+pub(crate) fn json_accounts<S: TxStore>(
+    engine: &Engine<S>,
+    style: DecimalStyle,
+) -> impl Iterator<Item = JsonAccount> + '_ {
+    engine
+        .accounts()
+        .map(move |(client_id, output_record)| JsonAccount::new(client_id, output_record, style))
+}
+
+/// Writes every admin action the engine applied to writer in csv format, in the order they were applied.
+pub fn write_admin_log<S: TxStore>(
+    engine: &Engine<S>,
+    writer: impl io::Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    for admin_record in engine.admin_log() {
+        wtr.serialize(admin_record)?;
+    }
+    // Serialize only writes the header with the first record.
+    if engine.admin_log().is_empty() {
+        wtr.write_record(["action", "client", "operator", "reason"])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::Amount;
+    use crate::dense_store::DenseTxStore;
+    use crate::disk_store::DiskTxStore;
+    use crate::transaction::{InputRecord, TxType};
+    use std::path;
+
+    // Test the output for a basic withdraw/deposit cases with different amounts
+    // Client 2 will decline a withdrawal because they are short 0.0001
+    // Client 1 will receive a duplicate deposit (tx 1), it will be ignored
+    // Client 3 will deposit to the smallest decimal precision and reject a withdrawal with too many decimals
+    // Client 4 will deposit 1 billion dollars and then reject a withdrawal / deposit for negative amounts
+    #[test]
+    fn basic_test() {
+        let basic_csv_file = path::Path::new("sample_data/deposit_withdraw.csv");
+        let mut engine = Engine::new();
+        engine.process_csv_file(basic_csv_file).unwrap();
+
+        let mut writer = io::BufWriter::new(Vec::new());
+
+        write_output(&engine, &mut writer, DecimalStyle::Fixed).unwrap();
+
+        let bytes = writer.into_inner().unwrap();
+
+        let mut rdr = csv::Reader::from_reader(io::BufReader::new(&*bytes));
+        for result in rdr.records() {
+            let record: csv::StringRecord = result.unwrap();
+            let client_id = record.get(0).unwrap();
+            let available = record.get(1).unwrap();
+            let held = record.get(2).unwrap();
+            let total = record.get(3).unwrap();
+            // client 1
+            if client_id == "1" {
+                assert_eq!(available, "0.0001");
+                assert_eq!(total, "0.0001");
+                assert_eq!(held, "0.0000");
+            }
+            // client 2
+            if client_id == "2" {
+                assert!(available == "2.0000");
+                assert!(total == "2.0000");
+                assert_eq!(held, "0.0000");
+            }
+            // client 3
+            if client_id == "3" {
+                assert_eq!(available, "1.0001");
+                assert_eq!(total, "1.0001");
+                assert_eq!(held, "0.0000");
+            }
+            // client 4
+            if client_id == "4" {
+                assert_eq!(available, "1000000000.0000");
+                assert_eq!(total, "1000000000.0000");
+                assert_eq!(held, "0.0000");
+            }
+        }
+    }
+
+    // Runs `csv_file` through the engine and returns the output.
+    fn output_of(csv_file: &str, style: DecimalStyle) -> String {
+        let mut engine = Engine::new();
+        engine.process_csv_file(path::Path::new(csv_file)).unwrap();
+        let mut bytes = Vec::new();
+        write_output(&engine, &mut bytes, style).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    // Negative balances keep their sign and exact digits.
+    #[test]
+    fn negative_account_test() {
+        assert_eq!(
+            output_of("sample_data/negative_account.csv", DecimalStyle::Fixed),
+            "client,available,held,total,locked\n1,-800.0000,0.0000,-800.0000,true\n"
+        );
+        assert_eq!(
+            output_of("sample_data/negative_account.csv", DecimalStyle::Minimal),
+            "client,available,held,total,locked\n1,-800,0,-800,true\n"
+        );
+    }
+
+    // Balances far beyond f32 precision are written exactly.
+    #[test]
+    fn large_balance_test() {
+        let mut engine = Engine::new();
+        for (tx_id, amount) in [(1, "922337203685477.5807"), (2, "123456.7891")] {
+            engine
+                .apply(InputRecord::new(
+                    TxType::Deposit,
+                    tx_id as u16,
+                    tx_id,
+                    Some(amount.parse().unwrap()),
+                ))
+                .unwrap();
+        }
+        let mut bytes = Vec::new();
+        write_output(&engine, &mut bytes, DecimalStyle::Fixed).unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "client,available,held,total,locked\n\
+             1,922337203685477.5807,0.0000,922337203685477.5807,false\n\
+             2,123456.7891,0.0000,123456.7891,false\n"
+        );
+    }
+
+    const SAMPLE_FILES: [&str; 6] = [
+        "sample_data/deposit_withdraw.csv",
+        "sample_data/disputes.csv",
+        "sample_data/partial_disputes.csv",
+        "sample_data/negative_account.csv",
+        "sample_data/bad_fields.csv",
+        "sample_data/transfers.csv",
+    ];
+
+    // Runs `csv_file` through an engine with `store` and returns the output.
+    fn output_with_store(store: impl TxStore, csv_file: &str) -> String {
+        let mut engine = Engine::with_store(store);
+        engine.process_csv_file(path::Path::new(csv_file)).unwrap();
+        let mut bytes = Vec::new();
+        write_output(&engine, &mut bytes, DecimalStyle::Fixed).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    // The disk store gives the same output as the default in-memory store, even when every
+    // transaction has to be read back from disk.
+    #[test]
+    fn disk_store_test() {
+        let dir =
+            std::env::temp_dir().join(format!("toy_payment_engine_output_{}", std::process::id()));
+        for csv_file in SAMPLE_FILES {
+            for cache_capacity in [0, 1] {
+                let store = DiskTxStore::create(&dir, cache_capacity).unwrap();
+                assert_eq!(
+                    output_with_store(store, csv_file),
+                    output_of(csv_file, DecimalStyle::Fixed),
+                    "{csv_file}"
+                );
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dense_store_test() {
+        for csv_file in SAMPLE_FILES {
+            assert_eq!(
+                output_with_store(DenseTxStore::new(), csv_file),
+                output_of(csv_file, DecimalStyle::Fixed),
+                "{csv_file}"
+            );
+        }
+    }
+
+    // An account read back from the output.
+    #[derive(serde::Deserialize, Debug, PartialEq, Eq)]
+    struct ReadAccount {
+        client: u16,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+    }
+
+    fn read_accounts(bytes: &[u8], format: OutputFormat) -> Vec<ReadAccount> {
+        match format {
+            OutputFormat::Csv => csv::Reader::from_reader(bytes)
+                .deserialize()
+                .collect::<Result<_, _>>()
+                .unwrap(),
+            OutputFormat::Json => serde_json::from_slice(bytes).unwrap(),
+            OutputFormat::JsonLines => serde_json::Deserializer::from_slice(bytes)
+                .into_iter()
+                .collect::<Result<_, _>>()
+                .unwrap(),
+        }
+    }
+
+    // Every format reads back to exactly the engine's accounts, whichever decimal style is used.
+    #[test]
+    fn round_trip_test() {
+        for csv_file in SAMPLE_FILES {
+            let mut engine = Engine::new();
+            engine.process_csv_file(path::Path::new(csv_file)).unwrap();
+            let accounts: Vec<ReadAccount> = engine
+                .accounts()
+                .map(|(client_id, output_record)| ReadAccount {
+                    client: client_id,
+                    available: output_record.available,
+                    held: output_record.held,
+                    total: output_record.total,
+                    locked: output_record.locked(),
+                })
+                .collect();
+            for format in [
+                OutputFormat::Csv,
+                OutputFormat::Json,
+                OutputFormat::JsonLines,
+            ] {
+                for style in [DecimalStyle::Fixed, DecimalStyle::Minimal] {
+                    let mut bytes = Vec::new();
+                    write_output_as(&engine, &mut bytes, format, style).unwrap();
+                    assert_eq!(
+                        read_accounts(&bytes, format),
+                        accounts,
+                        "{csv_file} {format:?} {style:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    // Amounts are strings with four places so they aren't read back as floats.
+    #[test]
+    fn json_output_test() {
+        let mut engine = Engine::new();
+        engine
+            .process_csv_file(path::Path::new("sample_data/negative_account.csv"))
+            .unwrap();
+        engine
+            .apply(InputRecord::new(
+                TxType::Deposit,
+                2,
+                100,
+                Some("0.1".parse().unwrap()),
+            ))
+            .unwrap();
+        let output = |format| {
+            let mut bytes = Vec::new();
+            write_output_as(&engine, &mut bytes, format, DecimalStyle::Fixed).unwrap();
+            String::from_utf8(bytes).unwrap()
+        };
+        assert_eq!(
+            output(OutputFormat::Json),
+            "[{\"client\":1,\"available\":\"-800.0000\",\"held\":\"0.0000\",\"total\":\"-800.0000\",\"locked\":true},\
+             {\"client\":2,\"available\":\"0.1000\",\"held\":\"0.0000\",\"total\":\"0.1000\",\"locked\":false}]\n"
+        );
+        assert_eq!(
+            output(OutputFormat::JsonLines),
+            "{\"client\":1,\"available\":\"-800.0000\",\"held\":\"0.0000\",\"total\":\"-800.0000\",\"locked\":true}\n\
+             {\"client\":2,\"available\":\"0.1000\",\"held\":\"0.0000\",\"total\":\"0.1000\",\"locked\":false}\n"
+        );
+
+        // No accounts is still valid JSON.
+        let mut bytes = Vec::new();
+        write_output_as(
+            &Engine::new(),
+            &mut bytes,
+            OutputFormat::Json,
+            DecimalStyle::Fixed,
+        )
+        .unwrap();
+        assert_eq!(bytes, b"[]\n");
+    }
+
+    // The JSON Lines samples have the same transactions as the csv ones.
+    #[test]
+    fn json_lines_input_test() {
+        for (json_file, csv_file) in [
+            ("sample_data/disputes.jsonl", "sample_data/disputes.csv"),
+            ("sample_data/bad_fields.jsonl", "sample_data/bad_fields.csv"),
+        ] {
+            let mut engine = Engine::new();
+            engine
+                .process_json_lines_file(path::Path::new(json_file))
+                .unwrap();
+            let mut bytes = Vec::new();
+            write_output(&engine, &mut bytes, DecimalStyle::Fixed).unwrap();
+            assert_eq!(
+                String::from_utf8(bytes).unwrap(),
+                output_of(csv_file, DecimalStyle::Fixed),
+                "{json_file}"
+            );
+        }
+    }
+}
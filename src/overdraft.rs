@@ -0,0 +1,324 @@
+use crate::amount::{Amount, DecimalStyle};
+use crate::transaction::TxType;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path;
+use std::str::FromStr;
+
+/// How far a client's available funds may go below zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverdraftPolicy {
+    /// Withdrawals, transfers and authorizations only up to the available funds, but disputes hold
+    /// funds even if that takes available negative.
+    #[default]
+    Permissive,
+    /// Nothing may take available funds below minus `limit`, zero for never negative. A dispute
+    /// that would is handled as `over_limit` says.
+    Limit {
+        limit: Amount,
+        over_limit: OverLimit,
+    },
+}
+
+/// What happens to a dispute that would take available funds past the limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverLimit {
+    /// The dispute is refused.
+    Reject,
+    /// Only as much as the limit allows is disputed, the dispute is refused if that is nothing.
+    Cap,
+}
+
+impl OverdraftPolicy {
+    /// How much a `tx_type` step may take out of `available`, `None` if there is no limit. It is
+    /// negative when the account is already past its limit.
+    pub fn spendable(&self, available: Amount, tx_type: TxType) -> Option<Amount> {
+        match (self, tx_type) {
+            (Self::Permissive, TxType::Dispute) => None,
+            (Self::Permissive, _) => Some(available),
+            (Self::Limit { limit, .. }, _) => Some(available.saturating_add(*limit)),
+        }
+    }
+}
+
+impl fmt::Display for OverdraftPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Permissive => f.write_str("permissive"),
+            Self::Limit { limit, over_limit } => {
+                if *limit == Amount::ZERO {
+                    f.write_str("no-negative")?;
+                } else {
+                    write!(
+                        f,
+                        "overdraft:{}",
+                        limit.to_string_with(DecimalStyle::Minimal)
+                    )?;
+                }
+                match over_limit {
+                    OverLimit::Reject => Ok(()),
+                    OverLimit::Cap => f.write_str(":cap"),
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for OverdraftPolicy {
+    type Err = String;
+
+    /// Parses `permissive`, `no-negative` or `overdraft:LIMIT`, the last two optionally followed by
+    /// `:cap` to cap disputes rather than refuse them.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, over_limit) = match s.strip_suffix(":cap") {
+            Some(name) => (name, OverLimit::Cap),
+            None => (s, OverLimit::Reject),
+        };
+        let limit = match name.split_once(':') {
+            None if name == "permissive" && over_limit == OverLimit::Reject => {
+                return Ok(Self::Permissive)
+            }
+            None if name == "no-negative" => Amount::ZERO,
+            Some(("overdraft", limit)) => match limit.parse::<Amount>() {
+                Ok(limit) if !limit.is_negative() => limit,
+                _ => return Err(format!("invalid overdraft limit {limit:?}")),
+            },
+            _ => {
+                return Err(format!(
+                    "unknown overdraft policy {s:?}, expected permissive, no-negative[:cap] or overdraft:LIMIT[:cap]"
+                ))
+            }
+        };
+        Ok(Self::Limit { limit, over_limit })
+    }
+}
+
+/// The overdraft policy of every client: a default and the clients that have their own.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct OverdraftPolicies {
+    pub default: OverdraftPolicy,
+    pub clients: HashMap<u16, OverdraftPolicy>,
+}
+
+#[derive(serde::Deserialize)]
+struct PolicyRow {
+    client: String,
+    policy: String,
+}
+
+impl OverdraftPolicies {
+    /// Reads the policies from a csv file, see `from_csv_reader`.
+    pub fn from_csv_file(path: &path::Path) -> Result<Self, Box<dyn Error>> {
+        Self::from_csv_reader(io::BufReader::new(fs::File::open(path)?))
+    }
+
+    /// Reads the policies from csv with the columns `client` and `policy`. The client is either a
+    /// client id or `default` for everyone else, and is permissive without a `default` row.
+    pub fn from_csv_reader(reader: impl io::Read) -> Result<Self, Box<dyn Error>> {
+        let mut policies = Self::default();
+        let mut has_default = false;
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in csv_reader.deserialize() {
+            let row: PolicyRow = row?;
+            let policy = row.policy.parse::<OverdraftPolicy>()?;
+            if row.client == "default" {
+                if has_default {
+                    return Err("the default policy is given twice".into());
+                }
+                has_default = true;
+                policies.default = policy;
+            } else {
+                let client_id = row
+                    .client
+                    .parse()
+                    .map_err(|_| format!("invalid client {:?}", row.client))?;
+                if policies.clients.insert(client_id, policy).is_some() {
+                    return Err(format!("client {client_id} has two policies").into());
+                }
+            }
+        }
+        Ok(policies)
+    }
+
+    /// The policy of `client_id`.
+    pub fn policy(&self, client_id: u16) -> OverdraftPolicy {
+        self.clients
+            .get(&client_id)
+            .copied()
+            .unwrap_or(self.default)
+    }
+}
+
+/// A dispute an overdraft policy refused or capped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverdraftOutcome {
+    /// The client that made the dispute.
+    pub client_id: u16,
+    pub tx_id: u32,
+    /// The client whose funds were held, the destination for a transfer.
+    pub account: u16,
+    /// The policy of `account`.
+    pub policy: OverdraftPolicy,
+    pub requested: Amount,
+    /// How much was disputed, zero if the dispute was refused.
+    pub disputed: Amount,
+}
+
+/// Writes every dispute an overdraft policy refused or capped to writer in csv format, in the
+/// order they were processed.
+/// * `style` - How trailing zeros are written in the amount columns.
+pub fn write_overdraft_report(
+    outcomes: &[OverdraftOutcome],
+    writer: impl io::Write,
+    style: DecimalStyle,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record([
+        "client",
+        "tx",
+        "account",
+        "policy",
+        "requested",
+        "disputed",
+        "outcome",
+    ])?;
+    for outcome in outcomes {
+        let result = if outcome.disputed == Amount::ZERO {
+            "rejected"
+        } else {
+            "capped"
+        };
+        wtr.write_record([
+            outcome.client_id.to_string(),
+            outcome.tx_id.to_string(),
+            outcome.account.to_string(),
+            outcome.policy.to_string(),
+            outcome.requested.to_string_with(style),
+            outcome.disputed.to_string_with(style),
+            result.to_owned(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn amount(num: &str) -> Amount {
+        num.parse().unwrap()
+    }
+
+    #[test]
+    fn policy_names_test() {
+        for (name, policy) in [
+            ("permissive", OverdraftPolicy::Permissive),
+            (
+                "no-negative",
+                OverdraftPolicy::Limit {
+                    limit: Amount::ZERO,
+                    over_limit: OverLimit::Reject,
+                },
+            ),
+            (
+                "no-negative:cap",
+                OverdraftPolicy::Limit {
+                    limit: Amount::ZERO,
+                    over_limit: OverLimit::Cap,
+                },
+            ),
+            (
+                "overdraft:12.5",
+                OverdraftPolicy::Limit {
+                    limit: amount("12.5"),
+                    over_limit: OverLimit::Reject,
+                },
+            ),
+            (
+                "overdraft:100:cap",
+                OverdraftPolicy::Limit {
+                    limit: amount("100"),
+                    over_limit: OverLimit::Cap,
+                },
+            ),
+        ] {
+            assert_eq!(name.parse(), Ok(policy));
+            assert_eq!(policy.to_string(), name);
+        }
+        for name in ["", "permissive:cap", "overdraft", "overdraft:-1", "cap"] {
+            assert!(name.parse::<OverdraftPolicy>().is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn spendable_test() {
+        let available = amount("-5");
+        assert_eq!(
+            OverdraftPolicy::Permissive.spendable(available, TxType::Withdrawal),
+            Some(available)
+        );
+        assert_eq!(
+            OverdraftPolicy::Permissive.spendable(available, TxType::Dispute),
+            None
+        );
+        let policy: OverdraftPolicy = "overdraft:20".parse().unwrap();
+        assert_eq!(
+            policy.spendable(available, TxType::Transfer),
+            Some(amount("15"))
+        );
+        assert_eq!(
+            policy.spendable(available, TxType::Dispute),
+            Some(amount("15"))
+        );
+    }
+
+    #[test]
+    fn policies_test() {
+        let policies = OverdraftPolicies::from_csv_reader(
+            "client, policy\n\
+             default, no-negative\n\
+             7, overdraft:50:cap\n\
+             8, permissive\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(policies.policy(1), "no-negative".parse().unwrap());
+        assert_eq!(policies.policy(7), "overdraft:50:cap".parse().unwrap());
+        assert_eq!(policies.policy(8), OverdraftPolicy::Permissive);
+        assert_eq!(
+            OverdraftPolicies::from_csv_reader("client,policy\n".as_bytes())
+                .unwrap()
+                .policy(1),
+            OverdraftPolicy::Permissive
+        );
+
+        for (csv, error) in [
+            (
+                "client,policy\ndefault,permissive\ndefault,no-negative\n",
+                "the default policy is given twice",
+            ),
+            (
+                "client,policy\n3,permissive\n3,no-negative\n",
+                "client 3 has two policies",
+            ),
+            ("client,policy\nx,permissive\n", "invalid client \"x\""),
+            (
+                "client,policy\n1,overdraft:x\n",
+                "invalid overdraft limit \"x\"",
+            ),
+        ] {
+            assert_eq!(
+                OverdraftPolicies::from_csv_reader(csv.as_bytes())
+                    .unwrap_err()
+                    .to_string(),
+                error
+            );
+        }
+    }
+}
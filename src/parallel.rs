@@ -0,0 +1,767 @@
+use crate::account::OutputRecord;
+use crate::admin::AdminRecord;
+use crate::engine::{csv_reader, parse_json_line, read_row, Engine};
+use crate::error::TxError;
+use crate::fees::FeeSchedule;
+use crate::overdraft::OverdraftPolicies;
+use crate::rejects::RejectsWriter;
+use crate::statement::Statement;
+use crate::transaction::{InputRecord, TxType};
+use itertools::Itertools;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::num::NonZeroUsize;
+use std::sync::mpsc;
+use std::thread;
+
+/// How many jobs are sent to a worker at a time.
+const BATCH_LEN: usize = 1024;
+
+/// How many batches can wait for a worker before the reader blocks.
+const QUEUE_LEN: usize = 16;
+
+/// Processes transactions on several threads. One reader, the caller's thread, routes every
+/// record to the worker thread that owns the client's shard of client ids, so each client's
+/// transactions are still applied in input order.
+///
+/// Transaction IDs are shared by every client, so the reader remembers which shard each deposit
+/// or withdrawal ID went to. When a transaction could refer to an ID another shard has, the
+/// reader asks that shard and refuses it the same way a single engine would. A transfer, or a
+/// dispute step of one, whose destination is in another shard borrows the destination account from
+/// that shard's worker once it has processed everything before. The accounts, admin log, statement,
+/// reports and rejects come out identical to processing everything with one `Engine`.
+pub struct ParallelEngine {
+    workers: Vec<Worker>,
+    // The shard that was last sent a deposit, withdrawal or transfer with each tx_id. Only it can
+    // have stored the transaction.
+    tx_owners: HashMap<u32, usize>,
+    // Every tx_id sent to a shard as a transfer, whether or not the shard stored it.
+    transfer_tx_ids: HashSet<u32>,
+    // Answers to `Message::Lookup`.
+    lookups: (
+        mpsc::Sender<Option<InputRecord>>,
+        mpsc::Receiver<Option<InputRecord>>,
+    ),
+    // Numbers every input record so the workers' results can be put back in input order.
+    next_seq: u64,
+}
+
+struct Worker {
+    sender: mpsc::SyncSender<Message>,
+    batch: Vec<Job>,
+    handle: thread::JoinHandle<Shard>,
+}
+
+enum Message {
+    Jobs(Vec<Job>),
+    /// Asks whether the shard stored the tx_id.
+    Lookup(u32, mpsc::Sender<Option<InputRecord>>),
+    RecordStatement(u16),
+    SetFeeSchedule(FeeSchedule),
+    SetOverdraftPolicies(OverdraftPolicies),
+    /// Asks for the rejects collected so far.
+    TakeRejects(mpsc::Sender<Vec<Reject>>),
+    /// Lends the client's account to the shard sent the matching `Borrow`, and waits for it back.
+    Lend(u16, mpsc::Sender<Loan>, mpsc::Receiver<Loan>),
+    /// Processes the job with an account lent by another shard, then gives it back.
+    Borrow(Job, mpsc::Receiver<Loan>, mpsc::Sender<Loan>),
+}
+
+/// A client account lent to another shard, with the statement if it is that client's.
+struct Loan {
+    client_id: u16,
+    account: Option<OutputRecord>,
+    statement: Option<Statement>,
+}
+
+struct Job {
+    seq: u64,
+    line: u64,
+    /// The record as it was read, if rejects are being collected.
+    raw_record: Option<String>,
+    kind: JobKind,
+}
+
+enum JobKind {
+    Apply(InputRecord),
+    /// A transaction the reader already found has to be refused.
+    Refuse(InputRecord, TxError),
+    Admin(AdminRecord),
+}
+
+struct Reject {
+    seq: u64,
+    line: u64,
+    raw_record: String,
+    error: TxError,
+}
+
+/// A worker's part of the engine.
+struct Shard {
+    engine: Engine,
+    // The seq of every entry in the engine's admin log.
+    admin_seqs: Vec<u64>,
+    // The seq of every entry in the engine's overdraft report.
+    overdraft_seqs: Vec<u64>,
+    rejects: Vec<Reject>,
+}
+
+impl ParallelEngine {
+    /// Starts an engine with `threads` worker threads.
+    pub fn new(threads: NonZeroUsize) -> Self {
+        let workers = (0..threads.get())
+            .map(|_| {
+                let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+                Worker {
+                    sender,
+                    batch: Vec::with_capacity(BATCH_LEN),
+                    handle: thread::spawn(move || run_worker(&receiver)),
+                }
+            })
+            .collect();
+        Self {
+            workers,
+            tx_owners: HashMap::new(),
+            transfer_tx_ids: HashSet::new(),
+            lookups: mpsc::channel(),
+            next_seq: 0,
+        }
+    }
+
+    /// Starts recording a statement for `client_id`, see `Engine::record_statement`.
+    pub fn record_statement(&mut self, client_id: u16) {
+        let shard = self.shard_of(client_id);
+        self.send(shard, Message::RecordStatement(client_id));
+    }
+
+    /// Charges fees from `schedule` on every transaction sent after, see `Engine::set_fee_schedule`.
+    pub fn set_fee_schedule(&mut self, schedule: FeeSchedule) {
+        for shard in 0..self.workers.len() {
+            self.send(shard, Message::SetFeeSchedule(schedule));
+        }
+    }
+
+    /// Limits overdrafts as `policies` say on every transaction sent after, see
+    /// `Engine::set_overdraft_policies`.
+    pub fn set_overdraft_policies(&mut self, policies: &OverdraftPolicies) {
+        for shard in 0..self.workers.len() {
+            self.send(shard, Message::SetOverdraftPolicies(policies.clone()));
+        }
+    }
+
+    /// Same as `Engine::process_csv_reader_with_rejects`, with `rejects` optional.
+    pub fn process_csv_reader<W: io::Write>(
+        &mut self,
+        reader: impl io::Read,
+        source: &str,
+        rejects: Option<&mut RejectsWriter<W>>,
+    ) -> Result<(), csv::Error> {
+        self.process_csv(reader, source, rejects, JobKind::Apply)
+    }
+
+    /// Same as `Engine::process_admin_csv_file`, for admin actions read from `reader`.
+    pub fn process_admin_csv_reader<W: io::Write>(
+        &mut self,
+        reader: impl io::Read,
+        source: &str,
+        rejects: Option<&mut RejectsWriter<W>>,
+    ) -> Result<(), csv::Error> {
+        self.process_csv(reader, source, rejects, JobKind::Admin)
+    }
+
+    /// Same as `Engine::process_json_lines_reader_with_rejects`, with `rejects` optional.
+    pub fn process_json_lines_reader<W: io::Write>(
+        &mut self,
+        reader: impl io::BufRead,
+        source: &str,
+        rejects: Option<&mut RejectsWriter<W>>,
+    ) -> io::Result<()> {
+        let keep_raw = rejects.is_some();
+        let mut parse_rejects = Vec::new();
+        for (index, line) in reader.split(b'\n').enumerate() {
+            let line = line?;
+            let trimmed = line.trim_ascii();
+            if trimmed.is_empty() {
+                continue;
+            }
+            let raw_record = keep_raw.then(|| String::from_utf8_lossy(trimmed).into_owned());
+            self.route(
+                index as u64 + 1,
+                raw_record,
+                parse_json_line(trimmed).map(JobKind::Apply),
+                &mut parse_rejects,
+            );
+        }
+        if let Some(rejects) = rejects {
+            self.write_rejects(parse_rejects, source, rejects)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the workers to finish and puts their shards together into one engine.
+    pub fn finish(mut self) -> Engine {
+        let mut engine = Engine::new();
+        let mut admin_log = Vec::new();
+        let mut overdraft_report = Vec::new();
+        for index in 0..self.workers.len() {
+            self.flush(index);
+        }
+        for worker in self.workers {
+            drop(worker.sender);
+            let shard = worker
+                .handle
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            let Shard {
+                engine: shard_engine,
+                admin_seqs,
+                overdraft_seqs,
+                ..
+            } = shard;
+            engine.client_map.extend(shard_engine.client_map);
+            engine.tx_store.tx_map.extend(shard_engine.tx_store.tx_map);
+            engine.fee_summary.merge(&shard_engine.fee_summary);
+            engine.fee_schedule = shard_engine.fee_schedule;
+            engine.overdraft_policies = shard_engine.overdraft_policies;
+            if shard_engine.statement.is_some() {
+                engine.statement = shard_engine.statement;
+            }
+            admin_log.push(admin_seqs.into_iter().zip(shard_engine.admin_log));
+            overdraft_report.push(
+                overdraft_seqs
+                    .into_iter()
+                    .zip(shard_engine.overdraft_report),
+            );
+        }
+        engine.admin_log = admin_log
+            .into_iter()
+            .kmerge_by(|(a, _), (b, _)| a < b)
+            .map(|(_, admin_record)| admin_record)
+            .collect();
+        engine.overdraft_report = overdraft_report
+            .into_iter()
+            .kmerge_by(|(a, _), (b, _)| a < b)
+            .map(|(_, outcome)| outcome)
+            .collect();
+        engine
+    }
+
+    fn process_csv<T: DeserializeOwned, W: io::Write>(
+        &mut self,
+        reader: impl io::Read,
+        source: &str,
+        rejects: Option<&mut RejectsWriter<W>>,
+        kind: fn(T) -> JobKind,
+    ) -> Result<(), csv::Error> {
+        let mut csv_reader = csv_reader(reader);
+        let headers = csv_reader.headers()?.clone();
+        let keep_raw = rejects.is_some();
+        let mut parse_rejects = Vec::new();
+        let mut string_record = csv::StringRecord::new();
+        while let Some((line, raw_record, record_res)) =
+            read_row(&mut csv_reader, &headers, &mut string_record)?
+        {
+            let raw_record = keep_raw.then_some(raw_record);
+            self.route(line, raw_record, record_res.map(kind), &mut parse_rejects);
+        }
+        if let Some(rejects) = rejects {
+            self.write_rejects(parse_rejects, source, rejects)?;
+        }
+        Ok(())
+    }
+
+    /// Sends a parsed record to the worker that owns its client. Records that couldn't be parsed
+    /// don't belong to any client, they are added to `parse_rejects` if rejects are being collected.
+    fn route(
+        &mut self,
+        line: u64,
+        raw_record: Option<String>,
+        kind: Result<JobKind, TxError>,
+        parse_rejects: &mut Vec<Reject>,
+    ) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let kind = match kind {
+            Ok(kind) => kind,
+            Err(error) => {
+                if let Some(raw_record) = raw_record {
+                    parse_rejects.push(Reject {
+                        seq,
+                        line,
+                        raw_record,
+                        error,
+                    });
+                }
+                return;
+            }
+        };
+        let (shard, kind) = match kind {
+            JobKind::Apply(record) => {
+                let shard = self.shard_of(record.client_id);
+                (shard, self.check_tx_id(shard, record))
+            }
+            JobKind::Admin(ref admin_record) => (self.shard_of(admin_record.client_id), kind),
+            JobKind::Refuse(..) => unreachable!("only the reader refuses transactions"),
+        };
+        let job = Job {
+            seq,
+            line,
+            raw_record,
+            kind,
+        };
+        if let Some(destination) = self.destination_elsewhere(shard, &job.kind) {
+            let (loan_sender, loan_receiver) = mpsc::channel();
+            let (return_sender, return_receiver) = mpsc::channel();
+            let lender = self.shard_of(destination);
+            self.send(
+                lender,
+                Message::Lend(destination, loan_sender, return_receiver),
+            );
+            self.send(shard, Message::Borrow(job, loan_receiver, return_sender));
+            return;
+        }
+        let worker = &mut self.workers[shard];
+        worker.batch.push(job);
+        if worker.batch.len() == BATCH_LEN {
+            self.flush(shard);
+        }
+    }
+
+    /// Returns the destination of a transfer, or of the transfer a dispute step refers to, if it
+    /// belongs to a shard other than `shard`.
+    fn destination_elsewhere(&mut self, shard: usize, kind: &JobKind) -> Option<u16> {
+        let destination = match kind {
+            JobKind::Apply(record) => match record.tx_type {
+                TxType::Deposit
+                | TxType::Withdrawal
+                | TxType::Authorize
+                | TxType::Capture
+                | TxType::Void => None,
+                TxType::Transfer => record.destination,
+                TxType::Dispute | TxType::Resolve | TxType::Chargeback
+                    if self.transfer_tx_ids.contains(&record.tx_id) =>
+                {
+                    // Only `shard` can have stored a transaction its client can dispute.
+                    self.lookup(shard, record.tx_id)
+                        .filter(|stored| stored.tx_type == TxType::Transfer)
+                        .and_then(|stored| stored.destination)
+                }
+                TxType::Dispute | TxType::Resolve | TxType::Chargeback => None,
+            },
+            JobKind::Refuse(..) | JobKind::Admin(_) => None,
+        }?;
+        (self.shard_of(destination) != shard).then_some(destination)
+    }
+
+    /// Refuses a transaction for `shard` that refers to a tx_id stored by another shard, the same
+    /// way the engine does: a deposit, withdrawal, transfer or authorization reusing it is a
+    /// duplicate and any other transaction is for another client's transaction.
+    fn check_tx_id(&mut self, shard: usize, record: InputRecord) -> JobKind {
+        let client_id = record.client_id;
+        let tx_id = record.tx_id;
+        let stored_elsewhere = match self.tx_owners.get(&tx_id) {
+            Some(&owner) if owner != shard => self.lookup(owner, tx_id).is_some(),
+            _ => false,
+        };
+        match record.tx_type {
+            TxType::Deposit | TxType::Withdrawal | TxType::Transfer | TxType::Authorize
+                if stored_elsewhere =>
+            {
+                JobKind::Refuse(record, TxError::DuplicateTxId { client_id, tx_id })
+            }
+            TxType::Deposit | TxType::Withdrawal | TxType::Transfer | TxType::Authorize => {
+                self.tx_owners.insert(tx_id, shard);
+                if record.tx_type == TxType::Transfer {
+                    self.transfer_tx_ids.insert(tx_id);
+                }
+                JobKind::Apply(record)
+            }
+            _ if stored_elsewhere => {
+                JobKind::Refuse(record, TxError::ClientMismatch { client_id, tx_id })
+            }
+            _ => JobKind::Apply(record),
+        }
+    }
+
+    /// Asks `shard` for the transaction it stored as `tx_id`, once it has processed everything sent
+    /// to it.
+    fn lookup(&mut self, shard: usize, tx_id: u32) -> Option<InputRecord> {
+        let sender = self.lookups.0.clone();
+        self.send(shard, Message::Lookup(tx_id, sender));
+        self.lookups.1.recv().expect("worker stopped")
+    }
+
+    /// Collects the rejects from every worker and writes them with `parse_rejects` in input order.
+    fn write_rejects<W: io::Write>(
+        &mut self,
+        parse_rejects: Vec<Reject>,
+        source: &str,
+        rejects: &mut RejectsWriter<W>,
+    ) -> Result<(), csv::Error> {
+        let (sender, receiver) = mpsc::channel();
+        for shard in 0..self.workers.len() {
+            self.send(shard, Message::TakeRejects(sender.clone()));
+        }
+        drop(sender);
+        let shard_rejects: Vec<Vec<Reject>> = receiver.iter().collect();
+        for reject in shard_rejects
+            .into_iter()
+            .chain([parse_rejects])
+            .kmerge_by(|a, b| a.seq < b.seq)
+        {
+            rejects.write(source, reject.line, &reject.raw_record, &reject.error)?;
+        }
+        Ok(())
+    }
+
+    fn shard_of(&self, client_id: u16) -> usize {
+        usize::from(client_id) % self.workers.len()
+    }
+
+    /// Sends `message` to `shard` after everything batched for it.
+    fn send(&mut self, shard: usize, message: Message) {
+        self.flush(shard);
+        self.workers[shard]
+            .sender
+            .send(message)
+            .expect("worker stopped");
+    }
+
+    fn flush(&mut self, shard: usize) {
+        let worker = &mut self.workers[shard];
+        if !worker.batch.is_empty() {
+            let jobs = std::mem::replace(&mut worker.batch, Vec::with_capacity(BATCH_LEN));
+            worker
+                .sender
+                .send(Message::Jobs(jobs))
+                .expect("worker stopped");
+        }
+    }
+}
+
+fn run_worker(receiver: &mpsc::Receiver<Message>) -> Shard {
+    let mut shard = Shard {
+        engine: Engine::new(),
+        admin_seqs: Vec::new(),
+        overdraft_seqs: Vec::new(),
+        rejects: Vec::new(),
+    };
+    for message in receiver {
+        match message {
+            Message::Jobs(jobs) => {
+                for job in jobs {
+                    process_job(&mut shard, job);
+                }
+            }
+            Message::Lookup(tx_id, sender) => {
+                let stored = shard.engine.transaction(tx_id).ok().flatten();
+                // The reader is waiting on the answer, so it is still there.
+                sender.send(stored).unwrap();
+            }
+            Message::RecordStatement(client_id) => shard.engine.record_statement(client_id),
+            Message::SetFeeSchedule(schedule) => shard.engine.set_fee_schedule(schedule),
+            Message::SetOverdraftPolicies(policies) => {
+                shard.engine.set_overdraft_policies(policies);
+            }
+            Message::TakeRejects(sender) => {
+                sender.send(std::mem::take(&mut shard.rejects)).unwrap();
+            }
+            Message::Lend(client_id, loan_sender, return_receiver) => {
+                let engine = &mut shard.engine;
+                let statement = engine
+                    .statement
+                    .take_if(|statement| statement.client_id() == client_id);
+                let loan = Loan {
+                    client_id,
+                    account: engine.client_map.remove(&client_id),
+                    statement,
+                };
+                // The borrower was sent its message right after this one, it gives the loan back
+                // once it has processed the job.
+                loan_sender.send(loan).expect("borrower stopped");
+                let loan = return_receiver.recv().expect("borrower stopped");
+                if let Some(account) = loan.account {
+                    engine.client_map.insert(client_id, account);
+                }
+                if loan.statement.is_some() {
+                    engine.statement = loan.statement;
+                }
+            }
+            Message::Borrow(job, loan_receiver, return_sender) => {
+                let loan = loan_receiver.recv().expect("lender stopped");
+                let client_id = loan.client_id;
+                if let Some(account) = loan.account {
+                    shard.engine.client_map.insert(client_id, account);
+                }
+                // There is only one statement, so this shard has none of its own if it gets one.
+                let lent_statement = loan.statement.is_some();
+                if lent_statement {
+                    shard.engine.statement = loan.statement;
+                }
+                process_job(&mut shard, job);
+                let loan = Loan {
+                    client_id,
+                    account: shard.engine.client_map.remove(&client_id),
+                    statement: lent_statement
+                        .then(|| shard.engine.statement.take())
+                        .flatten(),
+                };
+                return_sender.send(loan).expect("lender stopped");
+            }
+        }
+    }
+    shard
+}
+
+fn process_job(shard: &mut Shard, job: Job) {
+    let reported = shard.engine.overdraft_report.len();
+    let result = match job.kind {
+        JobKind::Apply(record) => shard.engine.apply(record),
+        JobKind::Refuse(record, error) => shard.engine.refuse(&record, error),
+        JobKind::Admin(admin_record) => {
+            let result = shard.engine.apply_admin(admin_record);
+            if result.is_ok() {
+                shard.admin_seqs.push(job.seq);
+            }
+            result
+        }
+    };
+    let new_outcomes = shard.engine.overdraft_report.len() - reported;
+    shard
+        .overdraft_seqs
+        .extend(std::iter::repeat_n(job.seq, new_outcomes));
+    if let (Err(error), Some(raw_record)) = (result, job.raw_record) {
+        shard.rejects.push(Reject {
+            seq: job.seq,
+            line: job.line,
+            raw_record,
+            error,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::amount::DecimalStyle;
+    use crate::fees::write_fee_summary;
+    use crate::output::{write_admin_log, write_output};
+    use crate::overdraft::write_overdraft_report;
+    use crate::statement::{write_statement, StatementFormat};
+
+    enum Input {
+        Csv(String),
+        JsonLines(String),
+        Admin(String),
+    }
+
+    // The accounts, rejects, admin log, statement of client 1, fee summary and overdraft report,
+    // all as text.
+    fn results(engine: &Engine, rejects: Vec<u8>) -> [String; 6] {
+        let mut output = Vec::new();
+        write_output(engine, &mut output, DecimalStyle::Fixed).unwrap();
+        let mut admin_log = Vec::new();
+        write_admin_log(engine, &mut admin_log).unwrap();
+        let mut statement = Vec::new();
+        write_statement(
+            engine.statement().unwrap(),
+            &mut statement,
+            StatementFormat::Csv,
+            DecimalStyle::Fixed,
+        )
+        .unwrap();
+        let mut fee_summary = Vec::new();
+        write_fee_summary(engine.fee_summary(), &mut fee_summary, DecimalStyle::Fixed).unwrap();
+        let mut overdraft_report = Vec::new();
+        write_overdraft_report(
+            engine.overdraft_report(),
+            &mut overdraft_report,
+            DecimalStyle::Fixed,
+        )
+        .unwrap();
+        [
+            output,
+            rejects,
+            admin_log,
+            statement,
+            fee_summary,
+            overdraft_report,
+        ]
+        .map(|bytes| String::from_utf8(bytes).unwrap())
+    }
+
+    // Every run charges the fees of the sample schedule.
+    fn fee_schedule() -> FeeSchedule {
+        FeeSchedule::from_csv_file(std::path::Path::new("sample_data/fee_schedule.csv")).unwrap()
+    }
+
+    // The engine only reads admin actions from files.
+    fn admin_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "toy_payment_engine_parallel_{}",
+            std::process::id()
+        ))
+    }
+
+    // And limits overdrafts as the sample policies say.
+    fn overdraft_policies() -> OverdraftPolicies {
+        OverdraftPolicies::from_csv_file(std::path::Path::new("sample_data/overdraft_policies.csv"))
+            .unwrap()
+    }
+
+    fn serial(inputs: &[Input]) -> [String; 6] {
+        let mut engine = Engine::new();
+        engine.set_fee_schedule(fee_schedule());
+        engine.set_overdraft_policies(overdraft_policies());
+        engine.record_statement(1);
+        let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+        for input in inputs {
+            match input {
+                Input::Csv(csv) => engine
+                    .process_csv_reader_with_rejects(csv.as_bytes(), "input", &mut rejects)
+                    .unwrap(),
+                Input::JsonLines(json) => engine
+                    .process_json_lines_reader_with_rejects(json.as_bytes(), "input", &mut rejects)
+                    .unwrap(),
+                Input::Admin(csv) => {
+                    let path = admin_path();
+                    std::fs::write(&path, csv).unwrap();
+                    engine
+                        .process_admin_csv_file(&path, Some(&mut rejects))
+                        .unwrap();
+                    std::fs::remove_file(path).unwrap();
+                }
+            }
+        }
+        results(&engine, rejects.into_inner().unwrap())
+    }
+
+    fn parallel(inputs: &[Input], threads: usize) -> [String; 6] {
+        let mut engine = ParallelEngine::new(NonZeroUsize::new(threads).unwrap());
+        engine.set_fee_schedule(fee_schedule());
+        engine.set_overdraft_policies(&overdraft_policies());
+        engine.record_statement(1);
+        let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+        for input in inputs {
+            match input {
+                Input::Csv(csv) => engine
+                    .process_csv_reader(csv.as_bytes(), "input", Some(&mut rejects))
+                    .unwrap(),
+                Input::JsonLines(json) => engine
+                    .process_json_lines_reader(json.as_bytes(), "input", Some(&mut rejects))
+                    .unwrap(),
+                Input::Admin(csv) => engine
+                    .process_admin_csv_reader(
+                        csv.as_bytes(),
+                        &admin_path().display().to_string(),
+                        Some(&mut rejects),
+                    )
+                    .unwrap(),
+            }
+        }
+        let engine = engine.finish();
+        results(&engine, rejects.into_inner().unwrap())
+    }
+
+    #[test]
+    fn sample_files_test() {
+        let read = |file| std::fs::read_to_string(file).unwrap();
+        let inputs = [
+            Input::Csv(read("sample_data/deposit_withdraw.csv")),
+            Input::Csv(read("sample_data/disputes.csv")),
+            Input::Admin(read("sample_data/admin_actions.csv")),
+            Input::Csv(read("sample_data/partial_disputes.csv")),
+            Input::Csv(read("sample_data/negative_account.csv")),
+            Input::JsonLines(read("sample_data/bad_fields.jsonl")),
+            Input::Csv(read("sample_data/bad_fields.csv")),
+            Input::JsonLines(read("sample_data/disputes.jsonl")),
+            Input::Csv(read("sample_data/transfers.csv")),
+            Input::Csv(read("sample_data/fees.csv")),
+            Input::Csv(read("sample_data/overdraft.csv")),
+            Input::Csv(read("sample_data/authorizations.csv")),
+        ];
+        let expected = serial(&inputs);
+        assert!(expected[5].contains(",capped\n"));
+        for threads in 1..=4 {
+            assert_eq!(parallel(&inputs, threads), expected, "{threads} threads");
+        }
+    }
+
+    // A small xorshift generator so the random inputs are the same on every run.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    // Random transactions for a few clients over a small range of tx ids, so that there are many
+    // duplicate ids, disputes of other clients' transactions, transfers across shards and
+    // captures of other clients' authorizations.
+    fn random_inputs(seed: u64) -> Vec<Input> {
+        let mut rng = Rng(seed);
+        let mut inputs = Vec::new();
+        for _ in 0..4 {
+            let mut csv = String::from("type,client,tx,amount,destination\n");
+            for _ in 0..3000 {
+                let tx_type = [
+                    "deposit",
+                    "deposit",
+                    "withdrawal",
+                    "transfer",
+                    "dispute",
+                    "resolve",
+                    "chargeback",
+                    "authorize",
+                    "capture",
+                    "void",
+                ][rng.below(10) as usize];
+                let amount = match rng.below(10) {
+                    0 => String::new(),
+                    1 => format!("-{}", rng.below(100)),
+                    _ if matches!(tx_type, "deposit" | "withdrawal" | "transfer" | "authorize") => {
+                        format!("{}.{:04}", rng.below(1000), rng.below(10000))
+                    }
+                    _ => String::new(),
+                };
+                let destination = match tx_type {
+                    "transfer" => rng.below(20).to_string(),
+                    _ => String::new(),
+                };
+                csv += &format!(
+                    "{tx_type},{},{},{amount},{destination}\n",
+                    rng.below(20),
+                    rng.below(3000)
+                );
+            }
+            inputs.push(Input::Csv(csv));
+            let mut admin = String::from("action,client,operator,reason\n");
+            for _ in 0..10 {
+                admin += &format!("unlock,{},ops,test\n", rng.below(20));
+            }
+            inputs.push(Input::Admin(admin));
+        }
+        inputs
+    }
+
+    #[test]
+    fn random_inputs_test() {
+        for seed in [1, 42] {
+            let inputs = random_inputs(seed);
+            let expected = serial(&inputs);
+            // Some disputes were refused or capped by the overdraft policies.
+            for threads in [2, 3, 8] {
+                // Too long to print if they differ.
+                assert!(
+                    parallel(&inputs, threads) == expected,
+                    "seed {seed}, {threads} threads"
+                );
+            }
+        }
+    }
+}
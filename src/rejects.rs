@@ -0,0 +1,144 @@
+use crate::error::TxError;
+use std::io;
+
+/// Writes one csv row per refused or unparseable input record.
+///
+/// Columns are the input the row came from, its line number, the record's fields as they were
+/// read, the rejection category and a human-readable reason.
+pub struct RejectsWriter<W: io::Write> {
+    wtr: csv::Writer<W>,
+}
+
+impl<W: io::Write> RejectsWriter<W> {
+    pub fn new(writer: W) -> Result<Self, csv::Error> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        wtr.write_record(["source", "line", "record", "category", "reason"])?;
+        Ok(Self { wtr })
+    }
+
+    /// Creates a writer that continues an earlier rejects output, so it doesn't write the header.
+    pub fn append(writer: W) -> Self {
+        Self {
+            wtr: csv::Writer::from_writer(writer),
+        }
+    }
+
+    pub fn write(
+        &mut self,
+        source: &str,
+        line: u64,
+        record: &str,
+        error: &TxError,
+    ) -> Result<(), csv::Error> {
+        self.wtr.write_record([
+            source,
+            line.to_string().as_str(),
+            record,
+            error.category(),
+            error.to_string().as_str(),
+        ])
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.wtr.flush()
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn into_inner(self) -> io::Result<W> {
+        self.wtr
+            .into_inner()
+            .map_err(|error| io::Error::new(error.error().kind(), error.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use std::path;
+
+    // Every line of bad_fields.csv except the header and the three applied rows is reported.
+    #[test]
+    fn bad_fields_rejects_test() {
+        let mut engine = Engine::new();
+        let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+        engine
+            .process_csv_file_with_rejects(
+                path::Path::new("sample_data/bad_fields.csv"),
+                &mut rejects,
+            )
+            .unwrap();
+        let bytes = rejects.into_inner().unwrap();
+
+        let mut rdr = csv::Reader::from_reader(&*bytes);
+        let rows: Vec<csv::StringRecord> = rdr.records().map(Result::unwrap).collect();
+        let lines: Vec<&str> = rows.iter().map(|row| row.get(1).unwrap()).collect();
+        assert_eq!(
+            lines,
+            ["2", "3", "4", "5", "6", "7", "8", "9", "12", "14", "15", "16", "17"]
+        );
+
+        let category = |line: &str| {
+            rows.iter()
+                .find(|row| row.get(1) == Some(line))
+                .unwrap()
+                .get(3)
+                .unwrap()
+                .to_owned()
+        };
+        assert!(rows
+            .iter()
+            .all(|row| row.get(0) == Some("sample_data/bad_fields.csv")));
+        assert_eq!(category("2"), "parse");
+        assert_eq!(category("5"), "missing_amount");
+        assert_eq!(category("14"), "missing_amount");
+        assert_eq!(category("17"), "insufficient_funds");
+        assert_eq!(rows[0].get(2), Some("# The following are invalid deposits"));
+    }
+
+    // Malformed JSON lines are reported like unparseable csv rows, and the same validation
+    // applies to the transactions that parse.
+    #[test]
+    fn bad_fields_json_lines_rejects_test() {
+        let mut engine = Engine::new();
+        let mut rejects = RejectsWriter::new(Vec::new()).unwrap();
+        let file = std::fs::File::open("sample_data/bad_fields.jsonl").unwrap();
+        engine
+            .process_json_lines_reader_with_rejects(
+                io::BufReader::new(file),
+                "bad_fields.jsonl",
+                &mut rejects,
+            )
+            .unwrap();
+        let bytes = rejects.into_inner().unwrap();
+
+        let mut rdr = csv::Reader::from_reader(&*bytes);
+        let rows: Vec<csv::StringRecord> = rdr.records().map(Result::unwrap).collect();
+        let lines_and_categories: Vec<(&str, &str)> = rows
+            .iter()
+            .map(|row| (row.get(1).unwrap(), row.get(3).unwrap()))
+            .collect();
+        assert_eq!(
+            lines_and_categories,
+            [
+                ("1", "parse"),
+                ("2", "parse"),
+                ("3", "missing_amount"),
+                ("4", "parse"),
+                ("5", "parse"),
+                ("6", "parse"),
+                ("7", "negative_amount"),
+                ("8", "parse"),
+                ("9", "parse"),
+                ("14", "missing_amount"),
+                ("15", "parse"),
+                ("16", "insufficient_funds"),
+            ]
+        );
+        assert_eq!(
+            rows[0].get(2),
+            Some(r#"{"type": "deposit", "tx": 1, "amount": "1.0"}"#)
+        );
+        assert!(rows[0].get(4).unwrap().contains("missing field `client`"));
+    }
+}
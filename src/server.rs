@@ -0,0 +1,357 @@
+use crate::amount::DecimalStyle;
+use crate::engine::{read_row, Engine};
+use crate::error::TxError;
+use crate::output::{write_output_as, OutputFormat};
+use crate::store::TxStore;
+use crate::transaction::InputRecord;
+use std::io::{self, BufRead, Write};
+use std::net;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// The columns of every transaction line, in order, unless the connection starts with a header.
+const HEADERS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+/// The optional column with the destination of a transfer.
+const DESTINATION: &str = "destination";
+
+/// Serves `engine` to every connection accepted on `listener`, each on its own thread. Only
+/// returns if accepting a connection fails.
+///
+/// Every connection streams csv transaction rows with the columns `type`, `client`, `tx` and
+/// `amount`, one per line and without quoting across lines. A header line is allowed first, which
+/// can also add the `destination` column for transfers. Each line is applied to the shared engine
+/// before the next line from that connection is read, and is answered with `ok` or
+/// `rejected,<category>,<reason>`, so a connection's transactions are applied in the order it sent
+/// them and the answers come back in that order too. Transactions from different connections are
+/// interleaved in the order they arrive. Blank lines are ignored.
+///
+/// Answers are only sent once the transactions they answer are written to the transaction store
+/// and synced to the journal, as its fsync policy allows, so an acknowledged transaction survives
+/// a crash of the server. Pipelined lines are answered together once the connection has nothing
+/// more buffered.
+///
+/// A line starting with `!` is a control command. `!accounts` answers with every account as
+/// `write_output_as` writes them in `format`, followed by a blank line.
+pub fn serve<S: TxStore + Send + 'static>(
+    listener: &net::TcpListener,
+    engine: Arc<Mutex<Engine<S>>>,
+    format: OutputFormat,
+    style: DecimalStyle,
+) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let engine = Arc::clone(&engine);
+        thread::spawn(move || {
+            // The client went away or the connection broke, there is nobody left to tell.
+            let _ = handle_connection(stream, &engine, format, style);
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection<S: TxStore>(
+    mut stream: net::TcpStream,
+    engine: &Mutex<Engine<S>>,
+    format: OutputFormat,
+    style: DecimalStyle,
+) -> io::Result<()> {
+    let mut reader = io::BufReader::new(stream.try_clone()?);
+    // Answers wait here until what they acknowledge is kept, see `acknowledge`.
+    let mut answers = Vec::new();
+    let mut headers = csv::StringRecord::from(HEADERS.to_vec());
+    let mut string_record = csv::StringRecord::new();
+    let mut first_line = true;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let row = line.trim();
+        if row.is_empty() {
+            continue;
+        }
+
+        if let Some(command) = row.strip_prefix('!') {
+            match command.trim() {
+                "accounts" => {
+                    // Rendered into memory, so a client that doesn't read its answers only holds
+                    // up its own connection rather than the engine.
+                    write_output_as(&lock(engine), &mut answers, format, style)
+                        .map_err(|error| io::Error::other(error.to_string()))?;
+                    writeln!(answers)?;
+                }
+                command => writeln!(
+                    answers,
+                    "{}",
+                    rejection("command", &format!("unknown command {command:?}"))
+                )?,
+            }
+        } else if let Some(header) = first_line.then(|| parse_header(row)).flatten() {
+            headers = header;
+            writeln!(answers, "ok")?;
+        } else {
+            let mut csv_reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .trim(csv::Trim::All)
+                .flexible(true)
+                .from_reader(row.as_bytes());
+            let result =
+                match read_row::<InputRecord, _>(&mut csv_reader, &headers, &mut string_record)? {
+                    Some((_, _, record_res)) => lock(engine).process_input_record(record_res),
+                    None => Err(TxError::Parse {
+                        reason: "empty row".to_owned(),
+                    }),
+                };
+            match result {
+                Ok(()) => writeln!(answers, "ok")?,
+                Err(error) => writeln!(
+                    answers,
+                    "{}",
+                    rejection(error.category(), &error.to_string())
+                )?,
+            }
+        }
+        first_line = false;
+
+        // Answer everything that was pipelined at once, rather than a packet and a sync per line.
+        if reader.buffer().is_empty() {
+            acknowledge(&mut stream, &mut answers, engine)?;
+        }
+    }
+    acknowledge(&mut stream, &mut answers, engine)
+}
+
+/// Writes the transaction store and syncs the journal, then sends `answers` and clears them.
+fn acknowledge<S: TxStore>(
+    stream: &mut net::TcpStream,
+    answers: &mut Vec<u8>,
+    engine: &Mutex<Engine<S>>,
+) -> io::Result<()> {
+    if answers.is_empty() {
+        return Ok(());
+    }
+    {
+        let mut engine = lock(engine);
+        engine.flush_store()?;
+        engine.sync_journal()?;
+    }
+    stream.write_all(answers)?;
+    answers.clear();
+    Ok(())
+}
+
+/// Returns `row` as the columns of the connection if it is a header line: every column, and
+/// `destination` if it is there, each named once.
+fn parse_header(row: &str) -> Option<csv::StringRecord> {
+    let header: csv::StringRecord = row.split(',').map(str::trim).collect();
+    let known = header
+        .iter()
+        .all(|column| HEADERS.contains(&column) || column == DESTINATION);
+    let complete = HEADERS
+        .iter()
+        .all(|column| header.iter().filter(|name| name == column).count() == 1);
+    (known && complete && header.iter().filter(|name| *name == DESTINATION).count() <= 1)
+        .then_some(header)
+}
+
+// Every change is committed to the engine at once, so a connection that panicked didn't leave
+// one half applied.
+pub(crate) fn lock<S>(engine: &Mutex<Engine<S>>) -> std::sync::MutexGuard<'_, Engine<S>> {
+    engine.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// The answer to a refused line, as a csv row so the reason can have commas in it.
+fn rejection(category: &str, reason: &str) -> String {
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    // Writing to memory can't fail.
+    wtr.write_record(["rejected", category, reason]).unwrap();
+    let mut bytes = wtr.into_inner().unwrap();
+    bytes.pop();
+    String::from_utf8(bytes).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::write_output;
+    use std::path;
+
+    // Starts a server on a free port, the server thread lives as long as the test process.
+    fn start(engine: Engine) -> net::SocketAddr {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = Arc::new(Mutex::new(engine));
+        thread::spawn(move || serve(&listener, engine, OutputFormat::Csv, DecimalStyle::Fixed));
+        addr
+    }
+
+    // Sends `lines` on a new connection and returns the answers, up to `answers` lines.
+    fn send(addr: net::SocketAddr, lines: &str, answers: usize) -> Vec<String> {
+        let stream = net::TcpStream::connect(addr).unwrap();
+        // Fail rather than hang if the server answers fewer lines than expected.
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        (&stream).write_all(lines.as_bytes()).unwrap();
+        io::BufReader::new(stream)
+            .lines()
+            .take(answers)
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn answers_test() {
+        let addr = start(Engine::new());
+        assert_eq!(
+            send(
+                addr,
+                "type, client, tx, amount\n\
+                 deposit, 1, 1, 10\n\
+                 \n\
+                 withdrawal, 1, 2, 20\n\
+                 deposit, 1, 1, 5\n\
+                 withdrawal,1\n\
+                 dispute, 1, 1,\n\
+                 !accounts\n\
+                 !balance\n",
+                10,
+            ),
+            [
+                "ok",
+                "ok",
+                "rejected,insufficient_funds,client 1: insufficient funds for tx 2",
+                "rejected,duplicate_tx_id,client 1: tx 1 was already processed",
+                "rejected,parse,\"failed to parse transaction: expected 4 fields, found 2\"",
+                "ok",
+                "client,available,held,total,locked",
+                "1,0.0000,10.0000,10.0000,false",
+                "",
+                "rejected,command,\"unknown command \"\"balance\"\"\"",
+            ]
+        );
+    }
+
+    // A header line can add the destination column, so that transfers can be streamed.
+    #[test]
+    fn header_test() {
+        let addr = start(Engine::new());
+        assert_eq!(
+            send(
+                addr,
+                "type, client, tx, amount, destination\n\
+                 deposit, 1, 1, 10,\n\
+                 transfer, 1, 2, 4, 2\n\
+                 !accounts\n",
+                6,
+            ),
+            [
+                "ok",
+                "ok",
+                "ok",
+                "client,available,held,total,locked",
+                "1,6.0000,0.0000,6.0000,false",
+                "2,4.0000,0.0000,4.0000,false",
+            ]
+        );
+        // Without it a transfer has no destination, and a header can't name unknown columns.
+        assert_eq!(
+            send(
+                addr,
+                "transfer, 1, 3, 1\ntype, client, tx, amount, memo\n",
+                2
+            ),
+            [
+                "rejected,invalid_destination,client 1: tx 3 needs a destination other than the source",
+                "rejected,parse,\"failed to parse transaction: expected 4 fields, found 5\"",
+            ]
+        );
+    }
+
+    // Connections streaming at the same time all apply to one engine and each connection's rows
+    // are applied in order, so withdrawing straight after a deposit always succeeds.
+    #[test]
+    fn concurrent_connections_test() {
+        let addr = start(Engine::new());
+        let connections: Vec<_> = (0..8u32)
+            .map(|connection| {
+                thread::spawn(move || {
+                    let mut lines = String::new();
+                    for row in 0..100 {
+                        let tx_id = connection * 1000 + row * 2;
+                        let client_id = row % 4;
+                        lines += &format!("deposit,{client_id},{tx_id},1.5\n");
+                        lines += &format!("withdrawal,{client_id},{},1.5\n", tx_id + 1);
+                    }
+                    send(addr, &lines, 200)
+                })
+            })
+            .collect();
+        for connection in connections {
+            let answers = connection.join().unwrap();
+            assert_eq!(answers.len(), 200);
+            assert!(answers.iter().all(|answer| answer == "ok"));
+        }
+        assert_eq!(
+            send(addr, "!accounts\n", 5),
+            [
+                "client,available,held,total,locked",
+                "0,0.0000,0.0000,0.0000,false",
+                "1,0.0000,0.0000,0.0000,false",
+                "2,0.0000,0.0000,0.0000,false",
+                "3,0.0000,0.0000,0.0000,false",
+            ]
+        );
+    }
+
+    // A transaction is in the store by the time it's acknowledged, while the connection is still
+    // open.
+    #[test]
+    fn acknowledged_test() {
+        let dir = std::env::temp_dir().join(format!(
+            "toy_payment_engine_server_store_{}",
+            std::process::id()
+        ));
+        let store = crate::disk_store::DiskTxStore::create(&dir, 0).unwrap();
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let engine = Arc::new(Mutex::new(Engine::with_store(store)));
+        thread::spawn(move || serve(&listener, engine, OutputFormat::Csv, DecimalStyle::Fixed));
+
+        let stream = net::TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        (&stream).write_all(b"deposit,1,1,10\n").unwrap();
+        let mut answer = String::new();
+        io::BufReader::new(&stream).read_line(&mut answer).unwrap();
+        assert_eq!(answer, "ok\n");
+        let log_len = std::fs::metadata(dir.join("tx.log")).unwrap().len();
+        assert_eq!(log_len, crate::disk_store::RECORD_LEN as u64);
+        drop(stream);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // Streaming a sample file gives the same accounts as processing it in a batch.
+    #[test]
+    fn sample_file_test() {
+        let csv_file = "sample_data/partial_disputes.csv";
+        let mut engine = Engine::new();
+        engine.process_csv_file(path::Path::new(csv_file)).unwrap();
+        let mut expected = Vec::new();
+        write_output(&engine, &mut expected, DecimalStyle::Fixed).unwrap();
+        let expected = String::from_utf8(expected).unwrap();
+
+        let addr = start(Engine::new());
+        let rows = std::fs::read_to_string(csv_file).unwrap();
+        let answers = rows.lines().filter(|row| !row.trim().is_empty()).count();
+        let output = send(
+            addr,
+            &format!("{rows}\n!accounts\n"),
+            answers + expected.lines().count(),
+        );
+        assert_eq!(output[answers..].join("\n") + "\n", expected);
+    }
+}
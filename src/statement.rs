@@ -0,0 +1,415 @@
+use crate::account::{AccountStatus, OutputRecord};
+use crate::amount::{Amount, DecimalStyle};
+use crate::error::TxError;
+use crate::transaction::{InputRecord, TxType};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// The other client of a transfer on a statement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counterparty {
+    /// The statement's client sent the transfer to this client.
+    To(u16),
+    /// This client sent the transfer to the statement's client.
+    From(u16),
+}
+
+impl fmt::Display for Counterparty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::To(client_id) => write!(f, "to {client_id}"),
+            Self::From(client_id) => write!(f, "from {client_id}"),
+        }
+    }
+}
+
+/// One transaction on a client's statement, with the balances after it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementLine {
+    pub tx_type: TxType,
+    pub tx_id: u32,
+    /// The amount on the input, if there was one.
+    pub amount: Option<Amount>,
+    /// The other client, for a transfer or a dispute, resolve or chargeback of one.
+    pub counterparty: Option<Counterparty>,
+    /// The fee the transaction charged, if there was one.
+    pub fee: Option<Amount>,
+    /// Why the transaction was refused, `None` if it was applied.
+    pub rejection: Option<TxError>,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub status: AccountStatus,
+}
+
+/// Everything that happened on one client's account, in the order it was processed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    client_id: u16,
+    lines: Vec<StatementLine>,
+}
+
+impl Statement {
+    pub const fn new(client_id: u16) -> Self {
+        Self {
+            client_id,
+            lines: Vec::new(),
+        }
+    }
+
+    pub const fn client_id(&self) -> u16 {
+        self.client_id
+    }
+
+    pub fn lines(&self) -> &[StatementLine] {
+        &self.lines
+    }
+
+    /// Adds `record` with its outcome and this client's account after it.
+    pub(crate) fn record(
+        &mut self,
+        record: &InputRecord,
+        result: &Result<(), TxError>,
+        counterparty: Option<Counterparty>,
+        fee: Option<Amount>,
+        account: Option<&OutputRecord>,
+    ) {
+        let account = account.copied().unwrap_or(OutputRecord::new(Amount::ZERO));
+        self.lines.push(StatementLine {
+            tx_type: record.tx_type,
+            tx_id: record.tx_id,
+            amount: record.amount,
+            counterparty,
+            fee,
+            rejection: result.clone().err(),
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            status: account.status,
+        });
+    }
+}
+
+/// How a statement is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StatementFormat {
+    #[default]
+    Csv,
+    /// A plain text table for people to read.
+    Text,
+}
+
+impl FromStr for StatementFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "text" => Ok(Self::Text),
+            _ => Err(format!(
+                "unknown statement format {s:?}, expected csv or text"
+            )),
+        }
+    }
+}
+
+/// Writes the statement to writer.
+/// * `style` - How trailing zeros are written in the amount columns.
+pub fn write_statement(
+    statement: &Statement,
+    writer: impl io::Write,
+    format: StatementFormat,
+    style: DecimalStyle,
+) -> Result<(), Box<dyn Error>> {
+    match format {
+        StatementFormat::Csv => write_csv(statement, writer, style),
+        StatementFormat::Text => write_text(statement, writer, style),
+    }
+}
+
+fn write_csv(
+    statement: &Statement,
+    writer: impl io::Write,
+    style: DecimalStyle,
+) -> Result<(), Box<dyn Error>> {
+    let mut wtr = csv::Writer::from_writer(writer);
+    wtr.write_record([
+        "client",
+        "tx",
+        "type",
+        "amount",
+        "counterparty",
+        "fee",
+        "available",
+        "held",
+        "total",
+        "locked",
+        "rejected",
+        "reason",
+    ])?;
+    for line in statement.lines() {
+        wtr.write_record([
+            statement.client_id.to_string(),
+            line.tx_id.to_string(),
+            line.tx_type.to_string(),
+            line.amount
+                .map(|amount| amount.to_string_with(style))
+                .unwrap_or_default(),
+            line.counterparty
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            line.fee
+                .map(|fee| fee.to_string_with(style))
+                .unwrap_or_default(),
+            line.available.to_string_with(style),
+            line.held.to_string_with(style),
+            line.total.to_string_with(style),
+            (line.status != AccountStatus::Active).to_string(),
+            line.rejection
+                .as_ref()
+                .map(TxError::category)
+                .unwrap_or_default()
+                .to_owned(),
+            line.rejection
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+        ])?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+fn write_text(
+    statement: &Statement,
+    mut writer: impl io::Write,
+    style: DecimalStyle,
+) -> Result<(), Box<dyn Error>> {
+    let header = ["tx", "type", "amount", "fee", "available", "held", "total"];
+    // The fee column is only shown when the client was charged any.
+    let has_fees = statement.lines().iter().any(|line| line.fee.is_some());
+    let columns: Vec<usize> = (0..header.len())
+        .filter(|&column| has_fees || header[column] != "fee")
+        .collect();
+    let rows: Vec<[String; 7]> = statement
+        .lines()
+        .iter()
+        .map(|line| {
+            [
+                line.tx_id.to_string(),
+                match line.counterparty {
+                    Some(counterparty) => format!("{} {counterparty}", line.tx_type),
+                    None => line.tx_type.to_string(),
+                },
+                line.amount
+                    .map(|amount| amount.to_string_with(style))
+                    .unwrap_or_default(),
+                line.fee
+                    .map(|fee| fee.to_string_with(style))
+                    .unwrap_or_default(),
+                line.available.to_string_with(style),
+                line.held.to_string_with(style),
+                line.total.to_string_with(style),
+            ]
+        })
+        .collect();
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, field) in widths.iter_mut().zip(row) {
+            *width = (*width).max(field.len());
+        }
+    }
+    // The tx and type columns are aligned left, the amounts right.
+    let write_row = |writer: &mut dyn io::Write, row: [&str; 7]| -> io::Result<()> {
+        for (index, &column) in columns.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "  " };
+            let width = widths[column];
+            match column {
+                0 | 1 => write!(writer, "{separator}{:<width$}", row[column])?,
+                _ => write!(writer, "{separator}{:>width$}", row[column])?,
+            }
+        }
+        Ok(())
+    };
+
+    writeln!(writer, "Statement for client {}", statement.client_id)?;
+    writeln!(writer)?;
+    write_row(&mut writer, header)?;
+    writeln!(writer)?;
+    for (row, line) in rows.iter().zip(statement.lines()) {
+        write_row(&mut writer, row.each_ref().map(String::as_str))?;
+        match &line.rejection {
+            Some(error) => writeln!(writer, "  REJECTED: {error}")?,
+            None => writeln!(writer)?,
+        }
+    }
+
+    writeln!(writer)?;
+    let rejected = statement
+        .lines()
+        .iter()
+        .filter(|line| line.rejection.is_some())
+        .count();
+    writeln!(
+        writer,
+        "{} transactions, {rejected} rejected",
+        statement.lines().len()
+    )?;
+    if let Some(last) = statement.lines().last() {
+        writeln!(
+            writer,
+            "Closing balance: available {}, held {}, total {}, account {}",
+            last.available.to_string_with(style),
+            last.held.to_string_with(style),
+            last.total.to_string_with(style),
+            last.status,
+        )?;
+    }
+    let fees = statement
+        .lines()
+        .iter()
+        .filter_map(|line| line.fee)
+        .fold(Amount::ZERO, Amount::saturating_add);
+    if fees != Amount::ZERO {
+        writeln!(writer, "Fees charged: {}", fees.to_string_with(style))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::fees::FeeSchedule;
+    use std::path;
+
+    fn statement_of(csv_file: &str, client_id: u16, format: StatementFormat) -> String {
+        let mut engine = Engine::new();
+        engine.record_statement(client_id);
+        engine.process_csv_file(path::Path::new(csv_file)).unwrap();
+        let mut bytes = Vec::new();
+        write_statement(
+            engine.statement().unwrap(),
+            &mut bytes,
+            format,
+            DecimalStyle::Fixed,
+        )
+        .unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    // Client 1 deposits, disputes, resolves, disputes again and is charged back, after which a
+    // deposit and a withdrawal are refused. Their dispute of client 2's deposit is refused too.
+    #[test]
+    fn csv_statement_test() {
+        assert_eq!(
+            statement_of("sample_data/disputes.csv", 1, StatementFormat::Csv),
+            "client,tx,type,amount,counterparty,fee,available,held,total,locked,rejected,reason\n\
+             1,1,deposit,500.0000,,,500.0000,0.0000,500.0000,false,,\n\
+             1,1,dispute,,,,0.0000,500.0000,500.0000,false,,\n\
+             1,2,dispute,,,,0.0000,500.0000,500.0000,false,client_mismatch,client 1: tx 2 belongs to another client\n\
+             1,1,resolve,,,,500.0000,0.0000,500.0000,false,,\n\
+             1,1,dispute,,,,0.0000,500.0000,500.0000,false,,\n\
+             1,12,deposit,5.0000,,,5.0000,500.0000,505.0000,false,,\n\
+             1,1,chargeback,,,,5.0000,0.0000,5.0000,true,,\n\
+             1,3,deposit,1000.0000,,,5.0000,0.0000,5.0000,true,account_locked,\"client 1: account is locked, tx 3 refused\"\n\
+             1,4,withdrawal,1.0000,,,5.0000,0.0000,5.0000,true,account_locked,\"client 1: account is locked, tx 4 refused\"\n"
+        );
+    }
+
+    // Client 2 receives a transfer from client 1, which is disputed and resolved, and charges back
+    // a transfer of their own to client 3. Both sides of a transfer see it with the other client.
+    #[test]
+    fn transfer_statement_test() {
+        assert_eq!(
+            statement_of("sample_data/transfers.csv", 2, StatementFormat::Csv),
+            "client,tx,type,amount,counterparty,fee,available,held,total,locked,rejected,reason\n\
+             2,2,deposit,20.0000,,,20.0000,0.0000,20.0000,false,,\n\
+             2,3,transfer,30.0000,from 1,,50.0000,0.0000,50.0000,false,,\n\
+             2,4,transfer,60.0000,to 3,,50.0000,0.0000,50.0000,false,insufficient_funds,client 2: insufficient funds for tx 4\n\
+             2,3,dispute,,from 1,,20.0000,30.0000,50.0000,false,,\n\
+             2,3,resolve,,from 1,,50.0000,0.0000,50.0000,false,,\n\
+             2,8,transfer,25.0000,to 3,,25.0000,0.0000,25.0000,false,,\n\
+             2,8,dispute,,to 3,,25.0000,0.0000,25.0000,false,,\n\
+             2,8,chargeback,,to 3,,50.0000,0.0000,50.0000,true,,\n\
+             2,9,deposit,5.0000,,,50.0000,0.0000,50.0000,true,account_locked,\"client 2: account is locked, tx 9 refused\"\n"
+        );
+        assert_eq!(
+            statement_of("sample_data/transfers.csv", 3, StatementFormat::Text),
+            "Statement for client 3\n\
+             \n\
+             tx  type                amount  available     held    total\n\
+             8   transfer from 2    25.0000    25.0000   0.0000  25.0000\n\
+             8   dispute from 2                 0.0000  25.0000  25.0000\n\
+             8   chargeback from 2              0.0000   0.0000   0.0000\n\
+             10  transfer to 2       5.0000     0.0000   0.0000   0.0000  REJECTED: client 2: account is locked, tx 10 refused\n\
+             \n\
+             4 transactions, 1 rejected\n\
+             Closing balance: available 0.0000, held 0.0000, total 0.0000, account active\n"
+        );
+    }
+
+    // Client 2 pays 1% on each deposit and only the 19.3 left of the 25 chargeback penalty.
+    #[test]
+    fn fee_statement_test() {
+        let mut engine = Engine::new();
+        engine.set_fee_schedule(
+            FeeSchedule::from_csv_file(path::Path::new("sample_data/fee_schedule.csv")).unwrap(),
+        );
+        engine.record_statement(2);
+        engine
+            .process_csv_file(path::Path::new("sample_data/fees.csv"))
+            .unwrap();
+        let mut bytes = Vec::new();
+        write_statement(
+            engine.statement().unwrap(),
+            &mut bytes,
+            StatementFormat::Text,
+            DecimalStyle::Fixed,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(bytes).unwrap(),
+            "Statement for client 2\n\
+             \n\
+             tx  type         amount      fee  available     held    total\n\
+             5   deposit     50.0000   0.5000    49.5000   0.0000  49.5000\n\
+             6   deposit     20.0000   0.2000    69.3000   0.0000  69.3000\n\
+             5   dispute                         19.3000  50.0000  69.3000\n\
+             5   chargeback           19.3000     0.0000   0.0000   0.0000\n\
+             \n\
+             4 transactions, 0 rejected\n\
+             Closing balance: available 0.0000, held 0.0000, total 0.0000, account locked\n\
+             Fees charged: 20.0000\n"
+        );
+    }
+
+    #[test]
+    fn text_statement_test() {
+        assert_eq!(
+            statement_of("sample_data/disputes.csv", 1, StatementFormat::Text),
+            "Statement for client 1\n\
+             \n\
+             tx  type           amount  available      held     total\n\
+             1   deposit      500.0000   500.0000    0.0000  500.0000\n\
+             1   dispute                   0.0000  500.0000  500.0000\n\
+             2   dispute                   0.0000  500.0000  500.0000  REJECTED: client 1: tx 2 belongs to another client\n\
+             1   resolve                 500.0000    0.0000  500.0000\n\
+             1   dispute                   0.0000  500.0000  500.0000\n\
+             12  deposit        5.0000     5.0000  500.0000  505.0000\n\
+             1   chargeback                5.0000    0.0000    5.0000\n\
+             3   deposit     1000.0000     5.0000    0.0000    5.0000  REJECTED: client 1: account is locked, tx 3 refused\n\
+             4   withdrawal     1.0000     5.0000    0.0000    5.0000  REJECTED: client 1: account is locked, tx 4 refused\n\
+             \n\
+             9 transactions, 3 rejected\n\
+             Closing balance: available 5.0000, held 0.0000, total 5.0000, account locked\n"
+        );
+
+        // A client with no transactions gets an empty statement.
+        let statement = statement_of("sample_data/disputes.csv", 9, StatementFormat::Text);
+        assert!(statement.ends_with("\n0 transactions, 0 rejected\n"));
+    }
+}
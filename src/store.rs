@@ -0,0 +1,73 @@
+use crate::transaction::InputRecord;
+use std::collections::HashMap;
+use std::io;
+
+/// Where the engine remembers processed deposits and withdrawals, so that they can be disputed
+/// later and so the same transaction ID isn't processed twice.
+pub trait TxStore {
+    /// Returns the transaction with `tx_id`, if it was stored.
+    fn get(&self, tx_id: u32) -> io::Result<Option<InputRecord>>;
+
+    /// Stores `record`, replacing any earlier version of the same transaction.
+    fn insert(&mut self, record: InputRecord) -> io::Result<()>;
+
+    fn contains(&self, tx_id: u32) -> io::Result<bool> {
+        Ok(self.get(tx_id)?.is_some())
+    }
+
+    /// Calls `f` with every stored transaction, in no particular order.
+    fn for_each(&self, f: &mut dyn FnMut(InputRecord) -> io::Result<()>) -> io::Result<()>;
+
+    /// Writes anything the store buffers to where it keeps transactions. Stores that don't buffer
+    /// have nothing to do.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: TxStore + ?Sized> TxStore for Box<S> {
+    fn get(&self, tx_id: u32) -> io::Result<Option<InputRecord>> {
+        (**self).get(tx_id)
+    }
+
+    fn insert(&mut self, record: InputRecord) -> io::Result<()> {
+        (**self).insert(record)
+    }
+
+    fn contains(&self, tx_id: u32) -> io::Result<bool> {
+        (**self).contains(tx_id)
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(InputRecord) -> io::Result<()>) -> io::Result<()> {
+        (**self).for_each(f)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Keeps every transaction in a `HashMap`. This is the default store.
+#[derive(Default)]
+pub struct MemoryTxStore {
+    pub(crate) tx_map: HashMap<u32, InputRecord>,
+}
+
+impl TxStore for MemoryTxStore {
+    fn get(&self, tx_id: u32) -> io::Result<Option<InputRecord>> {
+        Ok(self.tx_map.get(&tx_id).cloned())
+    }
+
+    fn insert(&mut self, record: InputRecord) -> io::Result<()> {
+        self.tx_map.insert(record.tx_id, record);
+        Ok(())
+    }
+
+    fn contains(&self, tx_id: u32) -> io::Result<bool> {
+        Ok(self.tx_map.contains_key(&tx_id))
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(InputRecord) -> io::Result<()>) -> io::Result<()> {
+        self.tx_map.values().cloned().try_for_each(f)
+    }
+}
@@ -0,0 +1,266 @@
+use crate::amount::{Amount, JsonAmount};
+use std::fmt;
+use std::path;
+use std::str::FromStr;
+
+/// The format transactions are read in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputFormat {
+    #[default]
+    Csv,
+    /// A JSON object for every transaction, one per line.
+    JsonLines,
+}
+
+impl InputFormat {
+    /// Guesses the format from the file extension, `.jsonl` or `.ndjson` for JSON Lines and csv
+    /// for anything else.
+    pub fn from_path(path: &path::Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("jsonl" | "ndjson") => Self::JsonLines,
+            _ => Self::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::JsonLines),
+            _ => Err(format!("unknown input format {s:?}, expected csv or jsonl")),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TxType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Moves funds from the client to the destination client.
+    Transfer,
+    /// Reserves funds of the client until they are captured or voided.
+    Authorize,
+    /// Takes all or part of what an authorization reserved out of the account.
+    Capture,
+    /// Releases what an authorization still reserves back to available.
+    Void,
+}
+
+impl fmt::Display for TxType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Transfer => "transfer",
+            Self::Authorize => "authorize",
+            Self::Capture => "capture",
+            Self::Void => "void",
+        })
+    }
+}
+
+/// The dispute state of a deposit or withdrawal, or the state of an authorization.
+///
+/// ```text
+/// NotApplied    Applied --dispute--> InDispute --chargeback--> ChargedBack
+///                  ^                  |     ^
+///                  +-----resolve------+     +-- dispute
+///
+/// Authorized --capture--> Captured
+///     |   ^
+///     |   +-- capture
+///     +-----void--------> Voided
+/// ```
+///
+/// When a dispute only covers part of the transaction, a resolve or chargeback of that part leaves
+/// the transaction in `InDispute` until nothing disputed is left. Likewise a capture of part of an
+/// authorization leaves it `Authorized` with the rest still reserved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TxState {
+    /// The transaction was refused, e.g. a withdrawal without enough funds. It can't be disputed.
+    #[default]
+    NotApplied,
+    /// The transaction moved funds and may be disputed.
+    Applied,
+    /// The transaction is disputed and its amount is held.
+    InDispute,
+    /// The transaction was reversed. This is final.
+    ChargedBack,
+    /// The authorization reserves its amount, less what was captured, in held.
+    Authorized,
+    /// The authorization was captured in full. This is final.
+    Captured,
+    /// What the authorization still reserved was released. This is final.
+    Voided,
+}
+
+impl fmt::Display for TxState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotApplied => "not_applied",
+            Self::Applied => "applied",
+            Self::InDispute => "in_dispute",
+            Self::ChargedBack => "charged_back",
+            Self::Authorized => "authorized",
+            Self::Captured => "captured",
+            Self::Voided => "voided",
+        })
+    }
+}
+
+impl TxState {
+    /// Returns the state a transaction moves to when `tx_type` (a dispute, resolve, chargeback,
+    /// capture or void) refers to it, or `None` if that isn't allowed from the current state.
+    pub const fn transition(self, tx_type: TxType) -> Option<Self> {
+        match (self, tx_type) {
+            (Self::Applied | Self::InDispute, TxType::Dispute) => Some(Self::InDispute),
+            (Self::InDispute, TxType::Resolve) => Some(Self::Applied),
+            (Self::InDispute, TxType::Chargeback) => Some(Self::ChargedBack),
+            (Self::Authorized, TxType::Capture) => Some(Self::Captured),
+            (Self::Authorized, TxType::Void) => Some(Self::Voided),
+            _ => None,
+        }
+    }
+}
+
+/// A single transaction as it appears in the input.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InputRecord {
+    #[serde(rename(deserialize = "type"))]
+    pub tx_type: TxType,
+    #[serde(skip_deserializing)]
+    pub(crate) state: TxState,
+    #[serde(skip_deserializing)]
+    pub(crate) disputed: Amount,
+    #[serde(skip_deserializing)]
+    pub(crate) charged_back: Amount,
+    #[serde(skip_deserializing)]
+    pub(crate) captured: Amount,
+    #[serde(rename(deserialize = "client"))]
+    pub client_id: u16,
+    #[serde(rename(deserialize = "tx"))]
+    pub tx_id: u32,
+    pub amount: Option<Amount>,
+    /// The client a transfer moves funds to. The column is optional and only used by transfers.
+    #[serde(default)]
+    pub destination: Option<u16>,
+}
+
+/// A transaction as it appears in JSON Lines input, e.g.
+/// `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`.
+#[derive(serde::Deserialize)]
+pub(crate) struct JsonInputRecord {
+    #[serde(rename = "type")]
+    tx_type: TxType,
+    client: u16,
+    tx: u32,
+    amount: Option<JsonAmount>,
+    destination: Option<u16>,
+}
+
+impl From<JsonInputRecord> for InputRecord {
+    fn from(record: JsonInputRecord) -> Self {
+        let mut input_record = Self::new(
+            record.tx_type,
+            record.client,
+            record.tx,
+            record.amount.map(|amount| amount.0),
+        );
+        input_record.destination = record.destination;
+        input_record
+    }
+}
+
+impl InputRecord {
+    pub const fn new(tx_type: TxType, client_id: u16, tx_id: u32, amount: Option<Amount>) -> Self {
+        Self {
+            tx_type,
+            state: TxState::NotApplied,
+            disputed: Amount::ZERO,
+            charged_back: Amount::ZERO,
+            captured: Amount::ZERO,
+            client_id,
+            tx_id,
+            amount,
+            destination: None,
+        }
+    }
+
+    /// A transfer of `amount` from `client_id` to `destination`.
+    pub const fn transfer(client_id: u16, destination: u16, tx_id: u32, amount: Amount) -> Self {
+        let mut record = Self::new(TxType::Transfer, client_id, tx_id, Some(amount));
+        record.destination = Some(destination);
+        record
+    }
+
+    /// The dispute state of this transaction. Only meaningful for transactions kept by the engine.
+    pub const fn state(&self) -> TxState {
+        self.state
+    }
+
+    /// The part of this transaction that is currently disputed.
+    pub const fn disputed_amount(&self) -> Amount {
+        self.disputed
+    }
+
+    /// The part of this transaction that was charged back.
+    pub const fn charged_back_amount(&self) -> Amount {
+        self.charged_back
+    }
+
+    /// The part of this authorization that was captured.
+    pub const fn captured_amount(&self) -> Amount {
+        self.captured
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transition_test() {
+        use TxState::{Applied, ChargedBack, InDispute, NotApplied};
+        use TxType::{Chargeback, Deposit, Dispute, Resolve, Withdrawal};
+
+        assert_eq!(Applied.transition(Dispute), Some(InDispute));
+        assert_eq!(InDispute.transition(Resolve), Some(Applied));
+        assert_eq!(InDispute.transition(Chargeback), Some(ChargedBack));
+
+        for tx_type in [Deposit, Withdrawal, Dispute, Resolve, Chargeback] {
+            assert_eq!(NotApplied.transition(tx_type), None);
+            assert_eq!(ChargedBack.transition(tx_type), None);
+        }
+        assert_eq!(Applied.transition(Resolve), None);
+        assert_eq!(Applied.transition(Chargeback), None);
+        assert_eq!(InDispute.transition(Dispute), Some(InDispute));
+        assert_eq!(Applied.transition(Deposit), None);
+    }
+
+    #[test]
+    fn authorization_transition_test() {
+        use TxState::{Applied, Authorized, Captured, InDispute, Voided};
+        use TxType::{Capture, Chargeback, Dispute, Resolve, Void};
+
+        assert_eq!(Authorized.transition(Capture), Some(Captured));
+        assert_eq!(Authorized.transition(Void), Some(Voided));
+        // An authorization can't be disputed, and only authorizations are captured or voided.
+        for tx_type in [Dispute, Resolve, Chargeback] {
+            assert_eq!(Authorized.transition(tx_type), None);
+        }
+        for state in [Applied, InDispute, Captured, Voided] {
+            assert_eq!(state.transition(Capture), None);
+            assert_eq!(state.transition(Void), None);
+        }
+    }
+}